@@ -6,6 +6,9 @@ mod allocator;
 #[path="linearscan/api.rs"]
 mod api;
 
+#[path="linearscan/dot.rs"]
+mod dot;
+
 #[path="linearscan/flatten.rs"]
 mod flatten;
 
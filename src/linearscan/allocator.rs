@@ -1,39 +1,261 @@
-use extra::sort::quick_sort;
 use extra::smallintmap::SmallIntMap;
+use extra::bitv::BitvSet;
 use std::{vec, uint, iterator};
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
 use linearscan::graph::{Graph, Interval,
-                        IntervalId, InstrId, StackId, BlockId,
-                        UseAny, UseRegister, UseFixed,
-                        Value, RegisterVal, StackVal};
-use linearscan::flatten::Flatten;
+                        IntervalId, InstrId, StackId, BlockId, ToPhi,
+                        UseAny, UseRegister, UsePrefer, UseFixed,
+                        Value, VirtualVal, RegisterVal, StackVal, RematVal,
+                        Statistics};
+use linearscan::flatten::{Flatten, BlockOrder, LoopAware};
 use linearscan::liveness::Liveness;
 use linearscan::gap::GapResolver;
 
+pub struct Config<K, G, R> {
+  // Group => number of registers available for allocation.
+  // NOTE: only first registers of group are used, and groups that are not
+  // present in map are using all their registers.
+  register_count: ~SmallIntMap<uint>,
+
+  // Give spilled intervals a register back when one is released by an
+  // interval that ended, and return slots of spilled intervals that ended
+  // (if `reuse_spill_slots` is set)
+  second_chance: bool,
+
+  // Weight of the next use by its distance and loop depth, register with
+  // the highest weight is spilled
+  use_weight: extern "Rust" fn(uint, uint) -> uint,
+
+  // Cost of spilling interval, it overrides `use_weight` and block
+  // frequencies. Register holding intervals with the lowest total cost is
+  // spilled
+  spill_cost: Option<~fn(&Interval<G, R>, &Graph<K, G, R>) -> f64>,
+
+  // Order of blocks in flattened graph
+  ordering: BlockOrder,
+
+  // Target has an instruction for swapping two values, otherwise move
+  // cycles are broken with a scratch register or spill slot
+  swap: bool,
+
+  // Target can move values between stack slots, otherwise such moves are
+  // done through scratch register
+  memory_moves: bool,
+
+  // Remove instructions without side effects whose outputs are unused
+  eliminate_dead: bool,
+
+  // Assign same register to source and destination of moves inserted by
+  // splits and phis, where they don't interfere
+  coalesce: bool,
+
+  // Join consecutive split children of the same interval that were given
+  // the same location, removing moves between them
+  merge_splits: bool,
+
+  // Remove blocks that can't be reached from root before allocation
+  skip_unreachable: bool,
+
+  // Group => register reserved for moves, it won't be allocated.
+  // NOTE: it should not be used by fixed uses either
+  scratch: ~SmallIntMap<uint>,
+
+  // (group, register) pairs that are never allocated (i.e. thread pointer),
+  // fixed uses of them are rejected
+  reserved: ~[(uint, uint)],
+
+  // Keep every value in its stack slot and give it a register only around
+  // uses requiring one (fast allocation for baseline code)
+  spill_everywhere: bool,
+
+  // Keep values without uses requiring register in registers that are
+  // free anyway, instead of spilling them right away
+  greedy_any: bool,
+
+  // Assign registers in a single pass without splitting, when values of
+  // group never outnumber its registers (i.e. in small functions)
+  fast_path: bool,
+
+  // Maximum number of splits per instruction in each group, allocation
+  // fails if it is exceeded
+  split_limit: uint,
+
+  // Maximum number of spill slots in each group (i.e. for bounded stack
+  // frames), allocation fails if more are required
+  max_spill_slots: Option<uint>,
+
+  // Give freed stack slots to other spilled intervals. Without it every
+  // spilled interval gets its own slot, which is easier to debug, but
+  // needs larger frame
+  reuse_spill_slots: bool,
+
+  // Choice between registers that are equally good
+  tie_break: TieBreak,
+
+  // Copy value used by several fixed uses of one instruction with
+  // different registers, instead of failing allocation
+  copy_fixed_conflicts: bool,
+
+  // Store spilled values right after their last register use (or
+  // definition) instead of as late as possible, registers are released
+  // sooner
+  early_store: bool,
+
+  // Split intervals on block boundaries when the split range contains one,
+  // otherwise split right before the position where it's required
+  align_splits: bool,
+
+  // Order of intervals starting at the same position
+  interval_order: IntervalOrder,
+
+  // Keep values in callee-saved registers across calls (but not safepoints)
+  // instead of spilling them. User should save and restore registers
+  // reported in `AllocatorResult::callee_saved`
+  preserve_callee_saved: bool,
+
+  // Check allocation results, and fail with description of the problems
+  verify: bool,
+
+  // Receives allocator's decisions as they are made (i.e. for tracing).
+  // NOTE: registers assigned by `fast_path` are not reported
+  on_event: Option<~fn(AllocEvent)>
+}
+
+// Decision of allocator, reported to `Config::on_event`
+#[deriving(Eq, Clone)]
+pub enum AllocEvent {
+  // Interval got a stack slot
+  Spilled(IntervalId, StackId),
+
+  // Interval was split at position, the last id is the new child
+  Split(IntervalId, InstrId, IntervalId),
+
+  // Interval got register with the index in its group
+  Assigned(IntervalId, uint)
+}
+
+// Order in which equally good registers are considered
+pub enum TieBreak {
+  // Register with the lowest index wins
+  LowestIndex,
+
+  // Register with the highest index wins
+  HighestIndex,
+
+  // Start with the register following the last chosen one
+  RoundRobin
+}
+
+// Order in which unhandled intervals are allocated.
+// NOTE: intervals are always walked by increasing start position, ordering
+// applies to intervals starting at the same position
+pub enum IntervalOrder {
+  // Interval created first wins
+  StartOrder,
+
+  // Longest interval wins
+  LongestFirst,
+
+  // Interval with the highest total weight of uses wins
+  CostliestFirst,
+
+  // Interval with fixed use wins, it has no other choice of register.
+  // Otherwise interval created first wins
+  FixedFirst
+}
+
+/// Default use weight: the farther the use, the cheaper the spill
+pub fn distance_weight(distance: uint, _: uint) -> uint {
+  return distance;
+}
+
+/// Use weight assuming that each loop level is executed ten times
+pub fn loop_depth_weight(distance: uint, depth: uint) -> uint {
+  let mut res = distance;
+  let mut i = 0;
+  while i < depth {
+    res /= 10;
+    i += 1;
+  }
+
+  // Zero weight is left for blocked registers, any use is heavier
+  if res == 0 {
+    return 1;
+  }
+  return res;
+}
+
+#[deriving(Eq)]
 pub struct AllocatorResult {
-  spill_count: ~[uint]
+  // Number of spill slots used in each group, double-width slots of paired
+  // values are counted twice
+  spill_count: ~[uint],
+
+  // Size of each group's spill area in bytes
+  spill_bytes: ~[uint],
+
+  // Size of the whole spill frame in bytes: areas of all groups placed one
+  // after another, each aligned to its slot size. Frame itself is aligned
+  // to the largest slot size
+  frame_size: uint,
+
+  // Callee-saved registers used in each group, user should save and
+  // restore them in prelude and epilogue
+  callee_saved: ~[~[uint]],
+
+  // Registers assigned to values in each group, sorted by index
+  used_registers: ~[~[uint]],
+
+  // Number of moves eliminated by coalescing
+  coalesced_moves: uint,
+
+  // Number of split children merged with their same-valued neighbours
+  merged_splits: uint,
+
+  // Work done by allocator in each group
+  stats: ~[Statistics]
 }
 
 struct GroupResult {
-  spill_count: uint
+  spill_count: uint,
+  spill_bytes: uint,
+  callee_saved: ~[uint],
+  used_registers: ~[uint]
 }
 
 struct AllocatorState<G, R> {
   group: ~G,
   register_count: uint,
+  register_limit: uint,
+  // Register reserved for moves
+  scratch: Option<uint>,
+  // Registers reserved by user
+  reserved: ~[uint],
+  // Offset of group's spill area and number of bytes used in it
+  spill_base: uint,
   spill_count: uint,
   spills: ~[Value<G, R>],
+  reuse_spills: bool,
+  // Maximum number of spill slots, and whether more were requested
+  max_spills: Option<uint>,
+  spill_overflow: bool,
+  // Order of candidate registers, and register to start with in round-robin
+  tie_break: TieBreak,
+  next_reg: uint,
   unhandled: ~[IntervalId],
   active: ~[IntervalId],
-  inactive: ~[IntervalId]
+  inactive: ~[IntervalId],
+  // Spilled intervals, that might get register back (see `second_chance`)
+  spilled: ~[IntervalId]
 }
 
-pub trait Allocator {
+pub trait Allocator<K, G, R> {
   // Prepare for allocation
-  fn prepare(&mut self);
+  fn prepare(&mut self) -> Result<(), ~str>;
 
   // Allocate registers
-  fn allocate(&mut self) -> Result<AllocatorResult, ~str>;
+  fn allocate(&mut self,
+              config: Config<K, G, R>) -> Result<AllocatorResult, ~str>;
 }
 
 enum SplitConf {
@@ -43,7 +265,9 @@ enum SplitConf {
 
 trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
   // Walk unhandled intervals in the order of increasing starting point
-  fn walk_intervals(&mut self, group: &G) -> Result<GroupResult, ~str>;
+  fn walk_intervals(&mut self,
+                    group: &G,
+                    spill_base: uint) -> Result<GroupResult, ~str>;
   // Try allocating free register
   fn allocate_free_reg<'r>(&'r mut self,
                            current: IntervalId,
@@ -53,8 +277,59 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
                               current: IntervalId,
                               state: &'r mut AllocatorState<G, R>)
       -> Result<(), ~str>;
+  // Get weight of the next use at `pos` (the higher - the cheaper to spill)
+  fn use_weight(&self, start: InstrId, pos: uint) -> uint;
+  // Get weight of each register by user-defined spill costs of intervals
+  // holding it (the higher - the cheaper to spill)
+  fn cost_weights<'r>(&'r self,
+                      current: IntervalId,
+                      use_pos: &[uint],
+                      state: &'r AllocatorState<G, R>) -> ~[uint];
+  // Move spilled interval to register, if it's free for the whole lifetime
+  fn second_chance<'r>(&'r mut self,
+                       current: IntervalId,
+                       state: &'r mut AllocatorState<G, R>);
+  // Release slots of spilled intervals that ended before `position`, and
+  // split others at the first gap after it if they could get a register
+  // for the rest of their lifetime
+  fn reload_spilled<'r>(&'r mut self,
+                        position: InstrId,
+                        released: bool,
+                        state: &'r mut AllocatorState<G, R>);
+  // Find register that is free from `from` until the end of interval
+  fn lifetime_free_reg<'r>(&'r self,
+                           current: IntervalId,
+                           from: InstrId,
+                           state: &'r AllocatorState<G, R>) -> Option<uint>;
+  // Give every unhandled interval a register for its whole lifetime, if
+  // it is possible without splitting. Returns false and leaves intervals
+  // untouched otherwise
+  fn fast_assign<'r>(&'r mut self,
+                     state: &'r mut AllocatorState<G, R>) -> bool;
+  // Check that graph is well-formed
+  fn check_graph(&self) -> Result<(), ~str>;
+
+  // Assign same register to move's source and destination where possible
+  fn coalesce(&mut self) -> uint;
+
+  // Try giving `to` the same register as `from`, `index` holds intervals
+  // in registers by flat blocks they are alive in
+  fn coalesce_pair(&mut self,
+                   from: IntervalId,
+                   to: IntervalId,
+                   index: &SmallIntMap<~[IntervalId]>) -> bool;
+
+  // Return flat blocks covered by interval's ranges
+  fn covered_blocks(&self, id: &IntervalId) -> ~[uint];
+
   // Add movements on block edges
-  fn resolve_data_flow(&mut self, list: &[BlockId]);
+  fn resolve_data_flow(&mut self, list: &[BlockId]) -> Result<(), ~str>;
+
+  // Copy inputs used in other register groups before their instructions
+  fn resolve_cross_inputs(&mut self) -> Result<(), ~str>;
+
+  // Copy tied inputs into output's register before their instructions
+  fn resolve_tied_inputs(&mut self) -> Result<(), ~str>;
 
   // Build live ranges for each interval
   fn build_ranges(&mut self, blocks: &[BlockId]) -> Result<(), ~str>;
@@ -66,10 +341,26 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
   // Helpers
   //
 
-  // Sort unhandled list (after insertion)
-  fn sort_unhandled<'r>(&'r mut self, state: &'r mut AllocatorState<G, R>);
+  // Insert interval into unhandled list, keeping it sorted by start position
+  // and `config.interval_order`
+  fn insert_unhandled<'r>(&'r mut self,
+                          current: IntervalId,
+                          state: &'r mut AllocatorState<G, R>);
+
+  // Return true if `a` should be allocated before `b`
+  fn allocated_before(&self, a: &IntervalId, b: &IntervalId) -> bool;
+
+  // Get total length of interval's ranges
+  fn interval_length(&self, id: &IntervalId) -> uint;
+
+  // Get sum of costs of blocks containing interval's uses
+  fn spill_cost(&self, id: &IntervalId) -> f64;
 
-  // Get register hint if present
+  // Report decision to user
+  fn emit(&self, event: AllocEvent);
+
+  // Get register hint if present, following chain of hints up to the
+  // root-most interval that has a register
   fn get_hint(&mut self, current: IntervalId) -> Option<R>;
 
   // Split interval at some optimal position and add split child to unhandled
@@ -78,11 +369,29 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
                conf: SplitConf,
                state: &'r mut AllocatorState<G, R>) -> IntervalId;
 
+  // Get spill value for interval, rematerialize it if possible
+  fn get_spill<'r>(&'r mut self,
+                   current: IntervalId,
+                   state: &'r mut AllocatorState<G, R>) -> Value<G, R>;
+
   // Split and spill all intervals intersecting with current
   fn split_and_spill<'r>(&'r mut self,
                          current: IntervalId,
                          state: &'r mut AllocatorState<G, R>);
 
+  // Split interval right before and after each use requiring register
+  fn split_around_uses<'r>(&'r mut self,
+                           current: IntervalId,
+                           state: &'r mut AllocatorState<G, R>);
+
+  // Find fixed (or must-not-be-spilled) interval occupying `reg` at or
+  // before `pos`, used for diagnostics
+  fn blocking_interval(&self,
+                       current: IntervalId,
+                       reg: uint,
+                       pos: InstrId,
+                       state: &AllocatorState<G, R>) -> Option<IntervalId>;
+
   // Iterate through all active intervals
   fn iter_active<'r>(&'r self, state: &'r AllocatorState<G, R>)
       -> iterator::Map<'r,
@@ -100,28 +409,63 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
                              vec::VecIterator<IntervalId> >;
 
   // Verify allocation results
-  fn verify(&self);
+  fn verify(&self) -> Result<(), ~str>;
+
+  // Verify that unhandled list is sorted
+  fn verify_unhandled(&self, state: &AllocatorState<G, R>);
 }
 
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
-     K: KindHelper<G, R> > Allocator for Graph<K, G, R> {
-  fn prepare(&mut self) {
+     K: KindHelper<G, R> > Allocator<K, G, R> for Graph<K, G, R> {
+  fn prepare(&mut self) -> Result<(), ~str> {
     if self.prepared {
-      return;
+      return Ok(());
+    }
+
+    // Bail out early on malformed graphs
+    match self.check_graph() {
+      Ok(_) => (),
+      Err(reason) => { return Err(reason); }
+    }
+
+    // Remove blocks that are never executed
+    if self.config.skip_unreachable {
+      self.remove_unreachable();
     }
 
+    // Remove unused computations
+    if self.config.eliminate_dead {
+      self.eliminate_dead();
+    }
+
+    // Give every data-flow move a block to live in
+    self.split_critical_edges();
+
+    // Keep previous values of conditionally defined outputs on other paths,
+    // nothing is done unless some instruction has `maybe_def`
+    self.merge_conditional_defs();
+
     // Get flat list of blocks
     self.flatten();
 
+    // Cache block lookups for splitting
+    self.build_block_index();
+
     // Build live_in/live_out
     self.liveness_analysis();
 
     self.prepared = true;
+    return Ok(());
   }
 
-  fn allocate(&mut self) -> Result<AllocatorResult, ~str> {
-    self.prepare();
+  fn allocate(&mut self,
+              config: Config<K, G, R>) -> Result<AllocatorResult, ~str> {
+    self.config = config;
+    match self.prepare() {
+      Ok(_) => (),
+      Err(reason) => { return Err(reason); }
+    }
 
     // Create physical fixed intervals
     let groups: ~[G] = GroupHelper::groups();
@@ -138,35 +482,98 @@ impl<G: GroupHelper<R>,
     }
 
     let list = self.get_block_list();
+    self.stats.clear();
 
     // Create live ranges
     match self.build_ranges(list) {
       Ok(_) => {
         let mut results = ~[];
+        let mut spill_base = 0;
+        let mut frame_align = 1;
         // In each register group
         for group in groups.iter() {
+          // Spill area of each group should be aligned to its slot size
+          let size = group.spill_size();
+          spill_base = (spill_base + size - 1) / size * size;
+          if size > frame_align {
+            frame_align = size;
+          }
+
           // Walk intervals!
-          match self.walk_intervals(group) {
+          match self.walk_intervals(group, spill_base) {
             Ok(res) => {
+              spill_base += res.spill_bytes;
               results.push(res);
             },
             Err(reason) => { return Err(reason); }
           }
         }
 
+        // Eliminate moves between non-interfering intervals
+        let coalesced = if self.config.coalesce { self.coalesce() } else { 0 };
+
+        // Join split children that ended up in the same location
+        let merged = if self.config.merge_splits {
+          self.merge_redundant_splits()
+        } else {
+          0
+        };
+
         // Add moves between blocks
-        self.resolve_data_flow(list);
+        match self.resolve_data_flow(list) {
+          Ok(_) => (),
+          Err(reason) => { return Err(reason); }
+        }
+
+        // Add moves to inputs used in other groups
+        match self.resolve_cross_inputs() {
+          Ok(_) => (),
+          Err(reason) => { return Err(reason); }
+        }
+
+        // Add moves to outputs of two-address instructions
+        match self.resolve_tied_inputs() {
+          Ok(_) => (),
+          Err(reason) => { return Err(reason); }
+        }
 
         // Resolve parallel moves
-        self.resolve_gaps();
+        match self.resolve_gaps() {
+          Ok(_) => (),
+          Err(reason) => { return Err(reason); }
+        }
 
         // Verify correctness of allocation
-        self.verify();
+        if self.config.verify {
+          match self.verify() {
+            Ok(_) => (),
+            Err(reason) => { return Err(reason); }
+          }
+        }
 
         // Map results from each group to a general result
         return Ok(AllocatorResult {
           spill_count: do results.map() |result| {
             result.spill_count
+          },
+          spill_bytes: do results.map() |result| {
+            result.spill_bytes
+          },
+          frame_size: (spill_base + frame_align - 1) / frame_align *
+                      frame_align,
+          callee_saved: do results.map() |result| {
+            result.callee_saved.clone()
+          },
+          used_registers: do results.map() |result| {
+            result.used_registers.clone()
+          },
+          coalesced_moves: coalesced,
+          merged_splits: merged,
+          stats: do groups.map() |group| {
+            match self.stats.find(&group.to_uint()) {
+              Some(stats) => stats.clone(),
+              None => Statistics::new()
+            }
           }
         });
       },
@@ -179,20 +586,60 @@ impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > AllocatorHelper<G, R> for Graph<K, G, R> {
   fn walk_intervals(&mut self,
-                    group: &G) -> Result<GroupResult, ~str> {
+                    group: &G,
+                    spill_base: uint) -> Result<GroupResult, ~str> {
     // Initialize allocator state
-    let reg_count = group.registers().len();
+    let reg_count = group.register_count();
+    let reg_limit = match self.config.register_count.find(&group.to_uint()) {
+      Some(&limit) if limit < reg_count => limit,
+      _ => reg_count
+    };
+    let mut reserved = ~[];
+    for &(g, r) in self.config.reserved.iter() {
+      if g == group.to_uint() {
+        reserved.push(r);
+      }
+    }
     let mut state = ~AllocatorState {
       group: ~group.clone(),
       register_count: reg_count,
+      register_limit: reg_limit,
+      scratch: match self.config.scratch.find(&group.to_uint()) {
+        Some(&r) => Some(r),
+        None => None
+      },
+      reserved: reserved,
+      spill_base: spill_base,
       spill_count: 0,
       spills: ~[],
+      reuse_spills: self.config.reuse_spill_slots,
+      max_spills: self.config.max_spill_slots,
+      spill_overflow: false,
+      tie_break: self.config.tie_break,
+      next_reg: 0,
       unhandled: ~[],
       active: ~[],
-      inactive: ~[]
+      inactive: ~[],
+      spilled: ~[]
     };
 
+    // Reserve location for breaking move cycles
+    match state.scratch {
+      Some(r) => {
+        let id = *self.physical.find(&group.to_uint()).unwrap()
+                               .find(&r).expect("Scratch register");
+        self.scratch.insert(group.to_uint(), id);
+      },
+      None if !self.config.swap => {
+        let id = Interval::<G, R>::new::<K>(self, group.clone());
+        self.get_mut_interval(&id).value = state.get_spill();
+        self.scratch.insert(group.to_uint(), id);
+      },
+      None => ()
+    }
+
     // We'll work with intervals that contain any ranges
+    let mut unhandled = ~[];
     for (_, interval) in self.intervals.iter() {
       if &interval.value.group() == state.group && interval.ranges.len() > 0 {
         if interval.fixed {
@@ -200,11 +647,57 @@ impl<G: GroupHelper<R>,
           state.active.push(interval.id);
         } else {
           // And everything else to unhandled
-          state.unhandled.push(interval.id);
+          unhandled.push(interval.id);
+        }
+      }
+    }
+    for id in unhandled.iter() {
+      self.insert_unhandled(*id, state);
+    }
+
+    // Only short pieces around register uses are competing for registers
+    if self.config.spill_everywhere {
+      for id in unhandled.iter() {
+        self.split_around_uses(*id, state);
+      }
+    }
+    let mut homes = ~SmallIntMap::new();
+
+    // Values that never compete for registers don't need the walk below
+    if self.config.fast_path && !self.config.spill_everywhere &&
+       self.fast_assign(state) {
+      self.get_mut_stats(group).fast_groups += 1;
+    }
+
+    // Bound total number of splits, so bad heuristics can't loop forever
+    let count = self.instructions.len();
+    let split_limit = if count == 0 ||
+                         self.config.split_limit > uint::max_value / count {
+      uint::max_value
+    } else {
+      self.config.split_limit * count
+    };
+
+    // Reserved registers can't satisfy fixed uses
+    let mut reserved = ~[];
+    for &r in state.reserved.iter() {
+      let reg: R = RegisterHelper::from_uint(group, r);
+      reserved.push_all(state.aliased(&reg));
+    }
+    for id in unhandled.iter() {
+      for u in self.get_interval(id).uses.iter() {
+        match u.kind {
+          UseFixed(ref r) if reserved.contains(&r.to_uint()) => {
+            return Err(fmt!("Fixed use of reserved register %u in group %u \
+                             at %u",
+                            r.to_uint(),
+                            group.to_uint(),
+                            u.pos.to_uint()));
+          },
+          _ => ()
         }
       }
     }
-    self.sort_unhandled(state);
 
     while state.unhandled.len() > 0 {
       let current = state.unhandled.shift();
@@ -212,12 +705,15 @@ impl<G: GroupHelper<R>,
 
       // active => inactive or handled
       let mut handled = ~[];
+      let mut released = false;
       do state.active.retain |id| {
         if self.get_interval(id).covers(position) {
           true
         } else {
           if position <= self.get_interval(id).end() {
             state.inactive.push(*id);
+          } else {
+            released = true;
           }
           handled.push(self.get_interval(id).value.clone());
           false
@@ -240,8 +736,55 @@ impl<G: GroupHelper<R>,
         state.to_handled(v)
       }
 
+      // Spilled intervals might use registers of handled ones
+      if self.config.second_chance {
+        self.reload_spilled(position, released, state);
+      }
+
       // Skip non-virtual intervals
-      if self.get_interval(&current).value.is_virtual() {
+      let spill_everywhere = self.config.spill_everywhere &&
+          !self.is_no_spill(&current) &&
+          self.get_interval(&current).next_required_use(InstrId(0)).is_none();
+      let idle = if spill_everywhere && self.config.greedy_any {
+        let start = self.get_interval(&current).start();
+        self.lifetime_free_reg(current, start, state)
+      } else {
+        None
+      };
+      if idle.is_some() && self.get_interval(&current).value.is_virtual() {
+        // Register that is idle for the whole lifetime costs nothing
+        self.get_mut_interval(&current).value =
+            RegisterVal(RegisterHelper::from_uint(state.group, idle.unwrap()));
+        self.emit(Assigned(current, idle.unwrap()));
+      } else if spill_everywhere &&
+                self.get_interval(&current).value.is_virtual() {
+        // All pieces of interval are sharing the same slot
+        let parent = match self.get_interval(&current).parent {
+          Some(parent) => parent,
+          None => current
+        };
+        let home = match homes.find(&parent.to_uint()) {
+          Some(home) => Some(home.clone()),
+          None => None
+        };
+        let home = match home {
+          Some(home) => home,
+          None => {
+            let home = if self.is_paired(&current) {
+              state.get_pair_spill()
+            } else {
+              state.get_spill()
+            };
+            homes.insert(parent.to_uint(), home.clone());
+            home
+          }
+        };
+        match home {
+          StackVal(_, slot) => self.emit(Spilled(current, slot)),
+          _ => ()
+        }
+        self.get_mut_interval(&current).value = home;
+      } else if self.get_interval(&current).value.is_virtual() {
         // Allocate free register
         if !self.allocate_free_reg(current, state) {
           // Or spill some active register
@@ -252,36 +795,98 @@ impl<G: GroupHelper<R>,
             }
           }
         }
+      } else if self.config.second_chance {
+        // Some register might be released since interval was spilled
+        self.second_chance(current, state);
       }
 
       // Push register interval to active
       match self.get_interval(&current).value {
         RegisterVal(_) => state.active.push(current),
+        StackVal(_, _) if self.config.second_chance && !spill_everywhere => {
+          state.spilled.push(current)
+        },
         _ => ()
       }
+
+      if self.get_mut_stats(group).splits > split_limit {
+        return Err(~"allocation did not converge");
+      }
+      if state.spill_overflow {
+        return Err(fmt!("Spill slot limit exceeded in group %u",
+                        group.to_uint()));
+      }
+    }
+    if state.spill_overflow {
+      return Err(fmt!("Spill slot limit exceeded in group %u",
+                      group.to_uint()));
+    }
+
+    // Collect used registers, and callee-saved ones among them
+    let mut used = ~[];
+    let mut callee_saved = ~[];
+    for (_, interval) in self.intervals.iter() {
+      let mut regs = match interval.value {
+        RegisterVal(ref r) if !interval.fixed && r.group() == *state.group => {
+          ~[r.clone()]
+        },
+        _ => loop
+      };
+      if self.is_paired(&interval.id) {
+        match interval.value.pair_high() {
+          RegisterVal(high) => regs.push(high),
+          _ => ()
+        }
+      }
+      for r in regs.iter() {
+        if !used.contains(&r.to_uint()) {
+          let mut i = 0;
+          while i < used.len() && used[i] < r.to_uint() {
+            i += 1;
+          }
+          used.insert(i, r.to_uint());
+        }
+        if r.is_callee_saved() && !callee_saved.contains(&r.to_uint()) {
+          callee_saved.push(r.to_uint());
+        }
+      }
     }
 
-    return Ok(GroupResult { spill_count: state.spill_count });
+    return Ok(GroupResult {
+      spill_count: state.spill_count / group.spill_size(),
+      spill_bytes: state.spill_count,
+      callee_saved: callee_saved,
+      used_registers: used
+    });
   }
 
   fn allocate_free_reg<'r>(&'r mut self,
                            current: IntervalId,
                            state: &'r mut AllocatorState<G, R>) -> bool {
-    let mut free_pos = vec::from_elem(state.register_count, uint::max_value);
+    let mut free_pos = state.initial_pos();
     let hint = self.get_hint(current);
 
-    // All active intervals use registers
-    for (_, reg) in self.iter_active(state) {
-      free_pos[reg.to_uint()] = 0;
+    // All active intervals use registers (and their aliases)
+    for (id, reg) in self.iter_active(state) {
+      for &i in state.occupied(reg, self.is_paired(id)).iter() {
+        free_pos[i] = 0;
+      }
     }
 
     // All inactive registers will eventually use registers
-    for (_, reg, pos) in self.iter_intersecting(current, state) {
-      if free_pos[reg.to_uint()] > pos.to_uint() {
-        free_pos[reg.to_uint()] = pos.to_uint();
+    for (id, reg, pos) in self.iter_intersecting(current, state) {
+      for &i in state.occupied(reg, self.is_paired(id)).iter() {
+        if free_pos[i] > pos.to_uint() {
+          free_pos[i] = pos.to_uint();
+        }
       }
     }
 
+    // Paired value needs both registers of a pair
+    if self.is_paired(&current) {
+      free_pos = state.pair_pos(free_pos);
+    }
+
     // Choose register with maximum free_pos
     let mut reg = 0;
     let mut max_pos = InstrId(0);
@@ -299,22 +904,16 @@ impl<G: GroupHelper<R>,
 
       // Other intervals should prefer register that's free for a longer time
       None => {
-        // Prefer hinted register
-        match hint {
-          Some(hint) => for (i, &pos) in free_pos.iter().enumerate() {
-            if pos > max_pos.to_uint() ||
-               hint.to_uint() == i && pos == max_pos.to_uint() {
-              max_pos = InstrId(pos);
-              reg = i;
-            }
-          },
-          None => for (i, &pos) in free_pos.iter().enumerate() {
-            if pos > max_pos.to_uint() {
-              max_pos = InstrId(pos);
-              reg = i;
-            }
+        // Prefer hinted register, and caller-saved ones
+        for &i in state.candidates().iter() {
+          let pos = free_pos[i];
+          if pos > max_pos.to_uint() ||
+             pos == max_pos.to_uint() && state.prefer(&hint, i, reg) {
+            max_pos = InstrId(pos);
+            reg = i;
           }
         }
+        state.next_reg = reg + 1;
       }
     }
 
@@ -352,8 +951,8 @@ impl<G: GroupHelper<R>,
 
       // Fast case, spill child if there're no register uses after split
       match self.get_interval(&child).next_use(InstrId(0)) {
-        None => {
-          self.get_mut_interval(&child).value = state.get_spill();
+        None if !self.is_no_spill(&child) && !self.config.greedy_any => {
+          self.get_mut_interval(&child).value = self.get_spill(child, state);
         },
         _ => ()
       }
@@ -362,6 +961,7 @@ impl<G: GroupHelper<R>,
     // Give current a register
     self.get_mut_interval(&current).value =
         RegisterVal(RegisterHelper::from_uint::<G, R>(state.group, reg));
+    self.emit(Assigned(current, reg));
 
     return true;
   }
@@ -370,19 +970,21 @@ impl<G: GroupHelper<R>,
                               current: IntervalId,
                               state: &'r mut AllocatorState<G, R>)
       -> Result<(), ~str> {
-    let mut use_pos = vec::from_elem(state.register_count, uint::max_value);
-    let mut block_pos = vec::from_elem(state.register_count, uint::max_value);
+    let mut use_pos = state.initial_pos();
+    let mut block_pos = state.initial_pos();
     let start = self.get_interval(&current).start();
     let hint = self.get_hint(current);
 
     // Populate use_pos from every non-fixed interval
     for (id, reg) in self.iter_active(state) {
       let interval = self.get_interval(id);
+      let paired = self.is_paired(id);
       if !interval.fixed {
-        let int_reg = reg.to_uint();
         match interval.next_use(start) {
-          Some(u) => if use_pos[int_reg] > u.pos.to_uint() {
-            use_pos[int_reg] = u.pos.to_uint();
+          Some(u) => for &int_reg in state.occupied(reg, paired).iter() {
+            if use_pos[int_reg] > u.pos.to_uint() {
+              use_pos[int_reg] = u.pos.to_uint();
+            }
           },
           None => ()
         }
@@ -390,36 +992,49 @@ impl<G: GroupHelper<R>,
     }
     for (id, reg, _) in self.iter_intersecting(current, state) {
       let interval = self.get_interval(id);
+      let paired = self.is_paired(id);
       if !interval.fixed {
-        let int_reg = reg.to_uint();
         match interval.next_use(start) {
-          Some(u) => if use_pos[int_reg] > u.pos.to_uint() {
-            use_pos[int_reg] = u.pos.to_uint();
+          Some(u) => for &int_reg in state.occupied(reg, paired).iter() {
+            if use_pos[int_reg] > u.pos.to_uint() {
+              use_pos[int_reg] = u.pos.to_uint();
+            }
           },
           None => ()
         }
       }
     }
 
-    // Populate block_pos from every fixed interval
+    // Populate block_pos from every fixed interval, intervals that must not
+    // be spilled are blocking their registers too
     for (id, reg) in self.iter_active(state) {
-      if self.get_interval(id).fixed {
-        let int_reg = reg.to_uint();
-        block_pos[int_reg] = 0;
-        use_pos[int_reg] = 0;
+      if self.get_interval(id).fixed || self.is_no_spill(id) {
+        for &int_reg in state.occupied(reg, false).iter() {
+          block_pos[int_reg] = 0;
+          use_pos[int_reg] = 0;
+        }
       }
     }
     for (id, reg, pos) in self.iter_intersecting(current, state) {
-      if self.get_interval(id).fixed {
-        let int_reg = reg.to_uint();
+      if self.get_interval(id).fixed || self.is_no_spill(id) {
         let int_pos = pos.to_uint();
-        block_pos[int_reg] = int_pos;
-        if use_pos[int_reg] > int_pos {
-          use_pos[int_reg] = int_pos;
+        for &int_reg in state.occupied(reg, false).iter() {
+          if block_pos[int_reg] > int_pos {
+            block_pos[int_reg] = int_pos;
+          }
+          if use_pos[int_reg] > int_pos {
+            use_pos[int_reg] = int_pos;
+          }
         }
       }
     }
 
+    // Paired value needs both registers of a pair
+    if self.is_paired(&current) {
+      use_pos = state.pair_pos(use_pos);
+      block_pos = state.pair_pos(block_pos);
+    }
+
     // Find register with the farest use
     let mut reg = 0;
     let mut max_pos = 0;
@@ -437,130 +1052,530 @@ impl<G: GroupHelper<R>,
 
       // Other intervals should prefer register that isn't used for longer time
       None => {
+        let weights = if self.config.spill_cost.is_some() {
+          self.cost_weights(current, use_pos, state)
+        } else {
+          do use_pos.map() |&pos| { self.use_weight(start, pos) }
+        };
+        let mut max_weight = 0;
+
         // Prefer hinted register
         match hint {
-          Some(hint) => for (i, &pos) in use_pos.iter().enumerate() {
-            if pos > max_pos || hint.to_uint() == i && pos == max_pos {
-              max_pos = pos;
+          Some(hint) => for &i in state.candidates().iter() {
+            let weight = weights[i];
+            if weight > max_weight ||
+               hint.to_uint() == i && weight == max_weight {
+              max_weight = weight;
+              max_pos = use_pos[i];
               reg = i;
             }
           },
-          None => for (i, &pos) in use_pos.iter().enumerate() {
-            if pos > max_pos {
-              max_pos = pos;
+          None => for &i in state.candidates().iter() {
+            let weight = weights[i];
+            if weight > max_weight {
+              max_weight = weight;
+              max_pos = use_pos[i];
               reg = i;
             }
           }
         }
+        state.next_reg = reg + 1;
       }
     }
 
-    let first_use = self.get_interval(&current).next_use(InstrId(0));
+    // Uses preferring register or any location could be satisfied with a
+    // stack slot, but not with a rematerialized value
+    let first_use = if self.remat_instr(&current).is_some() {
+      self.get_interval(&current).next_any_kind_use(InstrId(0))
+    } else {
+      self.get_interval(&current).next_required_use(InstrId(0))
+    };
+    let no_spill = self.is_no_spill(&current);
+    if no_spill && max_pos <= start.to_uint() {
+      return Err(fmt!("Interval %u can't be kept in register",
+                      current.to_uint()));
+    }
     match first_use {
-      Some(u) => {
-        if max_pos < u.pos.to_uint() {
-          if u.pos == start {
-            return Err(~"Incorrect input, allocation impossible");
-          }
-
-          // Spill current itself
-          self.get_mut_interval(&current).value = state.get_spill();
-
-          // And split before first register use
-          self.split(current, Between(start, u.pos), state);
-        } else {
-          // Assign register to current
-          self.get_mut_interval(&current).value =
-              RegisterVal(RegisterHelper::from_uint(state.group, reg));
+      Some(ref u) if max_pos < u.pos.to_uint() && !no_spill => {
+        if u.pos == start {
+          let reason = fmt!("Interval %u needs register %u at %u",
+                            current.to_uint(),
+                            reg,
+                            u.pos.to_uint());
+          return Err(match self.blocking_interval(current, reg, u.pos, state) {
+            Some(other) => fmt!("%s, but it is held by interval %u",
+                                reason,
+                                other.to_uint()),
+            None => reason
+          });
+        }
 
-          // If blocked somewhere before end by fixed interval
-          if block_pos[reg] <= self.get_interval(&current).end().to_uint() {
-            // Split before this position
-            self.split(current, Between(start, InstrId(block_pos[reg])), state);
-          }
+        // Spill current itself
+        self.get_mut_interval(&current).value =
+            self.get_spill(current, state);
 
-          // Split and spill, active and intersecting inactive
-          self.split_and_spill(current, state);
-        }
+        // And split before first register use
+        self.split(current, Between(start, u.pos), state);
       },
-      None => {
+      None if !no_spill => {
         // Spill current, it has no uses
-        self.get_mut_interval(&current).value = state.get_spill();
+        self.get_mut_interval(&current).value =
+            self.get_spill(current, state);
+      },
+      _ => {
+        // Assign register to current, values that must not be spilled are
+        // getting it even if others are used earlier
+        self.get_mut_interval(&current).value =
+            RegisterVal(RegisterHelper::from_uint(state.group, reg));
+        self.emit(Assigned(current, reg));
+
+        // If blocked somewhere before end by fixed interval
+        if block_pos[reg] <= self.get_interval(&current).end().to_uint() {
+          // Split before this position
+          self.split(current, Between(start, InstrId(block_pos[reg])), state);
+        }
+
+        // Split and spill, active and intersecting inactive
+        self.split_and_spill(current, state);
       }
     }
     return Ok(());
   }
 
-  fn iter_active<'r>(&'r self, state: &'r AllocatorState<G, R>)
-      -> iterator::Map<'r,
-                       &IntervalId,
-                       (&IntervalId, &R),
-                       vec::VecIterator<IntervalId> > {
-    state.active.iter().map(|id| {
-      match self.get_interval(id).value {
-        RegisterVal(ref reg) => (id, reg),
-        _ => fail!("Expected register in active")
-      }
-    })
-  }
+  fn use_weight(&self, start: InstrId, pos: uint) -> uint {
+    // Unused and blocked registers
+    if pos == uint::max_value || pos <= start.to_uint() {
+      return pos;
+    }
 
-  // Iterate through all inactive intervals that are intersecting with current
-  fn iter_intersecting<'r>(&'r self,
-                           current: IntervalId,
-                           state: &'r AllocatorState<G, R>)
-      -> iterator::FilterMap<'r,
-                             &IntervalId,
-                             (&IntervalId, &R, InstrId),
-                             vec::VecIterator<IntervalId> > {
-    state.inactive.iter().filter_map(|id| {
-      match self.get_intersection(id, &current) {
-        Some(pos) => match self.get_interval(id).value {
-          RegisterVal(ref reg) => Some((id, reg, pos)),
-          _ => fail!("Expected register in inactive")
-        },
-        None => None
-      }
-    })
+    let block = self.get_block(&self.block_of(&InstrId(pos)));
+    let distance = pos - start.to_uint();
+    return match block.frequency {
+      // Uses in rarely executed blocks are cheaper to spill
+      Some(f) => {
+        let weight = distance as f64 / f;
+        if f > 0f64 && weight < (uint::max_value - 1) as f64 {
+          weight as uint
+        } else {
+          uint::max_value - 1
+        }
+      },
+      None => (self.config.use_weight)(distance, block.loop_depth)
+    };
   }
 
-  fn sort_unhandled<'r>(&'r mut self, state: &'r mut AllocatorState<G, R>) {
-    // TODO(indutny) do sorted inserts and don't call this on every insertion,
-    // it is really expensive!
+  fn cost_weights<'r>(&'r self,
+                      current: IntervalId,
+                      use_pos: &[uint],
+                      state: &'r AllocatorState<G, R>) -> ~[uint] {
+    let cost = self.config.spill_cost.get_ref();
+    let start = self.get_interval(&current).start().to_uint();
 
-    // Sort intervals in the order of increasing start position
-    do quick_sort(state.unhandled) |left, right| {
-      let lstart = self.get_interval(left).start();
-      let rstart = self.get_interval(right).start();
+    // Sum costs of non-fixed intervals in each register
+    let mut costs = vec::from_elem(use_pos.len(), 0f64);
+    for (id, reg) in self.iter_active(state) {
+      if !self.get_interval(id).fixed {
+        let c = (*cost)(self.get_interval(id), self);
+        for &i in state.occupied(reg, self.is_paired(id)).iter() {
+          costs[i] += c;
+        }
+      }
+    }
+    for (id, reg, _) in self.iter_intersecting(current, state) {
+      if !self.get_interval(id).fixed {
+        let c = (*cost)(self.get_interval(id), self);
+        for &i in state.occupied(reg, self.is_paired(id)).iter() {
+          costs[i] += c;
+        }
+      }
+    }
 
-      lstart <= rstart
+    // Unused registers keep the highest weight and blocked ones - zero.
+    // Others are ranked by cost, the cheapest gets the highest weight
+    let available = do use_pos.map() |&pos| {
+      pos != uint::max_value && pos > start
     };
+    let mut weights = ~[];
+    for (i, &pos) in use_pos.iter().enumerate() {
+      if pos == uint::max_value {
+        weights.push(pos);
+      } else if !available[i] {
+        weights.push(0);
+      } else {
+        let mut weight = 1;
+        for (j, &other) in costs.iter().enumerate() {
+          if available[j] && other > costs[i] {
+            weight += 1;
+          }
+        }
+        weights.push(weight);
+      }
+    }
+    return weights;
   }
 
-  fn get_hint(&mut self, current: IntervalId) -> Option<R> {
-    match self.get_interval(&current).hint {
-      Some(ref id) => match self.get_interval(id).value {
-        RegisterVal(ref r) => {
-          assert!(r.group() == self.get_interval(&current).value.group());
-          Some(r.clone())
-        },
-        _ => None
+  fn second_chance<'r>(&'r mut self,
+                       current: IntervalId,
+                       state: &'r mut AllocatorState<G, R>) {
+    let value = self.get_interval(&current).value.clone();
+    match value {
+      StackVal(_, _) => (),
+      _ => return
+    }
+
+    let start = self.get_interval(&current).start();
+    match self.lifetime_free_reg(current, start, state) {
+      Some(r) => {
+        // Release stack slot, split parent using it has already ended
+        state.to_handled(&value);
+        self.get_mut_interval(&current).value =
+            RegisterVal(RegisterHelper::from_uint(state.group, r));
+        self.emit(Assigned(current, r));
       },
-      None => None
+      None => ()
     }
   }
 
-  fn split<'r>(&'r mut self,
-               current: IntervalId,
-               conf: SplitConf,
-               state: &'r mut AllocatorState<G, R>) -> IntervalId {
-    let split_pos = match conf {
-      Between(start, end) => self.optimal_split_pos(state.group, start, end),
-      At(pos) => pos
-    };
+  fn reload_spilled<'r>(&'r mut self,
+                        position: InstrId,
+                        released: bool,
+                        state: &'r mut AllocatorState<G, R>) {
+    // Moves could be inserted only at gaps
+    let mut pos = position;
+    while self.instructions.contains_key(&pos.to_uint()) &&
+          !self.is_gap(&pos) {
+      pos = pos.next();
+    }
+    let has_gap = self.instructions.contains_key(&pos.to_uint());
+
+    let spilled = state.spilled.clone();
+    state.spilled = ~[];
+    for id in spilled.iter() {
+      let value = self.get_interval(id).value.clone();
+      if position > self.get_interval(id).end() {
+        // Spilled interval is handled, its slot could be used by others
+        state.to_handled(&value);
+        loop;
+      }
 
-    let res = self.split_at(&current, split_pos);
-    state.unhandled.push(res);
-    self.sort_unhandled(state);
+      // Reload is useful only if there are uses left
+      let candidate = released && has_gap &&
+                      self.get_interval(id).start() < pos &&
+                      self.get_interval(id).covers(pos) &&
+                      self.get_interval(id).next_use(pos).is_some();
+      if !candidate || self.lifetime_free_reg(*id, pos, state).is_none() {
+        state.spilled.push(*id);
+        loop;
+      }
+
+      // Child keeps the slot until it gets register in `second_chance`,
+      // and the slot is released there
+      let child = self.split(*id, At(pos), state);
+      self.get_mut_interval(&child).value = value;
+    }
+  }
+
+  fn lifetime_free_reg<'r>(&'r self,
+                           current: IntervalId,
+                           from: InstrId,
+                           state: &'r AllocatorState<G, R>) -> Option<uint> {
+    let mut free_pos = state.initial_pos();
+    for (id, reg) in self.iter_active(state) {
+      for &i in state.occupied(reg, self.is_paired(id)).iter() {
+        free_pos[i] = 0;
+      }
+    }
+    for id in state.inactive.iter() {
+      let pos = match self.get_intersection_after(id, &current, from) {
+        Some(pos) => pos,
+        None => loop
+      };
+      let reg = match self.get_interval(id).value {
+        RegisterVal(ref reg) => reg,
+        _ => fail!("Expected register in inactive")
+      };
+      for &i in state.occupied(reg, self.is_paired(id)).iter() {
+        if free_pos[i] > pos.to_uint() {
+          free_pos[i] = pos.to_uint();
+        }
+      }
+    }
+
+    if self.is_paired(&current) {
+      free_pos = state.pair_pos(free_pos);
+    }
+
+    // Find register that is free until the interval's end
+    let end = self.get_interval(&current).end().to_uint();
+    let mut reg = None;
+    for (i, &pos) in free_pos.iter().enumerate() {
+      if pos < end {
+        loop;
+      }
+      reg = match reg {
+        Some(r) if !state.prefer(&None, i, r) => Some(r),
+        _ => Some(i)
+      };
+    }
+    return reg;
+  }
+
+  fn fast_assign<'r>(&'r mut self,
+                     state: &'r mut AllocatorState<G, R>) -> bool {
+    // Clobbered registers, fixed uses and pairs need splitting machinery
+    if state.active.len() != 0 {
+      return false;
+    }
+    for id in state.unhandled.iter() {
+      let interval = self.get_interval(id);
+      if interval.paired || !interval.value.is_virtual() ||
+         interval.uses.iter().any(|u| u.kind.is_fixed()) {
+        return false;
+      }
+    }
+
+    // Intervals are sorted by start, so greedy choice fails only if more
+    // values than registers are live at some position
+    let initial = state.initial_pos();
+    let mut live: ~[(InstrId, uint)] = ~[];
+    let mut assigned = ~[];
+    let mut fits = true;
+    for id in state.unhandled.iter() {
+      let start = self.get_interval(id).start();
+      do live.retain |&(end, _)| { end > start };
+
+      let mut occupied = ~[];
+      for &(_, r) in live.iter() {
+        let reg: R = RegisterHelper::from_uint(state.group, r);
+        occupied.push_all(state.aliased(&reg));
+      }
+
+      let hint = self.get_hint(*id);
+      let mut reg = None;
+      for &i in state.candidates().iter() {
+        if initial[i] == 0 || occupied.contains(&i) {
+          loop;
+        }
+        reg = match reg {
+          Some(r) if !state.prefer(&hint, i, r) => Some(r),
+          _ => Some(i)
+        };
+      }
+
+      match reg {
+        Some(r) => {
+          live.push((self.get_interval(id).end(), r));
+          assigned.push(*id);
+          self.get_mut_interval(id).value =
+              RegisterVal(RegisterHelper::from_uint(state.group, r));
+        },
+        None => {
+          fits = false;
+          break;
+        }
+      }
+    }
+
+    if !fits {
+      for id in assigned.iter() {
+        self.get_mut_interval(id).value = VirtualVal(*state.group.clone());
+      }
+      return false;
+    }
+    state.unhandled.clear();
+    return true;
+  }
+
+  fn blocking_interval(&self,
+                       current: IntervalId,
+                       reg: uint,
+                       pos: InstrId,
+                       state: &AllocatorState<G, R>) -> Option<IntervalId> {
+    let paired = self.is_paired(&current);
+    let blocks = |id: &IntervalId, r: &R| {
+      (self.get_interval(id).fixed || self.is_no_spill(id)) &&
+      state.occupied(r, false).iter().any(|&i| {
+        i == reg || paired && i == reg + 1
+      })
+    };
+
+    for (id, r) in self.iter_active(state) {
+      if blocks(id, r) {
+        return Some(*id);
+      }
+    }
+
+    let mut res = None;
+    let mut first = pos.next();
+    for (id, r, at) in self.iter_intersecting(current, state) {
+      if at < first && blocks(id, r) {
+        first = at;
+        res = Some(*id);
+      }
+    }
+    return res;
+  }
+
+  fn iter_active<'r>(&'r self, state: &'r AllocatorState<G, R>)
+      -> iterator::Map<'r,
+                       &IntervalId,
+                       (&IntervalId, &R),
+                       vec::VecIterator<IntervalId> > {
+    state.active.iter().map(|id| {
+      match self.get_interval(id).value {
+        RegisterVal(ref reg) => (id, reg),
+        _ => fail!("Expected register in active")
+      }
+    })
+  }
+
+  // Iterate through all inactive intervals that are intersecting with current
+  fn iter_intersecting<'r>(&'r self,
+                           current: IntervalId,
+                           state: &'r AllocatorState<G, R>)
+      -> iterator::FilterMap<'r,
+                             &IntervalId,
+                             (&IntervalId, &R, InstrId),
+                             vec::VecIterator<IntervalId> > {
+    state.inactive.iter().filter_map(|id| {
+      match self.get_intersection(id, &current) {
+        Some(pos) => match self.get_interval(id).value {
+          RegisterVal(ref reg) => Some((id, reg, pos)),
+          _ => fail!("Expected register in inactive")
+        },
+        None => None
+      }
+    })
+  }
+
+  fn insert_unhandled<'r>(&'r mut self,
+                          current: IntervalId,
+                          state: &'r mut AllocatorState<G, R>) {
+    // Binary search for the position after the last interval that should
+    // be allocated before or together with `current`
+    let mut low = 0;
+    let mut high = state.unhandled.len();
+    while low < high {
+      let middle = (low + high) / 2;
+      if !self.allocated_before(&current, &state.unhandled[middle]) {
+        low = middle + 1;
+      } else {
+        high = middle;
+      }
+    }
+    state.unhandled.insert(low, current);
+
+    self.verify_unhandled(state);
+  }
+
+  fn allocated_before(&self, a: &IntervalId, b: &IntervalId) -> bool {
+    let a_start = self.get_interval(a).start();
+    let b_start = self.get_interval(b).start();
+    if a_start != b_start {
+      return a_start < b_start;
+    }
+    return match self.config.interval_order {
+      StartOrder => false,
+      LongestFirst => self.interval_length(a) > self.interval_length(b),
+      CostliestFirst => self.spill_cost(a) > self.spill_cost(b),
+      FixedFirst => {
+        self.get_interval(a).next_fixed_use(InstrId(0)).is_some() &&
+        self.get_interval(b).next_fixed_use(InstrId(0)).is_none()
+      }
+    };
+  }
+
+  fn interval_length(&self, id: &IntervalId) -> uint {
+    let mut res = 0;
+    for range in self.get_interval(id).ranges.iter() {
+      res += range.end.to_uint() - range.start.to_uint();
+    }
+    return res;
+  }
+
+  fn spill_cost(&self, id: &IntervalId) -> f64 {
+    let mut res = 0f64;
+    for u in self.get_interval(id).uses.iter() {
+      res += self.get_block(&self.block_of(&u.pos)).cost();
+    }
+    return res;
+  }
+
+  fn emit(&self, event: AllocEvent) {
+    match self.config.on_event {
+      Some(ref f) => (*f)(event),
+      None => ()
+    }
+  }
+
+  fn get_hint(&mut self, current: IntervalId) -> Option<R> {
+    let group = self.get_interval(&current).value.group();
+    let mut visited = ~BitvSet::new();
+    let mut result = None;
+
+    // Register given by user is preferred by every split child
+    let parent = match self.get_interval(&current).parent {
+      Some(parent) => parent,
+      None => current
+    };
+    match self.get_interval(&parent).reg_hint {
+      Some(ref r) => { return Some(r.clone()); },
+      None => ()
+    }
+
+    visited.insert(current.to_uint());
+    let mut next = self.get_interval(&current).hint;
+    loop {
+      let id = match next {
+        // Hints might form a cycle (i.e. through phis)
+        Some(id) if visited.insert(id.to_uint()) => id,
+        _ => break
+      };
+
+      match self.get_interval(&id).value {
+        // NOTE: hint might be in other group (i.e. phi input), ignore it
+        RegisterVal(ref r) if r.group() == group => {
+          result = Some(r.clone());
+        },
+        _ => ()
+      }
+      next = self.get_interval(&id).hint;
+    }
+    return result;
+  }
+
+  fn split<'r>(&'r mut self,
+               current: IntervalId,
+               conf: SplitConf,
+               state: &'r mut AllocatorState<G, R>) -> IntervalId {
+    let split_pos = match conf {
+      Between(start, end) => self.optimal_split_pos(state.group, start, end),
+      At(pos) => pos
+    };
+
+    // Don't put moves between instruction and the one it is glued to
+    let split_pos = self.unsticky_split_pos(&current, split_pos);
+
+    let res = self.split_at(&current, split_pos);
+    self.get_mut_stats(state.group).splits += 1;
+    self.emit(Split(current, split_pos, res));
+    self.insert_unhandled(res, state);
+    return res;
+  }
+
+  fn get_spill<'r>(&'r mut self,
+                   current: IntervalId,
+                   state: &'r mut AllocatorState<G, R>) -> Value<G, R> {
+    // Rematerialized values do not occupy stack slots
+    let res = match self.remat_instr(&current) {
+      Some(instr) => RematVal(*state.group.clone(), instr),
+      None if self.is_paired(&current) => state.get_pair_spill(),
+      None => state.get_spill()
+    };
+    match res {
+      StackVal(_, slot) => self.emit(Spilled(current, slot)),
+      _ => ()
+    }
     return res;
   }
 
@@ -573,15 +1588,18 @@ impl<G: GroupHelper<R>,
     };
     let start = self.get_interval(&current).start();
 
-    // Filter out intersecting intervals
+    // Filter out intersecting intervals using register or its aliases
+    let regs = state.occupied(&reg, self.is_paired(&current));
     let mut to_split = ~[];
     for (id, _reg) in self.iter_active(state) {
-      if _reg == &reg {
+      let other = state.occupied(_reg, self.is_paired(id));
+      if other.iter().any(|r| regs.contains(r)) {
         to_split.push(id);
       }
     }
     for (id, _reg, _) in self.iter_intersecting(current, state) {
-      if _reg == &reg {
+      let other = state.occupied(_reg, self.is_paired(id));
+      if other.iter().any(|r| regs.contains(r)) {
         to_split.push(id);
       }
     }
@@ -600,11 +1618,31 @@ impl<G: GroupHelper<R>,
         None => self.get_interval(id).start()
       };
 
-      let spill_child = self.split(*id, Between(last_use, spill_pos), state);
-      self.get_mut_interval(&spill_child).value = state.get_spill();
+      // Store at the first gap after the last use
+      let mut store_pos = spill_pos;
+      if self.config.early_store {
+        let mut pos = last_use.next();
+        while pos < spill_pos && !self.is_gap(&pos) {
+          pos = pos.next();
+        }
+        store_pos = pos;
+      }
 
-      // Split before next register use position
-      match self.get_interval(&spill_child).next_use(spill_pos) {
+      let spill_child = self.split(*id, Between(last_use, store_pos), state);
+      self.get_mut_interval(&spill_child).value =
+          self.get_spill(spill_child, state);
+
+      // Split before next register use position, rematerialized value
+      // can't satisfy any use, so it is split before uses of any kind.
+      // NOTE: reload child with fixed use is getting the fixed register
+      // right away in `allocate_free_reg`/`allocate_blocked_reg`, so the
+      // reload doesn't need another move before the use
+      let next_use = if self.get_interval(&spill_child).value.is_remat() {
+        self.get_interval(&spill_child).next_any_kind_use(spill_pos)
+      } else {
+        self.get_interval(&spill_child).next_use(spill_pos)
+      };
+      match next_use {
         Some(u) => {
           self.split(*id, Between(spill_pos, u.pos), state);
         },
@@ -615,7 +1653,158 @@ impl<G: GroupHelper<R>,
     };
   }
 
-  fn resolve_data_flow(&mut self, list: &[BlockId]) {
+  fn split_around_uses<'r>(&'r mut self,
+                           current: IntervalId,
+                           state: &'r mut AllocatorState<G, R>) {
+    let mut uses = self.get_interval(&current).uses.clone();
+    do uses.retain |u| {
+      !u.kind.is_any() && !u.kind.is_prefer()
+    };
+
+    for u in uses.iter() {
+      // Split at the gap before and after the use
+      let before = if self.is_gap(&u.pos) { u.pos } else { u.pos.prev() };
+      let mut after = u.pos.next();
+      while self.instructions.contains_key(&after.to_uint()) &&
+            !self.is_gap(&after) {
+        after = after.next();
+      }
+
+      for pos in [before, after].iter() {
+        // Interval might have a hole or end there
+        match self.child_at(&current, *pos) {
+          Some(child) if self.get_interval(&child).start() < *pos => {
+            self.split(current, At(*pos), state);
+          },
+          _ => ()
+        }
+      }
+    }
+  }
+
+  fn check_graph(&self) -> Result<(), ~str> {
+    // Report all problems found by validation
+    return match self.validate() {
+      Ok(_) => Ok(()),
+      Err(errors) => Err(errors.connect("\n"))
+    };
+  }
+
+  fn coalesce(&mut self) -> uint {
+    // Collect moves inserted by splits and phis
+    let mut moves = ~[];
+    for (_, state) in self.gaps.iter() {
+      for action in state.actions.iter() {
+        moves.push((action.from, action.to));
+      }
+    }
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        ToPhi(_) => {
+          let input = self.get_output(&instr.inputs[0]);
+          let output = instr.output.expect("ToPhi output");
+          match (self.child_at(&input, instr.id),
+                 self.child_at(&output, instr.id)) {
+            (Some(from), Some(to)) => moves.push((from, to)),
+            _ => ()
+          }
+        },
+        _ => ()
+      }
+    }
+
+    // Only intervals alive in the same blocks could interfere
+    let mut index = SmallIntMap::new();
+    for (_, interval) in self.intervals.iter() {
+      match interval.value {
+        RegisterVal(_) => (),
+        _ => loop
+      }
+      for block in self.covered_blocks(&interval.id).iter() {
+        if !index.contains_key(block) {
+          index.insert(*block, ~[]);
+        }
+        match index.find_mut(block) {
+          Some(list) => list.push(interval.id),
+          None => ()
+        }
+      }
+    }
+
+    let mut count = 0;
+    for &(from, to) in moves.iter() {
+      if self.coalesce_pair(from, to, &index) {
+        count += 1;
+      }
+    }
+    return count;
+  }
+
+  fn coalesce_pair(&mut self,
+                   from: IntervalId,
+                   to: IntervalId,
+                   index: &SmallIntMap<~[IntervalId]>) -> bool {
+    // Moves of paired values are left as is
+    if self.is_paired(&from) || self.is_paired(&to) {
+      return false;
+    }
+
+    let reg = match (self.get_interval(&from).value.clone(),
+                     self.get_interval(&to).value.clone()) {
+      (RegisterVal(ref f), RegisterVal(ref t))
+          if f != t && f.group() == t.group() => f.clone(),
+      _ => return false
+    };
+
+    // Fixed uses should keep their registers
+    let has_fixed = {
+      let to_int = self.get_interval(&to);
+      to_int.fixed || to_int.uses.iter().any(|u| u.kind.is_fixed())
+    };
+    if has_fixed {
+      return false;
+    }
+
+    // Register (and its aliases) should be free during the whole lifetime
+    // of `to`
+    let aliases = reg.aliases();
+    for block in self.covered_blocks(&to).iter() {
+      let list = match index.find(block) {
+        Some(list) => list,
+        None => loop
+      };
+      for id in list.iter() {
+        let occupied = match self.get_interval(id).value {
+          RegisterVal(ref r) => r == &reg || aliases.contains(r),
+          _ => false
+        };
+        if *id != to && occupied &&
+           self.get_intersection(id, &to).is_some() {
+          return false;
+        }
+      }
+    }
+
+    self.get_mut_interval(&to).value = RegisterVal(reg);
+    return true;
+  }
+
+  fn covered_blocks(&self, id: &IntervalId) -> ~[uint] {
+    // Flat blocks are numbered in order of their positions
+    let mut res = ~[];
+    for range in self.get_interval(id).ranges.iter() {
+      let first = self.block_of(&range.start).to_uint();
+      let last = self.block_of(&range.end.prev()).to_uint();
+      for block in iterator::range(first, last + 1) {
+        if !res.contains(&block) {
+          res.push(block);
+        }
+      }
+    }
+    return res;
+  }
+
+  fn resolve_data_flow(&mut self, list: &[BlockId]) -> Result<(), ~str> {
     for block_id in list.iter() {
       let block_end = self.get_block(block_id).end().prev();
       let successors = self.get_block(block_id).successors.clone();
@@ -623,6 +1812,9 @@ impl<G: GroupHelper<R>,
         let succ_start = self.get_block(succ_id).start().clone();
         let live_in = self.get_block(succ_id).live_in.clone();
 
+        // All moves of the edge are placed in the same gap
+        let gap_pos = self.edge_move_pos(block_id, succ_id);
+
         for interval in live_in.iter() {
           let interval_id = IntervalId(interval);
           let parent = match self.get_interval(&interval_id).parent {
@@ -630,26 +1822,111 @@ impl<G: GroupHelper<R>,
             None => interval_id
           };
 
-          let from = self.child_at(&parent, block_end)
-                         .expect("Interval should exist at pred end");
-          let to = self.child_at(&parent, succ_start)
-                       .expect("Interval should exist at succ start");
+          let from = match self.child_at(&parent, block_end) {
+            Some(from) => from,
+            None => {
+              return Err(fmt!("Interval %u is not defined at the end of %u",
+                              parent.to_uint(),
+                              block_id.to_uint()));
+            }
+          };
+          let to = match self.child_at(&parent, succ_start) {
+            Some(to) => to,
+            None => {
+              return Err(fmt!("Interval %u is not defined at the start of %u",
+                              parent.to_uint(),
+                              succ_id.to_uint()));
+            }
+          };
           if from != to {
-            let gap_pos = if successors.len() == 2 {
-              succ_start
-            } else {
-              block_end
-            };
             self.get_mut_gap(&gap_pos).add_move(&from, &to);
           }
         }
       }
     }
+    return Ok(());
+  }
+
+  fn resolve_tied_inputs(&mut self) -> Result<(), ~str> {
+    let mut moves = ~[];
+    for (_, instr) in self.instructions.iter() {
+      match (instr.kind.tied_input(), instr.output) {
+        (Some(i), Some(output)) => {
+          moves.push((instr.id.prev(), self.get_output(&instr.inputs[i]),
+                      output));
+        },
+        _ => ()
+      }
+    }
+
+    for &(gap, input, output) in moves.iter() {
+      let from = match self.child_at(&input, gap) {
+        Some(from) => from,
+        None => {
+          return Err(fmt!("Interval %u is not defined at %u",
+                          input.to_uint(),
+                          gap.to_uint()));
+        }
+      };
+
+      // Output starts right after the gap, so its register is free there.
+      // NOTE: output usually reuses register of input that dies
+      if self.get_interval(&from).value != self.get_interval(&output).value {
+        self.get_mut_gap(&gap).add_move(&from, &output);
+      }
+    }
+    return Ok(());
+  }
+
+  fn resolve_cross_inputs(&mut self) -> Result<(), ~str> {
+    let mut moves = ~[];
+    for (_, instr) in self.instructions.iter() {
+      for &(i, tmp) in instr.cross_inputs.iter() {
+        moves.push((instr.id.prev(), self.get_output(&instr.inputs[i]), tmp));
+      }
+    }
+
+    for &(gap, input, tmp) in moves.iter() {
+      let from = match self.child_at(&input, gap) {
+        Some(from) => from,
+        None => {
+          return Err(fmt!("Interval %u is not defined at %u",
+                          input.to_uint(),
+                          gap.to_uint()));
+        }
+      };
+
+      // Moves in gap are parallel, child created by split in the same gap
+      // isn't holding value yet - read from its source instead
+      let mut source = from;
+      if self.get_interval(&from).start() == gap {
+        match self.gaps.find(&gap.to_uint()) {
+          Some(state) => for action in state.actions.iter() {
+            if action.to == from {
+              source = action.from;
+            }
+          },
+          None => ()
+        }
+      }
+
+      let to = self.child_at(&tmp, gap).expect("Cross input interval");
+      self.get_mut_gap(&gap).add_move(&source, &to);
+    }
+    return Ok(());
   }
 
   fn build_ranges(&mut self, blocks: &[BlockId])
       -> Result<(), ~str> {
     let physical = self.physical.clone();
+
+    // Register lists are requested once, groups may have lots of registers
+    let groups: ~[G] = GroupHelper::groups();
+    let mut registers = SmallIntMap::new();
+    for group in groups.iter() {
+      registers.insert(group.to_uint(), group.registers());
+    }
+
     for block_id in blocks.rev_iter() {
       let instructions = self.get_block(block_id).instructions.clone();
       let live_out = self.get_block(block_id).live_out.clone();
@@ -665,15 +1942,31 @@ impl<G: GroupHelper<R>,
       }
 
       for &instr_id in instructions.rev_iter() {
-        let instr = self.get_instr(&instr_id).clone();
+        let mut instr = self.get_instr(&instr_id).clone();
 
         // Call instructions should swap out all used registers into stack slots
-        let groups: ~[G] = GroupHelper::groups();
         for group in groups.iter() {
           self.physical.insert(group.to_uint(), ~SmallIntMap::new());
-          if instr.kind.clobbers(group) {
-            let regs = group.registers();
+          if instr.clobbers(group) {
+            // Safepoints are clobbering all registers, calls might clobber
+            // only some of them
+            let clobbered = if instr.spill_all {
+              ~[]
+            } else {
+              let all: &[R] = *registers.get(&group.to_uint());
+              instr.kind.clobbered_regs(group, all)
+            };
+            let regs = if instr.spill_all {
+              registers.get(&group.to_uint())
+            } else {
+              &clobbered
+            };
             for reg in regs.iter() {
+              // Callee-saved registers survive calls, but not safepoints
+              if self.config.preserve_callee_saved &&
+                 reg.is_callee_saved() && !instr.spill_all {
+                loop;
+              }
               self.get_mut_interval(physical.get(&group.to_uint())
                   .get(&reg.to_uint()))
                   .add_range(instr_id, instr_id.next());
@@ -681,12 +1974,22 @@ impl<G: GroupHelper<R>,
           }
         }
 
+        // Registers written as a side effect are blocked only at instruction
+        for reg in instr.kind.fixed_clobbers().iter() {
+          if instr.clobbers(&reg.group()) {
+            loop;
+          }
+          self.get_mut_interval(physical.get(&reg.group().to_uint())
+              .get(&reg.to_uint()))
+              .add_range(instr_id, instr_id.next());
+        }
+
         // Process output
         match instr.output {
           Some(output) => {
             // Call instructions are defining their value after the call
             let group = self.get_interval(&output).value.group();
-            let pos = if instr.kind.clobbers(&group) {
+            let pos = if instr.clobbers(&group) {
               instr_id.next()
             } else {
               instr_id
@@ -699,32 +2002,217 @@ impl<G: GroupHelper<R>,
               // Add short range otherwise
               self.get_mut_interval(&output).add_range(pos, pos.next());
             }
-            let out_kind = instr.kind.result_kind().unwrap();
+            // Pre-colored outputs are defined in a fixed register
+            let out_kind = match self.get_interval(&output).value {
+              RegisterVal(ref reg) => UseFixed(reg.clone()),
+              _ => instr.kind.result_kind().unwrap()
+            };
+            if out_kind.is_fixed() {
+              self.get_mut_interval(&output).value = VirtualVal(group.clone());
+            }
             self.get_mut_interval(&output).add_use(out_kind, pos);
+
+            // Paired values occupy two registers, constant-like values
+            // could be recomputed instead of spilling
+            if instr.kind.is_paired() {
+              self.get_mut_interval(&output).paired = true;
+            } else if instr.kind.is_rematerializable() &&
+                      instr.inputs.len() == 0 {
+              self.get_mut_interval(&output).remat = Some(instr_id);
+            }
           },
           None => ()
         }
 
+        // Values that are live across instruction reading all registers
+        // should be in registers there
+        for group in groups.iter() {
+          if !instr.kind.reads_all(group) {
+            loop;
+          }
+          // Inputs and outputs are getting their own uses
+          let inputs = do instr.inputs.map() |i| { self.get_output(i) };
+          let mut live = ~[];
+          for (_, interval) in self.intervals.iter() {
+            if !interval.fixed &&
+               interval.value.group() == *group &&
+               interval.covers(instr_id) &&
+               Some(interval.id) != instr.output &&
+               !inputs.contains(&interval.id) &&
+               !instr.temporary.contains(&interval.id) {
+              live.push(interval.id);
+            }
+          }
+          for id in live.iter() {
+            self.get_mut_interval(id).add_use(UseRegister(group.clone()),
+                                              instr_id);
+          }
+        }
+
         // Process temporary
-        for tmp in instr.temporary.iter() {
+        for (i, tmp) in instr.temporary.iter().enumerate() {
           let group = self.get_interval(tmp).value.group();
-          if instr.kind.clobbers(&group) {
+          if instr.clobbers(&group) {
             return Err(~"Call instruction can't have temporary registers");
           }
+
+          // Temporary might need a specific register
+          let kind = instr.kind.temporary_kind(i);
+          if kind.group() != group {
+            return Err(fmt!("Temporary %u of instruction %u has wrong group",
+                            i,
+                            instr_id.to_uint()));
+          }
           self.get_mut_interval(tmp).add_range(instr_id, instr_id.next());
-          self.get_mut_interval(tmp).add_use(group.use_reg(), instr_id);
+          self.get_mut_interval(tmp).add_use(kind, instr_id);
+        }
+
+        // Put input, that dies at commutative instruction, first - so
+        // output could reuse its register
+        if instr.kind.is_commutative() && instr.inputs.len() == 2 {
+          let dies = do instr.inputs.map() |input| {
+            !self.get_interval(&self.get_output(input)).covers(instr_id)
+          };
+          if !dies[0] && dies[1] {
+            instr.inputs.swap(0, 1);
+            let stored = self.get_mut_instr(&instr_id);
+            stored.inputs.swap(0, 1);
+            stored.swapped = !stored.swapped;
+          }
+          match instr.output {
+            Some(output) if dies[0] || dies[1] => {
+              let input = self.get_output(&instr.inputs[0]);
+              if self.get_interval(&output).hint.is_none() {
+                self.get_mut_interval(&output).hint = Some(input);
+              }
+            },
+            _ => ()
+          }
+        }
+
+        // Conditionally defined output prefers previous value's register, so
+        // implicit phi movements could be eliminated
+        match (instr.kind.maybe_def(), instr.output) {
+          (Some(i), Some(output)) => {
+            let input = self.get_output(&instr.inputs[i]);
+            if self.get_interval(&output).hint.is_none() {
+              self.get_mut_interval(&output).hint = Some(input);
+            }
+          },
+          _ => ()
+        }
+
+        // Output of two-address instruction prefers tied input's register
+        let tied = instr.kind.tied_input();
+        match (tied, instr.output) {
+          (Some(i), Some(output)) => {
+            let input = self.get_output(&instr.inputs[i]);
+            if self.get_interval(&output).hint.is_none() {
+              self.get_mut_interval(&output).hint = Some(input);
+            }
+          },
+          _ => ()
         }
 
         // Process inputs
         for (i, input_instr) in instr.inputs.iter().enumerate() {
           let input = self.get_output(input_instr);
+          // Tied input is copied into output's register before instruction,
+          // other inputs should not share register with output
+          let end = match tied {
+            Some(t) if t != i && instr.output.is_some() => instr_id.next(),
+            _ => instr_id
+          };
           if !self.get_interval(&input).covers(instr_id) {
-            self.get_mut_interval(&input).add_range(block_from, instr_id);
+            self.get_mut_interval(&input).add_range(block_from, end);
+          }
+          // Phi inputs might come from other groups
+          let mut kind = match instr.kind {
+            ToPhi(_) => UseAny(self.get_interval(&input).value.group()),
+            _ => instr.kind.use_kind(i)
+          };
+
+          // Input might be pinned to register by user
+          for &(index, reg) in instr.fixed_inputs.iter() {
+            if index == i {
+              let group = self.get_interval(&input).value.group();
+              kind = UseFixed(RegisterHelper::from_uint(&group, reg));
+            }
+          }
+
+          // Same value might be required in different fixed registers
+          let mut conflict = false;
+          if self.config.copy_fixed_conflicts {
+            for u in self.get_interval(&input).uses.iter() {
+              if u.pos != instr_id {
+                break;
+              }
+              match (&u.kind, &kind) {
+                (&UseFixed(ref a), &UseFixed(ref b)) if a != b => {
+                  conflict = true;
+                },
+                _ => ()
+              }
+            }
+          }
+
+          // Use in other group or conflicting fixed use: copy value into a
+          // short interval of that group in the gap before instruction
+          if conflict ||
+             kind.group() != self.get_interval(&input).value.group() {
+            let mut existing = None;
+            for &(index, tmp) in instr.cross_inputs.iter() {
+              if index == i {
+                existing = Some(tmp);
+              }
+            }
+            let tmp = match existing {
+              Some(tmp) => tmp,
+              None => {
+                let tmp = Interval::<G, R>::new::<K>(self, kind.group());
+                self.get_mut_instr(&instr_id).cross_inputs.push((i, tmp));
+                tmp
+              }
+            };
+            self.get_mut_interval(&tmp).add_range(instr_id.prev(), instr_id);
+            self.get_mut_interval(&tmp).add_use(kind, instr_id);
+            loop;
           }
-          let kind = instr.kind.use_kind(i);
           self.get_mut_interval(&input).add_use(kind, instr_id);
         }
       }
+
+      // Loop-carried values are redefined at the end of the loop and are
+      // live again at its header: extend them over the whole loop, so that
+      // each of them stays allocated (in register or on stack) at every
+      // position of the loop and is available on the back edge
+      let predecessors = self.get_block(block_id).predecessors.clone();
+      let live_in = self.get_block(block_id).live_in.clone();
+      let (index, depth) = {
+        let block = self.get_block(block_id);
+        (block.loop_index, block.loop_depth)
+      };
+      let is_header = depth != 0 && match self.loop_headers.find(&index) {
+        Some(header) => header == block_id,
+        None => false
+      };
+      for pred in predecessors.iter() {
+        // Back edge: predecessor is inside of the loop headed by block,
+        // either directly or in one of the nested loops
+        let pred_block = self.get_block(pred);
+        if !is_header ||
+           !(pred_block.loop_index == index || pred_block.loop_depth > depth) {
+          loop;
+        }
+        let loop_end = self.get_block(pred).end();
+        let kill = self.get_block(pred).live_kill.clone();
+        for int_id in live_in.iter() {
+          if kill.contains(&int_id) {
+            self.get_mut_interval(&IntervalId(int_id))
+                .add_range(block_from, loop_end);
+          }
+        }
+      }
     }
 
     // Now split all intervals with fixed uses
@@ -761,51 +2249,300 @@ impl<G: GroupHelper<R>,
     }
   }
 
-  #[cfg(test)]
-  fn verify(&self) {
+  fn verify(&self) -> Result<(), ~str> {
+    let mut errors = ~[];
     for (_, interval) in self.intervals.iter() {
+      let id = interval.id.to_uint();
+
+      // Intervals in aliasing registers should not intersect
+      match interval.value {
+        RegisterVal(ref r) => {
+          let mut aliases = r.aliases();
+
+          // High half of the pair is occupied too
+          if self.is_paired(&interval.id) {
+            if r.to_uint() % 2 != 0 {
+              errors.push(fmt!("Paired interval %u is in odd register", id));
+            }
+            match interval.value.pair_high() {
+              RegisterVal(high) => {
+                aliases.push_all(high.aliases());
+                aliases.push(high);
+              },
+              _ => errors.push(fmt!("Interval %u has no high register", id))
+            }
+          }
+          for (_, other) in self.intervals.iter() {
+            match other.value {
+              RegisterVal(ref o) if aliases.contains(o) => {
+                if self.get_intersection(&interval.id, &other.id).is_some() {
+                  errors.push(fmt!("Intervals %u and %u are intersecting \
+                                    in aliased registers",
+                                   id,
+                                   other.id.to_uint()));
+                }
+              },
+              _ => ()
+            }
+          }
+        },
+        _ => ()
+      }
+
       if interval.ranges.len() > 0 {
         // Every interval should have a non-virtual value
-        assert!(!interval.value.is_virtual());
+        if interval.value.is_virtual() {
+          errors.push(fmt!("Interval %u is not allocated", id));
+        }
 
         // Each use should receive the same type of input as it has requested
         for u in interval.uses.iter() {
+          let pos = u.pos.to_uint();
+
           // Allocated groups should not differ from specified
-          assert!(u.kind.group() == interval.value.group());
-          match u.kind {
-            // Any use - no restrictions
-            UseAny(_) => (),
+          if u.kind.group() != interval.value.group() {
+            errors.push(fmt!("Use of interval %u at %u is in other group",
+                             id,
+                             pos));
+          }
+          let ok = match u.kind {
+            // Any use - anything, except rematerialized value
+            UseAny(_) => !interval.value.is_remat(),
+            // Preferred register - same, stack slot is acceptable too
+            UsePrefer(_) => !interval.value.is_remat(),
             UseRegister(_) => match interval.value {
-              RegisterVal(_) => (), // ok
-              _ => fail!("Register expected")
+              RegisterVal(_) => true,
+              _ => false
             },
             UseFixed(ref r0) => match interval.value {
-              RegisterVal(ref r1) if r0 == r1 => (), // ok
-              _ => fail!("Expected fixed register")
+              RegisterVal(ref r1) => r0 == r1,
+              _ => false
             }
+          };
+          if !ok {
+            errors.push(fmt!("Use of interval %u at %u got %?",
+                             id,
+                             pos,
+                             interval.value));
           }
         }
       }
     }
+
+    // Instruction that shouldn't be separated from the next one has no moves
+    // after it
+    for (pos, gap) in self.gaps.iter() {
+      if gap.actions.len() != 0 && self.sticky_gap(&InstrId(pos)) {
+        errors.push(fmt!("Gap %u has moves, but it is glued to instruction %u",
+                         pos,
+                         pos - 1));
+      }
+    }
+
+    if errors.len() == 0 {
+      return Ok(());
+    }
+    return Err(errors.connect("\n"));
+  }
+
+  #[cfg(test)]
+  fn verify_unhandled(&self, state: &AllocatorState<G, R>) {
+    let mut i = 1;
+    while i < state.unhandled.len() {
+      assert!(!self.allocated_before(&state.unhandled[i],
+                                     &state.unhandled[i - 1]));
+      i += 1;
+    }
   }
   #[cfg(not(test))]
-  fn verify(&self) {
+  fn verify_unhandled(&self, _: &AllocatorState<G, R>) {
     // Production mode, no verification
   }
 }
 
+impl<K, G, R> Config<K, G, R> {
+  pub fn new() -> Config<K, G, R> {
+    Config {
+      register_count: ~SmallIntMap::new(),
+      second_chance: false,
+      use_weight: distance_weight,
+      spill_cost: None,
+      ordering: LoopAware,
+      swap: true,
+      memory_moves: true,
+      eliminate_dead: false,
+      coalesce: false,
+      merge_splits: false,
+      skip_unreachable: false,
+      scratch: ~SmallIntMap::new(),
+      reserved: ~[],
+      spill_everywhere: false,
+      greedy_any: false,
+      fast_path: false,
+      split_limit: 16,
+      max_spill_slots: None,
+      reuse_spill_slots: true,
+      tie_break: LowestIndex,
+      copy_fixed_conflicts: false,
+      early_store: false,
+      align_splits: true,
+      interval_order: StartOrder,
+      preserve_callee_saved: false,
+      verify: verify_by_default(),
+      on_event: None
+    }
+  }
+}
+
+impl AllocatorResult {
+  /// Return true if any value was spilled to stack
+  pub fn spilled(&self) -> bool {
+    return self.total_spills() != 0;
+  }
+
+  /// Return number of spill slots used in all groups, see `spill_count`
+  pub fn total_spills(&self) -> uint {
+    let mut res = 0;
+    for count in self.spill_count.iter() {
+      res += *count;
+    }
+    return res;
+  }
+}
+
+// Tests are always verifying allocation results
+#[cfg(test)]
+fn verify_by_default() -> bool { true }
+#[cfg(not(test))]
+fn verify_by_default() -> bool { false }
+
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > AllocatorState<G, R> {
+  // Return register indexes in the order they should be considered
+  fn candidates(&self) -> ~[uint] {
+    let count = self.register_count;
+    match self.tie_break {
+      LowestIndex => vec::from_fn(count, |i| i),
+      HighestIndex => vec::from_fn(count, |i| count - i - 1),
+      RoundRobin => vec::from_fn(count, |i| (self.next_reg + i) % count)
+    }
+  }
+
+  // Return per-register positions, registers that are not available for
+  // allocation are blocked from the very start
+  fn initial_pos(&self) -> ~[uint] {
+    let mut res = do vec::from_fn(self.register_count) |i| {
+      if i < self.register_limit {
+        uint::max_value
+      } else {
+        0
+      }
+    };
+
+    // Scratch register and its aliases are never allocated
+    match self.scratch {
+      Some(r) => {
+        let reg: R = RegisterHelper::from_uint(self.group, r);
+        for &i in self.aliased(&reg).iter() {
+          res[i] = 0;
+        }
+      },
+      None => ()
+    }
+
+    // So are reserved ones
+    for &r in self.reserved.iter() {
+      let reg: R = RegisterHelper::from_uint(self.group, r);
+      for &i in self.aliased(&reg).iter() {
+        res[i] = 0;
+      }
+    }
+    return res;
+  }
+
+  // Return true if register `candidate` should be preferred over `best`
+  // when both are free for the same time
+  fn prefer(&self, hint: &Option<R>, candidate: uint, best: uint) -> bool {
+    match *hint {
+      Some(ref h) if h.to_uint() == candidate => return true,
+      Some(ref h) if h.to_uint() == best => return false,
+      _ => ()
+    }
+
+    // Callee-saved registers require saving in prelude
+    let c: R = RegisterHelper::from_uint(self.group, candidate);
+    let b: R = RegisterHelper::from_uint(self.group, best);
+    return !c.is_callee_saved() && b.is_callee_saved();
+  }
+
+  // Return indexes of register and all registers aliasing it
+  fn aliased(&self, reg: &R) -> ~[uint] {
+    let mut res = ~[reg.to_uint()];
+    for alias in reg.aliases().iter() {
+      assert!(alias.group() == *self.group);
+      res.push(alias.to_uint());
+    }
+    return res;
+  }
+
+  // Return indexes of registers occupied by interval in `reg`
+  fn occupied(&self, reg: &R, paired: bool) -> ~[uint] {
+    let mut res = self.aliased(reg);
+    if paired {
+      let high: R = RegisterHelper::from_uint(self.group, reg.to_uint() + 1);
+      res.push_all(self.aliased(&high));
+    }
+    return res;
+  }
+
+  // Return positions for pairs: each even register gets the minimum of its
+  // and next register's position, odd registers are never allocated for pairs
+  fn pair_pos(&self, pos: ~[uint]) -> ~[uint] {
+    do vec::from_fn(pos.len()) |i| {
+      if i % 2 == 0 && i + 1 < pos.len() {
+        uint::min(pos[i], pos[i + 1])
+      } else {
+        0
+      }
+    }
+  }
+
+  // Record failure if `count` new slots won't fit into the limit
+  fn reserve_spills(&mut self, count: uint) {
+    match self.max_spills {
+      Some(max) => {
+        let used = self.spill_count / self.group.spill_size();
+        if used + count > max {
+          self.spill_overflow = true;
+        }
+      },
+      None => ()
+    }
+  }
+
+  fn get_pair_spill(&mut self) -> Value<G, R> {
+    // NOTE: slots from spill list are single-width, allocate a new one
+    self.reserve_spills(2);
+    let slot = self.spill_base + self.spill_count;
+    self.spill_count += 2 * self.group.spill_size();
+    StackVal(*self.group.clone(), StackId(slot))
+  }
+
   fn get_spill(&mut self) -> Value<G, R> {
     return if self.spills.len() > 0 {
       self.spills.shift()
     } else {
-      let slot = self.spill_count;
-      self.spill_count += 1;
+      // NOTE: spill area is aligned, so every slot is aligned too
+      self.reserve_spills(1);
+      let slot = self.spill_base + self.spill_count;
+      self.spill_count += self.group.spill_size();
       StackVal(*self.group.clone(), StackId(slot))
     }
   }
 
   fn to_handled(&mut self, value: &Value<G, R>) {
+    if !self.reuse_spills {
+      return;
+    }
     match value {
       &StackVal(ref group, slot) => {
         self.spills.push(StackVal(group.clone(), slot))
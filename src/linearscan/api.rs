@@ -1,13 +1,26 @@
 // Private imports
 use linearscan::graph::{Block, Instruction, User, Phi, ToPhi,
-                        UseAny, UseRegister, UseFixed};
+                        UseAny, UseRegister, UsePrefer, UseFixed};
 
 // Public API
-pub use linearscan::graph::{Graph, UseKind,
-                            BlockId, InstrId, StackId,
-                            Value, RegisterVal, StackVal};
-pub use linearscan::allocator::{Allocator};
-pub use linearscan::generator::{Generator, GeneratorFunctions};
+pub use linearscan::graph::{Graph, UseKind, Use, LiveRange,
+                            BlockId, InstrId, IntervalId, StackId,
+                            Value, RegisterVal, StackVal, Statistics,
+                            AllocEstimate};
+pub use linearscan::allocator::{Allocator, AllocatorResult, Config,
+                                TieBreak, LowestIndex, HighestIndex,
+                                RoundRobin, IntervalOrder, StartOrder,
+                                LongestFirst, CostliestFirst, FixedFirst,
+                                AllocEvent, Spilled, Split, Assigned,
+                                distance_weight, loop_depth_weight};
+pub use linearscan::gap::{GapResolver};
+pub use linearscan::liveness::{Liveness};
+pub use linearscan::flatten::{BlockOrder, LoopAware, ReversePostorder};
+pub use linearscan::dot::{Dot};
+pub use linearscan::json::{AllocationJson};
+pub use linearscan::generator::{Generator, GeneratorFunctions,
+                                RewriteTable, InstrAssignment,
+                                SpillOp, SpillOpKind, Store, Reload};
 
 struct BlockBuilder<'self, K, G, R> {
   graph: &'self mut Graph<K, G, R>,
@@ -19,17 +32,32 @@ pub trait GroupHelper<Register>: Clone+Eq {
   fn registers(&self) -> ~[Register];
   fn to_uint(&self) -> uint;
   fn from_uint(i: uint) -> Self;
+
+  /// Number of registers in group, override it if building the list of
+  /// registers is expensive
+  fn register_count(&self) -> uint { self.registers().len() }
+
+  /// Size (and alignment) of group's spill slot in bytes, by default stack
+  /// ids are just indexes of slots
+  fn spill_size(&self) -> uint { 1 }
 }
 
 pub trait RegisterHelper<Group>: Clone+Eq {
   fn group(&self) -> Group;
   fn to_uint(&self) -> uint;
   fn from_uint(g: &Group, i: uint) -> Self;
+
+  /// Return registers overlapping with this one (i.e. `ax` and `eax`)
+  fn aliases(&self) -> ~[Self] { ~[] }
+
+  /// Return true if register is preserved across calls
+  fn is_callee_saved(&self) -> bool { false }
 }
 
 pub trait GroupAutoHelper<Register> {
   fn use_any(&self) -> UseKind<Self, Register>;
   fn use_reg(&self) -> UseKind<Self, Register>;
+  fn use_prefer(&self) -> UseKind<Self, Register>;
 }
 
 pub trait RegisterAutoHelper<Group> {
@@ -41,6 +69,65 @@ pub trait KindHelper<G: GroupHelper<R>, R: RegisterHelper<G> >: Clone {
   fn temporary(&self) -> ~[G];
   fn use_kind(&self, i: uint) -> UseKind<G, R>;
   fn result_kind(&self) -> Option<UseKind<G, R> >;
+
+  /// Return registers of `group` that are clobbered by instruction, by
+  /// default clobbering instructions are clobbering all registers of group.
+  /// NOTE: `registers` is the group's register list requested once by
+  /// allocator
+  fn clobbered_regs(&self, group: &G, registers: &[R]) -> ~[R] {
+    if self.clobbers(group) {
+      registers.to_owned()
+    } else {
+      ~[]
+    }
+  }
+
+  /// Return registers that are written by instruction regardless of its
+  /// operands (i.e. `mul` writing `rdx`). Unlike calls, values are only
+  /// moved out of these registers
+  fn fixed_clobbers(&self) -> ~[R] { ~[] }
+
+  /// Return true if instruction may read any register of `group` (i.e.
+  /// inline assembly), every value of group that is live at instruction is
+  /// kept in register there
+  fn reads_all(&self, _group: &G) -> bool { false }
+
+  /// Return true if no moves should be inserted right after instruction
+  /// (i.e. it produces flags that are consumed by the next instruction)
+  fn no_spill_after(&self) -> bool { false }
+
+  /// Return index of input that should share register with instruction's
+  /// output (i.e. two-address instructions like `add rax, rbx`)
+  fn tied_input(&self) -> Option<uint> { None }
+
+  /// Return index of input whose value is kept in output on paths that
+  /// don't pass through instruction's block (i.e. lowered `cmov`). Output
+  /// is merged with that input in block's successors via an implicit phi
+  fn maybe_def(&self) -> Option<uint> { None }
+
+  /// Return true if instruction without inputs is cheaper to recompute
+  /// than to spill and reload (i.e. constants)
+  fn is_rematerializable(&self) -> bool { false }
+
+  /// Return true if instruction's output occupies a pair of adjacent
+  /// registers (even and odd ones) or a double-width spill slot
+  fn is_paired(&self) -> bool { false }
+
+  /// Return use kind of `i`th temporary operand, by default temporaries
+  /// could be placed in any register of their group
+  fn temporary_kind(&self, i: uint) -> UseKind<G, R> {
+    UseRegister(self.temporary()[i].clone())
+  }
+
+  /// Return true if instruction's two inputs could be swapped
+  fn is_commutative(&self) -> bool { false }
+
+  /// Return false if instruction may be removed when its output is unused
+  fn has_side_effects(&self) -> bool { true }
+
+  /// Return true if instruction could end block without successors (i.e.
+  /// `return` or `throw`), by default any instruction without output could
+  fn is_return(&self) -> bool { self.result_kind().is_none() }
 }
 
 pub trait GraphAPI<K: KindHelper<G, R>,
@@ -53,12 +140,20 @@ pub trait GraphAPI<K: KindHelper<G, R>,
                 id: BlockId,
                 body: &fn(b: &mut BlockBuilder<K, G, R>));
   fn new_instr(&mut self, kind: K, args: ~[InstrId]) -> InstrId;
+  fn empty_block_with_id(&mut self, id: BlockId) -> BlockId;
+  fn new_instr_with_id(&mut self,
+                       id: InstrId,
+                       kind: K,
+                       args: ~[InstrId]) -> InstrId;
   fn set_root(&mut self, id: BlockId);
+  fn spill_all_at(&mut self, id: InstrId);
+  fn no_spill(&mut self, id: InstrId);
 }
 
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > GroupAutoHelper<R> for G {
   fn use_any(&self) -> UseKind<G, R> { UseAny(self.clone()) }
   fn use_reg(&self) -> UseKind<G, R> { UseRegister(self.clone()) }
+  fn use_prefer(&self) -> UseKind<G, R> { UsePrefer(self.clone()) }
 }
 
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > RegisterAutoHelper<G> for R {
@@ -70,6 +165,7 @@ impl<G: GroupHelper<R>,
      K: KindHelper<G, R> > GraphAPI<K, G, R> for Graph<K, G, R> {
   /// Create empty block
   pub fn empty_block(&mut self) -> BlockId {
+    assert!(!self.prepared);
     let block = ~Block::new(self);
     let id = block.id;
     self.blocks.insert(id.to_uint(), block);
@@ -97,6 +193,14 @@ impl<G: GroupHelper<R>,
     return res;
   }
 
+  /// Attach phi to the block it is defined in. Phi is attached by `goto` of
+  /// any block giving it an input, so this is needed only when all its
+  /// inputs are coming from blocks with several successors
+  pub fn attach_phi(&mut self, phi: InstrId, block: BlockId) {
+    assert!(self.phis.contains(&phi));
+    self.phi_blocks.insert(phi.to_uint(), block);
+  }
+
   /// Perform operations on block
   pub fn with_block(&mut self,
                     id: BlockId,
@@ -113,10 +217,57 @@ impl<G: GroupHelper<R>,
     return Instruction::new(self, User(kind), args);
   }
 
+  /// Create empty block with id chosen by user (i.e. for deterministic
+  /// tests or ids from other IR), id must not be used by other block.
+  /// Blocks created later are numbered after the largest id
+  pub fn empty_block_with_id(&mut self, id: BlockId) -> BlockId {
+    assert!(!self.prepared);
+    assert!(!self.blocks.contains_key(&id.to_uint()));
+    let next = self.block_id;
+    self.block_id = id.to_uint();
+    let res = self.empty_block();
+    if next > self.block_id {
+      self.block_id = next;
+    }
+    return res;
+  }
+
+  /// Create instruction with id chosen by user, id must not be used by
+  /// other instruction. Instructions created later are numbered after the
+  /// largest id
+  pub fn new_instr_with_id(&mut self,
+                           id: InstrId,
+                           kind: K,
+                           args: ~[InstrId]) -> InstrId {
+    assert!(!self.prepared);
+    assert!(!self.instructions.contains_key(&id.to_uint()));
+    let next = self.instr_id;
+    self.instr_id = id.to_uint();
+    let res = self.new_instr(kind, args);
+    if next > self.instr_id {
+      self.instr_id = next;
+    }
+    return res;
+  }
+
   /// Set graph's root block
   pub fn set_root(&mut self, id: BlockId) {
     self.root = Some(id);
   }
+
+  /// Force every value that is live across instruction to be spilled
+  pub fn spill_all_at(&mut self, id: InstrId) {
+    assert!(!self.prepared);
+    self.get_mut_instr(&id).spill_all = true;
+  }
+
+  /// Keep instruction's output in register for its whole lifetime,
+  /// allocation fails if it is impossible (i.e. value is live across call)
+  pub fn no_spill(&mut self, id: InstrId) {
+    assert!(!self.prepared);
+    let out = self.get_instr(&id).output.expect("No-spill instruction output");
+    self.get_mut_interval(&out).no_spill = true;
+  }
 }
 
 impl<'self,
@@ -132,6 +283,31 @@ impl<'self,
     return instr_id;
   }
 
+  /// add instruction with id chosen by user to block
+  pub fn add_with_id(&mut self,
+                     id: InstrId,
+                     kind: K,
+                     args: ~[InstrId]) -> InstrId {
+    let instr_id = self.graph.new_instr_with_id(id, kind, args);
+
+    self.add_existing(instr_id);
+
+    return instr_id;
+  }
+
+  /// add instruction to block, and put its output in the fixed register
+  pub fn add_fixed(&mut self, kind: K, args: ~[InstrId], reg: R) -> InstrId {
+    let instr_id = self.add(kind, args);
+
+    // NOTE: pre-colored value is turned into a fixed use in `build_ranges`
+    let out = self.graph.get_instr(&instr_id).output
+                  .expect("Fixed instruction output");
+    assert!(self.graph.get_interval(&out).value.group() == reg.group());
+    self.graph.get_mut_interval(&out).value = RegisterVal(reg);
+
+    return instr_id;
+  }
+
   /// add existing instruction to block
   pub fn add_existing(&mut self, instr_id: InstrId) {
     assert!(!self.graph.get_instr(&instr_id).added);
@@ -143,6 +319,78 @@ impl<'self,
     block.instructions.push(instr_id);
   }
 
+  /// make instruction's output prefer `reg` (i.e. when it'll be a call
+  /// argument), the hint is ignored at fixed uses and if `reg` is busy
+  pub fn set_hint(&mut self, instr: InstrId, reg: R) {
+    let out = self.graph.get_instr(&instr).output.expect("Hinted output");
+    assert!(self.graph.get_interval(&out).value.group() == reg.group());
+    self.graph.get_mut_interval(&out).reg_hint = Some(reg);
+  }
+
+  /// make instruction's output prefer register of `other`'s output, it
+  /// replaces hints added by `to_phi`
+  pub fn set_hint_to(&mut self, instr: InstrId, other: InstrId) {
+    let out = self.graph.get_instr(&instr).output.expect("Hinted output");
+    let hint = self.graph.get_instr(&other).output.expect("Hint output");
+    self.graph.get_mut_interval(&out).hint = Some(hint);
+  }
+
+  /// add terminator returning `args` in `regs` (i.e. ABI return registers),
+  /// and end block. Values are moved to their registers right before it.
+  pub fn add_return(&mut self,
+                    kind: K,
+                    args: ~[InstrId],
+                    regs: ~[R]) -> InstrId {
+    assert!(args.len() == regs.len());
+    let instr_id = self.add(kind, args);
+
+    // NOTE: pinned inputs are turned into fixed uses in `build_ranges`
+    for (i, reg) in regs.iter().enumerate() {
+      self.graph.get_mut_instr(&instr_id).fixed_inputs.push((i, reg.to_uint()));
+    }
+    self.end();
+
+    return instr_id;
+  }
+
+  /// insert instruction into block right before `anchor`
+  /// NOTE: allowed only before allocation, block might be already ended
+  pub fn insert_before(&mut self,
+                       anchor: InstrId,
+                       kind: K,
+                       args: ~[InstrId]) -> InstrId {
+    let index = self.anchor_index(anchor);
+    return self.insert_at(index, kind, args);
+  }
+
+  /// insert instruction into block right after `anchor`
+  /// NOTE: allowed only before allocation, block might be already ended
+  pub fn insert_after(&mut self,
+                      anchor: InstrId,
+                      kind: K,
+                      args: ~[InstrId]) -> InstrId {
+    let index = self.anchor_index(anchor);
+    return self.insert_at(index + 1, kind, args);
+  }
+
+  fn anchor_index(&self, anchor: InstrId) -> uint {
+    // Positions are assigned during allocation
+    assert!(!self.graph.prepared);
+    let block = self.graph.get_block(&self.block);
+    match block.instructions.position_elem(&anchor) {
+      Some(index) => index,
+      None => fail!("Anchor instruction is not in block")
+    }
+  }
+
+  fn insert_at(&mut self, index: uint, kind: K, args: ~[InstrId]) -> InstrId {
+    let instr_id = self.graph.new_instr(kind, args);
+    self.graph.get_mut_instr(&instr_id).added = true;
+    self.graph.get_mut_instr(&instr_id).block = self.block;
+    self.graph.get_mut_block(&self.block).instructions.insert(index, instr_id);
+    return instr_id;
+  }
+
   /// add arg to existing instruction in block
   pub fn add_arg(&mut self, id: InstrId, arg: InstrId) {
     assert!(self.graph.get_instr(&id).block == self.block);
@@ -150,6 +398,8 @@ impl<'self,
   }
 
   /// add phi movement to block
+  /// NOTE: should be called once in every predecessor of phi's block, there
+  /// is no limit on the number of predecessors
   pub fn to_phi(&mut self, input: InstrId, phi: InstrId) {
     let group = match self.graph.get_instr(&phi).kind {
       Phi(ref group) => group.clone(),
@@ -168,14 +418,15 @@ impl<'self,
     self.graph.get_mut_instr(&res).output = Some(out);
     self.add_existing(res);
     self.graph.get_mut_instr(&phi).inputs.push(res);
-    assert!(self.graph.get_instr(&phi).inputs.len() <= 2);
   }
 
   /// end block
+  /// NOTE: only blocks passing control to successors could be empty, they
+  /// get positions (gaps) when graph is flattened
   pub fn end(&mut self) {
     let block = self.graph.get_mut_block(&self.block);
     assert!(!block.ended);
-    assert!(block.instructions.len() > 0);
+    assert!(block.instructions.len() > 0 || block.successors.len() > 0);
     block.ended = true;
   }
 
@@ -183,6 +434,18 @@ impl<'self,
   pub fn goto(&mut self, target_id: BlockId) {
     self.graph.get_mut_block(&self.block).add_successor(target_id);
     self.graph.get_mut_block(&target_id).add_predecessor(self.block);
+
+    // Phis getting values from this block are defined in its successor
+    let instructions = self.graph.get_block(&self.block).instructions.clone();
+    let phis = self.graph.phis.clone();
+    for phi in phis.iter() {
+      let attached = self.graph.get_instr(phi).inputs.iter().any(|input| {
+        instructions.contains(input)
+      });
+      if attached && !self.graph.phi_blocks.contains_key(&phi.to_uint()) {
+        self.graph.phi_blocks.insert(phi.to_uint(), target_id);
+      }
+    }
     self.end();
   }
 
@@ -195,8 +458,35 @@ impl<'self,
     self.end();
   }
 
+  /// add `left` and `right` to block's successors, and ask flattener to
+  /// place `fallthrough` (one of them) right after block. The hint is
+  /// ignored if loop bodies can't be kept contiguous otherwise
+  pub fn branch_hinted(&mut self,
+                       left: BlockId,
+                       right: BlockId,
+                       fallthrough: BlockId) {
+    assert!(fallthrough == left || fallthrough == right);
+    self.graph.get_mut_block(&self.block).fallthrough = Some(fallthrough);
+    self.branch(left, right);
+  }
+
+  /// add every block in `targets` to block's successors
+  pub fn switch(&mut self, targets: ~[BlockId]) {
+    for target in targets.iter() {
+      self.graph.get_mut_block(&self.block).add_successor(*target);
+      self.graph.get_mut_block(target).add_predecessor(self.block);
+    }
+    self.end();
+  }
+
   /// mark block as root
   pub fn make_root(&mut self) {
     self.graph.set_root(self.block);
   }
+
+  /// set block's execution frequency (i.e. from profile), it is used
+  /// instead of loop depth when choosing spill and split positions
+  pub fn set_frequency(&mut self, frequency: f64) {
+    self.graph.get_mut_block(&self.block).frequency = Some(frequency);
+  }
 }
@@ -0,0 +1,71 @@
+use extra::bitv::BitvSet;
+use linearscan::{KindHelper, GroupHelper, RegisterHelper};
+use linearscan::graph::{Graph, Block};
+
+pub trait Dot {
+  // Export control-flow graph in GraphViz format
+  fn to_dot(&self) -> ~str;
+}
+
+trait DotHelper<K> {
+  fn dot_block(&self, block: &Block<K>) -> ~str;
+  fn dot_set(&self, set: &BitvSet) -> ~str;
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > DotHelper<K> for Graph<K, G, R> {
+  fn dot_block(&self, block: &Block<K>) -> ~str {
+    let mut label = fmt!("b%u", block.id.to_uint());
+
+    if block.instructions.len() == 0 {
+      label.push_str("\\nempty");
+    } else {
+      label.push_str(fmt!("\\n[%u, %u)",
+                          block.start().to_uint(),
+                          block.end().to_uint()));
+    }
+    label.push_str(fmt!("\\ndepth: %u", block.loop_depth));
+
+    // Liveness is available only after preparation
+    if self.prepared {
+      label.push_str(fmt!("\\nin: %s", self.dot_set(block.live_in)));
+      label.push_str(fmt!("\\nout: %s", self.dot_set(block.live_out)));
+    } else {
+      label.push_str("\\nunprepared");
+    }
+
+    return fmt!("  b%u [label=\"%s\"];\n", block.id.to_uint(), label);
+  }
+
+  fn dot_set(&self, set: &BitvSet) -> ~str {
+    let items: ~[~str] = set.iter().map(|i| i.to_str()).collect();
+    return ~"{" + items.connect(", ") + "}";
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > Dot for Graph<K, G, R> {
+  fn to_dot(&self) -> ~str {
+    let mut result = ~"digraph {\n";
+
+    // Export blocks
+    for (_, block) in self.blocks.iter() {
+      result.push_str(self.dot_block(*block));
+    }
+
+    // Export edges, after flattening loop ends are jumping backwards
+    for (_, block) in self.blocks.iter() {
+      for succ in block.successors.iter() {
+        result.push_str(fmt!("  b%u -> b%u [label=\"%s\"];\n",
+                             block.id.to_uint(),
+                             succ.to_uint(),
+                             if *succ <= block.id { "back" } else { "forward" }));
+      }
+    }
+
+    result.push_str("}\n");
+    return result;
+  }
+}
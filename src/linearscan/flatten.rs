@@ -3,6 +3,15 @@ use extra::bitv::BitvSet;
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
 use linearscan::graph::{Graph, BlockId};
 
+// Order of blocks in flattened graph
+pub enum BlockOrder {
+  // Loop bodies are placed contiguously
+  LoopAware,
+
+  // Strict reverse post-order of depth-first traversal
+  ReversePostorder
+}
+
 struct MapResult {
   block: BlockId,
   score: uint
@@ -22,6 +31,16 @@ trait FlattenHelper {
   // Assign loop_index/loop_depth to each block
   fn flatten_assign_indexes(&mut self);
 
+  // Get list of blocks in loop-aware order
+  fn flatten_loop_order(&mut self) -> ~[BlockId];
+
+  // Get list of blocks in reverse post-order
+  fn flatten_rpo(&self) -> ~[BlockId];
+
+  // Get block's successors in the order they should be visited, successor
+  // visited last is placed right after block
+  fn flatten_successors(&self, block: &BlockId) -> ~[BlockId];
+
   // Assign new ids to blocks and instructions
   fn flatten_reindex_blocks(&mut self, list: &[BlockId]) -> ~[BlockId];
   fn flatten_reindex_instructions(&mut self, list: &[BlockId]);
@@ -66,6 +85,8 @@ impl<G: GroupHelper<R>,
       let mut queue = ~[];
       let expected_depth = self.get_block(&start_id).loop_depth;
 
+      self.loop_headers.insert(loop_index, start_id);
+
       // Decrement number of incoming forward branches
       assert!(self.get_block(&start_id).incoming_forward_branches == 2);
       self.get_mut_block(&start_id).incoming_forward_branches -= 1;
@@ -101,6 +122,81 @@ impl<G: GroupHelper<R>,
     }
   }
 
+  fn flatten_loop_order(&mut self) -> ~[BlockId] {
+    let mut queue = ~[self.root.expect("Root block")];
+    let mut list = ~[];
+    let mut visited = ~BitvSet::new();
+
+    // Visit each block and its successors
+    while queue.len() > 0 {
+      let cur = queue.shift();
+
+      // Skip visited blocks
+      if !visited.insert(cur.to_uint()) { loop; }
+
+      list.push(cur);
+
+      // Visit successors if they've no unvisited incoming forward edges
+      let successors = self.flatten_successors(&cur);
+      for succ_id in successors.iter() {
+        let succ = self.get_mut_block(succ_id);
+        if succ.incoming_forward_branches == 0 {
+          loop;
+        }
+
+        succ.incoming_forward_branches -= 1;
+        if succ.incoming_forward_branches == 0 {
+          queue.unshift(*succ_id);
+        }
+      }
+    }
+
+    return list;
+  }
+
+  fn flatten_rpo(&self) -> ~[BlockId] {
+    let root = self.root.expect("Root block");
+    let mut visited = ~BitvSet::new();
+    let mut postorder = ~[];
+
+    // Stack of blocks with index of the next successor to visit
+    let mut stack = ~[(root, 0u)];
+    visited.insert(root.to_uint());
+    while stack.len() > 0 {
+      let (cur, i) = *stack.last();
+      let successors = self.flatten_successors(&cur);
+      if i < successors.len() {
+        let len = stack.len();
+        stack[len - 1] = (cur, i + 1);
+        if visited.insert(successors[i].to_uint()) {
+          stack.push((successors[i], 0));
+        }
+      } else {
+        postorder.push(cur);
+        stack.pop();
+      }
+    }
+
+    postorder.reverse();
+    return postorder;
+  }
+
+  fn flatten_successors(&self, block: &BlockId) -> ~[BlockId] {
+    let block = self.get_block(block);
+    let mut res = block.successors.clone();
+    match block.fallthrough {
+      // Leaving loop before its body is laid out would split the loop
+      Some(fallthrough) if self.get_block(&fallthrough).loop_depth <
+                           block.loop_depth => (),
+      Some(fallthrough) => {
+        do res.retain |succ| { *succ != fallthrough };
+        res.push(fallthrough);
+      },
+      None => ()
+    }
+    return res;
+  }
+
   fn flatten_reindex_blocks(&mut self, list: &[BlockId]) -> ~[BlockId] {
     let mut block_id = 0;
     let mut queue = ~[];
@@ -140,9 +236,33 @@ impl<G: GroupHelper<R>,
       block.predecessors = do block.predecessors.map() |pred| {
         *mapping.find(&pred.to_uint()).expect("predecessor")
       };
+      block.fallthrough = match block.fallthrough {
+        Some(f) => Some(*mapping.find(&f.to_uint()).expect("fallthrough")),
+        None => None
+      };
       self.blocks.insert(block.id.to_uint(), block);
     }
 
+    // Update blocks of phis
+    let mut phi_blocks = ~SmallIntMap::new();
+    for (&phi, block) in self.phi_blocks.iter() {
+      match mapping.find(&block.to_uint()) {
+        Some(block) => { phi_blocks.insert(phi, *block); },
+        None => ()
+      }
+    }
+    self.phi_blocks = phi_blocks;
+
+    // Update loop headers
+    let mut headers = ~SmallIntMap::new();
+    for (&index, header) in self.loop_headers.iter() {
+      headers.insert(index, *mapping.find(&header.to_uint()).expect("header"));
+    }
+    self.loop_headers = headers;
+
+    // Keep mapping to translate ids given at construction
+    self.block_map = mapping;
+
     return result;
   }
 
@@ -182,17 +302,18 @@ impl<G: GroupHelper<R>,
           queue.push(gap);
         }
       }
-      if list.len() != 0 {
-        let end_gap = self.create_gap(block);
-        new_list.push(end_gap.id);
-        queue.push(end_gap);
-      }
+      // NOTE: empty blocks (i.e. split edges) are getting both gaps too,
+      // so moves from predecessor and to successor won't be mixed
+      let end_gap = self.create_gap(block);
+      new_list.push(end_gap.id);
+      queue.push(end_gap);
 
       // Replace block's instruction list
       self.get_mut_block(block).instructions = new_list;
     }
 
     // Add phis to queue
+    let mut phi_blocks = ~SmallIntMap::new();
     let mut i = 0;
     while i < self.phis.len() {
       let mut phi = self.instructions.pop(&self.phis[i].to_uint())
@@ -202,13 +323,19 @@ impl<G: GroupHelper<R>,
       let id = self.instr_id();
       map.insert(phi.id.to_uint(), id);
 
-      // Update id
+      // Update id and recorded block
+      match self.phi_blocks.pop(&phi.id.to_uint()) {
+        Some(block) => { phi_blocks.insert(id.to_uint(), block); },
+        None => ()
+      }
       phi.id = id;
+      self.phis[i] = id;
 
       // Queue phi
       queue.push(phi);
       i += 1;
     }
+    self.phi_blocks = phi_blocks;
 
     // Remove all other instructions
     self.instructions.clear();
@@ -227,6 +354,9 @@ impl<G: GroupHelper<R>,
 
       self.instructions.insert(instr.id.to_uint(), instr);
     }
+
+    // Keep mapping to translate ids given at construction
+    self.instr_map = map;
   }
 }
 
@@ -236,33 +366,10 @@ impl<G: GroupHelper<R>,
   fn flatten(&mut self) {
     self.flatten_assign_indexes();
 
-    let mut queue = ~[self.root.expect("Root block")];
-    let mut list = ~[];
-    let mut visited = ~BitvSet::new();
-
-    // Visit each block and its successors
-    while queue.len() > 0 {
-      let cur = queue.shift();
-
-      // Skip visited blocks
-      if !visited.insert(cur.to_uint()) { loop; }
-
-      list.push(cur);
-
-      // Visit successors if they've no unvisited incoming forward edges
-      let successors = self.get_block(&cur).successors.clone();
-      for succ_id in successors.iter() {
-        let succ = self.get_mut_block(succ_id);
-        if succ.incoming_forward_branches == 0 {
-          loop;
-        }
-
-        succ.incoming_forward_branches -= 1;
-        if succ.incoming_forward_branches == 0 {
-          queue.unshift(*succ_id);
-        }
-      }
-    }
+    let mut list = match self.config.ordering {
+      LoopAware => self.flatten_loop_order(),
+      ReversePostorder => self.flatten_rpo()
+    };
 
     // Assign flat ids to every block
     list = self.flatten_reindex_blocks(list);
@@ -1,7 +1,8 @@
 use std::vec;
 use linearscan::*;
-use linearscan::graph::{Graph, InstrId, GapState, GapAction,
-                        Move, Swap};
+use linearscan::graph::{Graph, Instruction, InstrId, IntervalId, GapState,
+                        GapAction, Movement, ToPhi, Move, Swap, CrossMove,
+                        Value, RegisterVal};
 
 #[deriving(Eq)]
 enum MoveStatus {
@@ -11,11 +12,28 @@ enum MoveStatus {
 }
 
 pub trait GapResolver {
-  fn resolve_gaps(&mut self);
+  fn resolve_gaps(&mut self) -> Result<(), ~str>;
+
+  // Replace resolved gap states with movement instructions in blocks
+  fn flush_moves(&mut self);
+
+  // Check that executing `sequential` actions one by one delivers value of
+  // every source in `parallel` ones to its destination
+  fn check_gap(&self,
+               parallel: &[GapAction],
+               sequential: &[GapAction]) -> Result<(), ~str>;
 }
 
-trait GapResolverHelper {
-  fn resolve_gap(&mut self, id: &InstrId) -> ~GapState;
+trait GapResolverHelper<G> {
+  fn resolve_gap(&mut self, id: &InstrId) -> Result<~GapState, ~str>;
+  fn lower_swaps(&self, actions: ~[GapAction]) -> ~[GapAction];
+  fn lower_memory_moves(&self,
+                        actions: ~[GapAction]) -> Result<~[GapAction], ~str>;
+  fn check_phi_moves(&self) -> Result<(), ~str>;
+  fn count_actions(&mut self);
+  fn verify_gap(&self, parallel: &[GapAction], sequential: &[GapAction]);
+  fn scratch_register(&self, group: &G) -> Option<IntervalId>;
+  fn overlaps(&self, a: &IntervalId, b: &IntervalId) -> bool;
   fn move_one(&mut self,
               actions: &[GapAction],
               i: uint,
@@ -26,24 +44,130 @@ trait GapResolverHelper {
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R>+Clone> GapResolver for Graph<K, G, R> {
-  fn resolve_gaps(&mut self) {
+  fn resolve_gaps(&mut self) -> Result<(), ~str> {
     let mut keys = ~[];
     for (id, _) in self.gaps.iter() {
       keys.push(InstrId(*id));
     }
     for id in keys.iter() {
-      let state = self.resolve_gap(id);
+      match self.resolve_gap(id) {
+        // Overwrite previous state
+        Ok(state) => { self.gaps.insert(id.to_uint(), state); },
+        Err(reason) => { return Err(reason); }
+      }
+    }
+    self.count_actions();
+    return self.check_phi_moves();
+  }
+
+  fn flush_moves(&mut self) {
+    let blocks = self.get_block_list();
+    for block in blocks.iter() {
+      let instructions = self.get_block(block).instructions.clone();
+      let mut result = ~[];
+
+      for id in instructions.iter() {
+        // Moves at call instructions should happen before the call itself
+        let is_gap = self.is_gap(id);
+        if is_gap {
+          result.push(*id);
+        }
+
+        match self.gaps.pop(&id.to_uint()) {
+          Some(state) => for action in state.actions.iter() {
+            let move = Instruction::new_empty(self,
+                                              Movement(action.clone()),
+                                              ~[]);
+            self.get_mut_instr(&move).added = true;
+            self.get_mut_instr(&move).block = *block;
+            self.set_instr_block(&move, block);
+            result.push(move);
+          },
+          None => ()
+        }
 
-      // Overwrite previous state
-      self.gaps.insert(id.to_uint(), state);
+        if !is_gap {
+          result.push(*id);
+        }
+      }
+
+      // Replace block's instruction list
+      self.get_mut_block(block).instructions = result;
     }
   }
+
+  fn check_gap(&self,
+               parallel: &[GapAction],
+               sequential: &[GapAction]) -> Result<(), ~str> {
+    // Locations with their current contents, every other location holds
+    // its initial value
+    let mut contents: ~[(Value<G, R>, Value<G, R>)] = ~[];
+    fn get<G: Eq+Clone, R: Eq+Clone>(contents: &[(Value<G, R>, Value<G, R>)],
+                                     loc: &Value<G, R>) -> Value<G, R> {
+      for &(ref l, ref v) in contents.iter() {
+        if l == loc {
+          return v.clone();
+        }
+      }
+      return loc.clone();
+    }
+    fn set<G: Eq+Clone, R: Eq+Clone>(contents: &mut ~[(Value<G, R>,
+                                                       Value<G, R>)],
+                                     loc: &Value<G, R>,
+                                     value: Value<G, R>) {
+      do contents.retain |&(ref l, _)| { l != loc };
+      contents.push((loc.clone(), value));
+    }
+
+    // Both halves of paired values are moved
+    let halves = |id: &IntervalId| {
+      let value = self.get_interval(id).value.clone();
+      if self.is_paired(id) {
+        ~[value.pair_high(), value]
+      } else {
+        ~[value]
+      }
+    };
+
+    for action in sequential.iter() {
+      let from = halves(&action.from);
+      let to = halves(&action.to);
+      for (f, t) in from.iter().zip(to.iter()) {
+        let value = get(contents, f);
+        match action.kind {
+          Swap => {
+            let other = get(contents, t);
+            set(&mut contents, f, other);
+          },
+          Move | CrossMove => ()
+        }
+        set(&mut contents, t, value);
+      }
+    }
+
+    for action in parallel.iter() {
+      let from = halves(&action.from);
+      let to = halves(&action.to);
+      for (f, t) in from.iter().zip(to.iter()) {
+        // Nop moves and stores to rematerialized values are skipped
+        if f == t || t.is_remat() {
+          loop;
+        }
+        if get(contents, t) != *f {
+          return Err(fmt!("Gap move from %u to %u is not performed",
+                          action.from.to_uint(),
+                          action.to.to_uint()));
+        }
+      }
+    }
+    return Ok(());
+  }
 }
 
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
-     K: KindHelper<G, R>+Clone> GapResolverHelper for Graph<K, G, R> {
-  fn resolve_gap(&mut self, id: &InstrId) -> ~GapState {
+     K: KindHelper<G, R>+Clone> GapResolverHelper<G> for Graph<K, G, R> {
+  fn resolve_gap(&mut self, id: &InstrId) -> Result<~GapState, ~str> {
     let state = self.gaps.pop(&id.to_uint()).unwrap();
     let mut status = vec::from_elem(state.actions.len(), ToMove);
 
@@ -55,7 +179,178 @@ impl<G: GroupHelper<R>,
       }
       i += 1;
     }
-    ~GapState { actions: result }
+
+    // Moves between register groups need special instructions
+    for action in result.mut_iter() {
+      let from = self.get_interval(&action.from).value.group();
+      let to = self.get_interval(&action.to).value.group();
+      if action.kind == Move && from != to {
+        action.kind = CrossMove;
+      }
+    }
+
+    // Target can't move between stack slots, use scratch register
+    if !self.config.memory_moves {
+      match self.lower_memory_moves(result) {
+        Ok(actions) => { result = actions; },
+        Err(reason) => { return Err(reason); }
+      }
+    }
+
+    // Target can't swap values, use moves through scratch location
+    if !self.config.swap {
+      result = self.lower_swaps(result);
+    }
+    self.verify_gap(state.actions, result);
+    Ok(~GapState { actions: result })
+  }
+
+  fn lower_swaps(&self, actions: ~[GapAction]) -> ~[GapAction] {
+    let mut result = ~[];
+    for action in actions.iter() {
+      if action.kind != Swap {
+        result.push(action.clone());
+        loop;
+      }
+
+      let group = self.get_interval(&action.from).value.group();
+      let scratch = *self.scratch.find(&group.to_uint())
+                                 .expect("Scratch location");
+
+      // from => scratch, to => from, scratch => to
+      result.push(GapAction { kind: Move, from: action.from, to: scratch });
+      result.push(GapAction { kind: Move, from: action.to, to: action.from });
+      result.push(GapAction { kind: Move, from: scratch, to: action.to });
+    }
+    return result;
+  }
+
+  fn lower_memory_moves(&self,
+                        actions: ~[GapAction]) -> Result<~[GapAction], ~str> {
+    let mut result = ~[];
+    for action in actions.iter() {
+      let from = self.get_interval(&action.from).value.clone();
+      let to = self.get_interval(&action.to).value.clone();
+      let group = from.group();
+
+      // NOTE: swaps will be lowered to moves through scratch register later
+      let memory = match action.kind {
+        Move => from.is_stack() && to.is_stack(),
+        Swap => from.is_stack() && to.is_stack() ||
+                !self.config.swap && (from.is_stack() || to.is_stack()),
+        CrossMove => false
+      };
+      if !memory {
+        result.push(action.clone());
+        loop;
+      }
+
+      let scratch = match self.scratch_register(&group) {
+        Some(scratch) => scratch,
+        None => {
+          return Err(fmt!("No scratch register for stack-to-stack move in \
+                           group %u", group.to_uint()));
+        }
+      };
+
+      if action.kind == Swap {
+        if from.is_stack() && to.is_stack() {
+          return Err(fmt!("Can't swap stack slots of intervals %u and %u",
+                          action.from.to_uint(),
+                          action.to.to_uint()));
+        }
+        result.push(action.clone());
+        loop;
+      }
+
+      // from => scratch, scratch => to
+      result.push(GapAction { kind: Move, from: action.from, to: scratch });
+      result.push(GapAction { kind: Move, from: scratch, to: action.to });
+    }
+    return Ok(result);
+  }
+
+  fn check_phi_moves(&self) -> Result<(), ~str> {
+    if self.config.memory_moves {
+      return Ok(());
+    }
+
+    // Moves to phis are emitted by generator, it'll need scratch register
+    // for stack-to-stack ones
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        ToPhi(_) => {
+          let input = self.get_value(&self.get_output(&instr.inputs[0]),
+                                     instr.id).expect("ToPhi input");
+          let output = self.get_value(&instr.output.expect("ToPhi output"),
+                                      instr.id).expect("ToPhi output");
+          let group = input.group();
+          if input.is_stack() && output.is_stack() &&
+             self.scratch_register(&group).is_none() {
+            return Err(fmt!("No scratch register for stack-to-stack move in \
+                             group %u", group.to_uint()));
+          }
+        },
+        _ => ()
+      }
+    }
+    return Ok(());
+  }
+
+  fn count_actions(&mut self) {
+    let mut actions = ~[];
+    for (_, state) in self.gaps.iter() {
+      actions.push_all(state.actions);
+    }
+    for action in actions.iter() {
+      let from = self.get_interval(&action.from).value.clone();
+      let to = self.get_interval(&action.to).value.clone();
+      let stats = self.get_mut_stats(&to.group());
+      match action.kind {
+        Swap => stats.swaps += 1,
+        Move | CrossMove => stats.moves += 1
+      }
+      if from.is_remat() {
+        stats.remats += 1;
+      } else if from.is_stack() && !to.is_stack() {
+        stats.reloads += 1;
+      }
+    }
+  }
+
+  #[cfg(test)]
+  fn verify_gap(&self, parallel: &[GapAction], sequential: &[GapAction]) {
+    match self.check_gap(parallel, sequential) {
+      Ok(_) => (),
+      Err(reason) => fail!(reason)
+    }
+  }
+  #[cfg(not(test))]
+  fn verify_gap(&self, _: &[GapAction], _: &[GapAction]) {
+    // Production mode, no verification
+  }
+
+  fn scratch_register(&self, group: &G) -> Option<IntervalId> {
+    match self.scratch.find(&group.to_uint()) {
+      Some(id) => match self.get_interval(id).value {
+        RegisterVal(_) => Some(*id),
+        _ => None
+      },
+      None => None
+    }
+  }
+
+  fn overlaps(&self, a: &IntervalId, b: &IntervalId) -> bool {
+    let locations = |id: &IntervalId| {
+      let value = self.get_interval(id).value.clone();
+      if self.is_paired(id) {
+        ~[value.pair_high(), value]
+      } else {
+        ~[value]
+      }
+    };
+    let b = locations(b);
+    return locations(a).iter().any(|v| b.contains(v));
   }
 
   fn move_one(&mut self,
@@ -67,8 +362,8 @@ impl<G: GroupHelper<R>,
     let from = self.get_interval(&actions[i].from).value.clone();
     let to = self.get_interval(&actions[i].to).value.clone();
 
-    // Ignore nop moves
-    if from == to { return false; }
+    // Ignore nop moves, and stores to rematerialized values
+    if from == to || to.is_remat() { return false; }
 
     s[i] = Moving;
     let mut j = 0;
@@ -76,9 +371,8 @@ impl<G: GroupHelper<R>,
     let mut sentinel = false;
     while j < actions.len() {
       assert!(actions[j].kind == Move);
-      let other_from = self.get_interval(&actions[j].from).value.clone();
-
-      if other_from == to {
+      // NOTE: paired values might partially overlap with others
+      if self.overlaps(&actions[j].from, &actions[i].to) {
         match s[j] {
           ToMove => {
             let r = self.move_one(actions, j, s, result);
@@ -1,6 +1,9 @@
+use std::vec;
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
-use linearscan::graph::{Graph, Value, InstrId, BlockId, Gap,
-                        Phi, ToPhi, User, Swap, Move};
+use linearscan::graph::{Graph, Value, VirtualVal, RegisterVal, StackVal,
+                        RematVal, InstrId, BlockId, StackId, Gap,
+                        Phi, ToPhi, User, Movement, GapAction, Swap, Move,
+                        CrossMove};
 
 pub trait Generator<K, G> {
   fn generate(&self, g: &mut G);
@@ -21,6 +24,12 @@ pub trait GeneratorFunctions<K, G: GroupHelper<R>, R: RegisterHelper<G> > {
   /// Move value from `from` to `to`
   fn move(&mut self, from: &Value<G, R>, to: &Value<G, R>);
 
+  /// Move value between different register groups (i.e. from general
+  /// purpose register to floating point one)
+  fn cross_move(&mut self, from: &Value<G, R>, to: &Value<G, R>) {
+    self.move(from, to)
+  }
+
   /// Block start notification, might be used to relocate labels
   fn block(&mut self, id: BlockId);
 
@@ -36,8 +45,43 @@ pub trait GeneratorFunctions<K, G: GroupHelper<R>, R: RegisterHelper<G> > {
            succ: &[BlockId]);
 }
 
+pub struct InstrAssignment<G, R> {
+  id: InstrId,
+  inputs: ~[Value<G, R>],
+  output: Option<Value<G, R> >,
+  temps: ~[Value<G, R>]
+}
+
+#[deriving(Eq, Clone, ToStr)]
+pub enum SpillOpKind {
+  // Register => stack slot
+  Store,
+
+  // Stack slot => register
+  Reload
+}
+
+pub struct SpillOp<G, R> {
+  kind: SpillOpKind,
+
+  // Gap (or materialized move) performing the operation
+  pos: InstrId,
+  group: G,
+  slot: StackId,
+  register: R
+}
+
+pub trait RewriteTable<G, R> {
+  /// Return allocated values of every user instruction's operands
+  fn rewrite_table(&self) -> ~[InstrAssignment<G, R>];
+
+  /// Return allocated values of instruction's operands
+  fn get_assignment(&self, id: &InstrId) -> InstrAssignment<G, R>;
+}
+
 pub trait GeneratorHelper<K, GF> {
   fn generate_gap(&self, g: &mut GF, id: &InstrId);
+  fn generate_action(&self, g: &mut GF, action: &GapAction);
 }
 
 impl<G: GroupHelper<R>,
@@ -48,80 +92,117 @@ impl<G: GroupHelper<R>,
   fn generate(&self, g: &mut GF) {
     g.prelude();
 
-    // Invoke functions in order of increasing instruction id
-    for (id, instr) in self.instructions.iter() {
-      // Skip phis
-      match instr.kind {
-        Phi(_) => loop,
-        _ => ()
-      };
-
+    // Invoke functions in order of blocks and their instructions
+    for (_, block) in self.blocks.iter() {
       // Notify about block start
-      let block = self.get_block(&instr.block);
-      if *id == block.start().to_uint() {
-        g.block(block.id);
-      }
+      g.block(block.id);
 
-      // Call instructions and gaps have GapState
-      let is_gap = match instr.kind { Gap => true, _ => false };
-      if is_gap || self.gaps.contains_key(id) {
-        self.generate_gap(g, &InstrId(*id));
-      }
+      for (i, id) in block.instructions.iter().enumerate() {
+        let instr = self.get_instr(id);
 
-      // Non-gap instructions
-      if !is_gap {
-        // NOTE: call instruction's output is located right after instruction
-        let output = match instr.output {
-          Some(ref out) => {
-            let group = instr.kind.result_kind().unwrap().group();
-            self.get_value(out, if instr.kind.clobbers(&group) {
-              instr.id.next()
-            } else {
-              instr.id
-            })
-          },
-          None => None
-        };
-        let inputs = do instr.inputs.map() |inp| {
-          self.get_value(&self.get_output(inp), instr.id).expect("input")
-        };
-        let temporary = do instr.temporary.map() |tmp| {
-          self.get_value(tmp, instr.id).expect("temporary")
-        };
-        match instr.kind {
-          Phi(_) => (),
-          ToPhi(_) => {
-            assert!(inputs.len() == 1);
-            let out = output.expect("ToPhi output");
-            if out != inputs[0] {
-              g.move(&inputs[0], &out);
-            }
-          },
-          Gap => (), // handled separately
-          User(ref k) => g.instr(k,
-                                 output,
-                                 inputs,
-                                 temporary,
-                                 block.successors)
+        // Call instructions and gaps have GapState
+        let is_gap = match instr.kind { Gap => true, _ => false };
+        if is_gap || self.gaps.contains_key(&id.to_uint()) {
+          self.generate_gap(g, id);
         }
-      }
 
-      // Handle last instruction
-      if instr.id == block.end().prev() {
-        match block.successors.len() {
-          0 => g.epilogue(),
-          1 => if block.successors[0].to_uint() != block.id.to_uint() + 1 {
-            // Goto to non-consequent successor
-            g.goto(block.successors[0])
-          },
-          2 => (), // Should be handled in instruction
-          _ => fail!("Too much successors")
+        // Non-gap instructions
+        if !is_gap {
+          let InstrAssignment { inputs, output, temps, _ } =
+              self.get_assignment(id);
+          match instr.kind {
+            Phi(_) => (),
+            ToPhi(_) => {
+              assert!(inputs.len() == 1);
+              let out = output.expect("ToPhi output");
+              if out == inputs[0] {
+                // nop
+              } else if out.group() != inputs[0].group() {
+                g.cross_move(&inputs[0], &out);
+              } else if !self.config.memory_moves && inputs[0].is_stack() &&
+                        out.is_stack() {
+                // Move through scratch register
+                let group = out.group();
+                let scratch = self.scratch.find(&group.to_uint())
+                                          .expect("Scratch register");
+                let scratch = self.get_interval(scratch).value.clone();
+                g.move(&inputs[0], &scratch);
+                g.move(&scratch, &out);
+              } else {
+                g.move(&inputs[0], &out);
+              }
+            },
+            Gap => (), // handled separately
+            Movement(ref action) => self.generate_action(g, action),
+            User(ref k) => g.instr(k,
+                                   output,
+                                   inputs,
+                                   temps,
+                                   block.successors)
+          }
+        }
+
+        // Handle last instruction
+        if i == block.instructions.len() - 1 {
+          match block.successors.len() {
+            0 => g.epilogue(),
+            1 => if block.successors[0].to_uint() != block.id.to_uint() + 1 {
+              // Goto to non-consequent successor
+              g.goto(block.successors[0])
+            },
+            _ => () // Should be handled in instruction
+          }
         }
       }
     }
   }
 }
 
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > RewriteTable<G, R> for Graph<K, G, R> {
+  fn rewrite_table(&self) -> ~[InstrAssignment<G, R>] {
+    let mut result = ~[];
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        User(_) => result.push(self.get_assignment(&instr.id)),
+        _ => ()
+      }
+    }
+    return result;
+  }
+
+  fn get_assignment(&self, id: &InstrId) -> InstrAssignment<G, R> {
+    let instr = self.get_instr(id);
+
+    // NOTE: call instruction's output is located right after instruction
+    let output = match instr.output {
+      Some(ref out) => {
+        let group = instr.kind.result_kind().unwrap().group();
+        self.get_value(out, if instr.clobbers(&group) {
+          instr.id.next()
+        } else {
+          instr.id
+        })
+      },
+      None => None
+    };
+    let inputs = do vec::from_fn(instr.inputs.len()) |i| {
+      self.get_value(&self.get_input(&instr.id, i), instr.id).expect("input")
+    };
+    let temps = do instr.temporary.map() |tmp| {
+      self.get_value(tmp, instr.id).expect("temporary")
+    };
+
+    return InstrAssignment {
+      id: instr.id,
+      inputs: inputs,
+      output: output,
+      temps: temps
+    };
+  }
+}
+
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R>,
@@ -130,15 +211,196 @@ impl<G: GroupHelper<R>,
   fn generate_gap(&self, g: &mut GF, id: &InstrId) {
     match self.gaps.find(&id.to_uint()) {
       Some(state) => for action in state.actions.iter() {
-        let from = self.get_interval(&action.from).value.clone();
-        let to = self.get_interval(&action.to).value.clone();
+        self.generate_action(g, action);
+      },
+      None => ()
+    }
+  }
+
+  fn generate_action(&self, g: &mut GF, action: &GapAction) {
+    let from = self.get_interval(&action.from).value.clone();
+    let to = self.get_interval(&action.to).value.clone();
 
-        match action.kind {
-          Swap => g.swap(&from, &to),
-          Move => g.move(&from, &to)
+    // Reload of rematerialized value, recompute it
+    match from {
+      RematVal(_, ref id) => {
+        assert!(action.kind == Move);
+        match self.get_instr(id).kind {
+          User(ref k) => g.instr(k, Some(to), [], [], []),
+          _ => fail!("Unexpected remat instruction")
         }
+        return;
       },
-      None => ()
+      _ => ()
+    }
+
+    match action.kind {
+      Swap => g.swap(&from, &to),
+      Move => g.move(&from, &to),
+      CrossMove => g.cross_move(&from, &to)
+    }
+
+    // High halves of paired values
+    if self.is_paired(&action.from) {
+      let from = from.pair_high();
+      let to = to.pair_high();
+      match action.kind {
+        Swap => g.swap(&from, &to),
+        Move => g.move(&from, &to),
+        CrossMove => g.cross_move(&from, &to)
+      }
+    }
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > Graph<K, G, R> {
+  /// Return textual snapshot of allocation: every block in flattened order
+  /// with its instructions, their operands' locations and gap moves.
+  /// NOTE: output depends only on allocation decisions, so it could be
+  /// compared against golden strings in tests
+  pub fn allocation_snapshot(&self) -> ~str {
+    let mut res = ~"";
+    for (_, block) in self.blocks.iter() {
+      let successors = do block.successors.map() |succ| {
+        fmt!("%u", succ.to_uint())
+      };
+      res.push_str(fmt!("block %u -> [%s]\n",
+                        block.id.to_uint(),
+                        successors.connect(", ")));
+
+      for id in block.instructions.iter() {
+        // Moves are placed before the instruction at the same position
+        match self.gaps.find(&id.to_uint()) {
+          Some(state) => for action in state.actions.iter() {
+            res.push_str(fmt!("  %u: %s\n",
+                              id.to_uint(),
+                              self.action_to_str(action)));
+          },
+          None => ()
+        }
+
+        let instr = self.get_instr(id);
+        let kind = match instr.kind {
+          User(ref k) => fmt!("%?", k),
+          ToPhi(_) => ~"to_phi",
+          Movement(ref action) => {
+            res.push_str(fmt!("  %u: %s\n",
+                              id.to_uint(),
+                              self.action_to_str(action)));
+            loop;
+          },
+          Phi(_) | Gap => loop
+        };
+        let InstrAssignment { inputs, output, temps, _ } =
+            self.get_assignment(id);
+        let output = match output {
+          Some(ref v) => fmt!("Some(%s)", self.value_to_str(v)),
+          None => ~"None"
+        };
+        let inputs = do inputs.map() |v| { self.value_to_str(v) };
+        let temps = do temps.map() |v| { self.value_to_str(v) };
+        res.push_str(fmt!("  %u: %s out: %s in: [%s] tmp: [%s]\n",
+                          id.to_uint(),
+                          kind,
+                          output,
+                          inputs.connect(", "),
+                          temps.connect(", ")));
+      }
+    }
+    return res;
+  }
+
+  /// Return stores and reloads of split values in the order of their
+  /// positions. Moves between different values (i.e. phi moves) are not
+  /// included. Should be invoked after allocation.
+  pub fn spill_schedule(&self) -> ~[SpillOp<G, R>] {
+    let mut res = ~[];
+    let blocks = self.get_block_list();
+    for block in blocks.iter() {
+      for id in self.get_block(block).instructions.iter() {
+        match self.gaps.find(&id.to_uint()) {
+          Some(state) => for action in state.actions.iter() {
+            self.push_spill_op(&mut res, *id, action);
+          },
+          None => ()
+        }
+        match self.get_instr(id).kind {
+          Movement(ref action) => self.push_spill_op(&mut res, *id, action),
+          _ => ()
+        }
+      }
+    }
+    return res;
+  }
+
+  fn push_spill_op(&self,
+                   res: &mut ~[SpillOp<G, R>],
+                   pos: InstrId,
+                   action: &GapAction) {
+    if action.kind != Move {
+      return;
+    }
+
+    // Only moves between pieces of the same value
+    let from_parent = match self.get_interval(&action.from).parent {
+      Some(parent) => parent,
+      None => action.from
+    };
+    let to_parent = match self.get_interval(&action.to).parent {
+      Some(parent) => parent,
+      None => action.to
+    };
+    if from_parent != to_parent {
+      return;
+    }
+
+    let from = self.get_interval(&action.from).value.clone();
+    let to = self.get_interval(&action.to).value.clone();
+    match (from, to) {
+      (RegisterVal(r), StackVal(g, slot)) => res.push(SpillOp {
+        kind: Store,
+        pos: pos,
+        group: g,
+        slot: slot,
+        register: r
+      }),
+      (StackVal(g, slot), RegisterVal(r)) => res.push(SpillOp {
+        kind: Reload,
+        pos: pos,
+        group: g,
+        slot: slot,
+        register: r
+      }),
+      _ => ()
+    }
+  }
+
+  fn action_to_str(&self, action: &GapAction) -> ~str {
+    let kind = match action.kind {
+      Swap => "swap",
+      Move => "move",
+      CrossMove => "cross_move"
+    };
+    fmt!("%s %s -> %s",
+         kind,
+         self.value_to_str(&self.get_interval(&action.from).value),
+         self.value_to_str(&self.get_interval(&action.to).value))
+  }
+
+  // Slots and instruction ids are printed as plain numbers, so snapshots
+  // don't depend on how ids are represented
+  fn value_to_str(&self, value: &Value<G, R>) -> ~str {
+    match *value {
+      VirtualVal(ref g) => fmt!("VirtualVal(%?)", *g),
+      RegisterVal(ref r) => fmt!("RegisterVal(%?)", *r),
+      StackVal(ref g, slot) => fmt!("StackVal(%?, StackId(%u))",
+                                    *g,
+                                    slot.to_uint()),
+      RematVal(ref g, id) => fmt!("RematVal(%?, InstrId(%u))",
+                                  *g,
+                                  id.to_uint())
     }
   }
 }
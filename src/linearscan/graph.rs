@@ -1,14 +1,16 @@
-use extra::smallintmap::SmallIntMap;
+use extra::smallintmap::{SmallIntMap, SmallIntMapIterator};
 use extra::bitv::BitvSet;
-use std::uint;
+use std::{vec, uint, iterator};
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
+use linearscan::allocator::{Config, AllocatorResult};
 
 #[deriving(Eq, Ord, Clone)]
 pub struct BlockId(uint);
 #[deriving(Eq, Ord, Clone)]
 pub struct InstrId(uint);
-#[deriving(Eq, Ord, Clone)]
+#[deriving(Eq, Ord, Clone, ToStr)]
 pub struct IntervalId(uint);
+// Byte offset of spill slot
 #[deriving(Eq, Ord, Clone)]
 pub struct StackId(uint);
 
@@ -21,9 +23,33 @@ pub struct Graph<K, G, R> {
   blocks: ~SmallIntMap<~Block<K> >,
   instructions: ~SmallIntMap<~Instruction<K, G> >,
   phis: ~[InstrId],
+
+  // Phi => block defining it, see `attach_phi`
+  phi_blocks: ~SmallIntMap<BlockId>,
   gaps: ~SmallIntMap<~GapState>,
   prepared: bool,
-  physical: ~SmallIntMap<~SmallIntMap<IntervalId> >
+  physical: ~SmallIntMap<~SmallIntMap<IntervalId> >,
+  config: Config<K, G, R>,
+
+  // Group => location used for breaking move cycles
+  scratch: ~SmallIntMap<IntervalId>,
+
+  // Group => statistics of the last allocation
+  stats: ~SmallIntMap<Statistics>,
+
+  // Loop index => loop header block
+  loop_headers: ~SmallIntMap<BlockId>,
+
+  // Position => block lookup tables, see `build_block_index`
+  instr_to_block: ~SmallIntMap<BlockId>,
+  block_ends: ~[(InstrId, BlockId)],
+
+  // Blocks inserted on critical edges by allocator, user has never seen them
+  split_blocks: ~[BlockId],
+
+  // Construction-time ids => flattened ids
+  block_map: ~SmallIntMap<BlockId>,
+  instr_map: ~SmallIntMap<InstrId>
 }
 
 // Trait for all ids
@@ -42,6 +68,12 @@ pub struct Block<K> {
   loop_depth: uint,
   incoming_forward_branches: uint,
 
+  // Execution frequency given by user (i.e. from profile)
+  frequency: Option<f64>,
+
+  // Successor that should be placed right after block, if possible
+  fallthrough: Option<BlockId>,
+
   // Fields for liveness analysis
   live_gen: ~BitvSet,
   live_kill: ~BitvSet,
@@ -59,7 +91,21 @@ pub struct Instruction<K, G> {
   output: Option<IntervalId>,
   inputs: ~[InstrId],
   temporary: ~[IntervalId],
-  added: bool
+  added: bool,
+
+  // Every live value should be spilled across this instruction
+  spill_all: bool,
+
+  // Inputs of commutative instruction were swapped by allocator
+  swapped: bool,
+
+  // (input index, interval) pairs for inputs used in other register group,
+  // they are copied into that interval right before the instruction
+  cross_inputs: ~[(uint, IntervalId)],
+
+  // (input index, register index) pairs for inputs that should be in the
+  // specific register of their group, overriding kind's use
+  fixed_inputs: ~[(uint, uint)]
 }
 
 // Abstraction to allow having user-specified instruction types
@@ -69,7 +115,10 @@ pub enum InstrKind<K, G> {
   User(K),
   Gap,
   Phi(G),
-  ToPhi(G)
+  ToPhi(G),
+
+  // Gap action materialized by `flush_moves`
+  Movement(GapAction)
 }
 
 pub struct Interval<G, R> {
@@ -80,14 +129,29 @@ pub struct Interval<G, R> {
   parent: Option<IntervalId>,
   uses: ~[Use<G, R>],
   children: ~[IntervalId],
-  fixed: bool
+  fixed: bool,
+
+  // Instruction that can recompute interval's value instead of spilling it
+  remat: Option<InstrId>,
+
+  // Value occupies two adjacent registers or a double-width spill slot
+  paired: bool,
+
+  // Value should stay in register for its whole lifetime
+  no_spill: bool,
+
+  // Register preferred by user, it wins over other hints
+  reg_hint: Option<R>
 }
 
 #[deriving(Eq, Clone)]
 pub enum Value<G, R> {
   VirtualVal(G),
   RegisterVal(R),
-  StackVal(G, StackId)
+  StackVal(G, StackId),
+
+  // Spilled value that is recomputed at every reload by instruction
+  RematVal(G, InstrId)
 }
 
 #[deriving(Clone)]
@@ -100,6 +164,9 @@ pub struct Use<G, R> {
 pub enum UseKind<G, R> {
   UseAny(G),
   UseRegister(G),
+
+  // Register is preferred, but stack slot is acceptable under pressure
+  UsePrefer(G),
   UseFixed(R)
 }
 
@@ -113,19 +180,57 @@ pub struct GapState {
   actions: ~[GapAction]
 }
 
-#[deriving(Eq, Clone)]
+#[deriving(Eq, Clone, ToStr)]
 pub enum GapActionKind {
   Move,
-  Swap
+  Swap,
+
+  // Move between different register groups
+  CrossMove
 }
 
-#[deriving(Clone)]
+#[deriving(Clone, ToStr)]
 pub struct GapAction {
   kind: GapActionKind,
   from: IntervalId,
   to: IntervalId
 }
 
+// Counters of allocator's work
+#[deriving(Eq, Clone, ToStr)]
+pub struct Statistics {
+  // Interval splits
+  splits: uint,
+
+  // Moves emitted in gaps (including reloads)
+  moves: uint,
+
+  // Swaps emitted in gaps
+  swaps: uint,
+
+  // Moves from spill slots into registers
+  reloads: uint,
+
+  // Recomputations of rematerialized values
+  remats: uint,
+
+  // Groups assigned in a single pass, without splits and spills
+  fast_groups: uint
+}
+
+// Expected outcome of allocation, computed from liveness only
+pub struct AllocEstimate {
+  // Peak number of simultaneously live values in each group
+  pressure: SmallIntMap<uint>,
+
+  // Number of values that don't fit into registers at the peak, summed
+  // over groups
+  spills: uint,
+
+  // Whether at least one value will be spilled
+  needs_spills: bool
+}
+
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > Graph<K, G, R> {
@@ -140,9 +245,19 @@ impl<G: GroupHelper<R>,
       blocks: ~SmallIntMap::new(),
       instructions: ~SmallIntMap::new(),
       phis: ~[],
+      phi_blocks: ~SmallIntMap::new(),
       gaps: ~SmallIntMap::new(),
       prepared: false,
-      physical: ~SmallIntMap::new()
+      physical: ~SmallIntMap::new(),
+      config: Config::new(),
+      scratch: ~SmallIntMap::new(),
+      stats: ~SmallIntMap::new(),
+      loop_headers: ~SmallIntMap::new(),
+      instr_to_block: ~SmallIntMap::new(),
+      block_ends: ~[],
+      split_blocks: ~[],
+      block_map: ~SmallIntMap::new(),
+      instr_map: ~SmallIntMap::new()
     }
   }
 
@@ -156,7 +271,11 @@ impl<G: GroupHelper<R>,
       output: None,
       inputs: ~[],
       temporary: ~[],
-      added: true
+      added: true,
+      spill_all: false,
+      swapped: false,
+      cross_inputs: ~[],
+      fixed_inputs: ~[]
     };
   }
 
@@ -178,6 +297,110 @@ impl<G: GroupHelper<R>,
     return blocks;
   }
 
+  /// Return block of phi, or None if phi wasn't attached to any block
+  /// (see `attach_phi`)
+  pub fn phi_block(&self, phi: &InstrId) -> Option<BlockId> {
+    return self.phi_blocks.find(&phi.to_uint()).map(|block| **block);
+  }
+
+  /// Check graph structure and return list of all found problems
+  pub fn validate(&self) -> Result<(), ~[~str]> {
+    let mut errors = ~[];
+
+    if self.root.is_none() {
+      errors.push(~"Root block is not set");
+    }
+
+    for (_, block) in self.blocks.iter() {
+      if !block.ended {
+        errors.push(fmt!("Block %u is not ended", block.id.to_uint()));
+      } else if block.successors.len() == 0 {
+        // Exit blocks should end with return
+        let ret = match block.instructions.last_opt() {
+          Some(id) => self.get_instr(id).kind.is_return(),
+          None => false
+        };
+        if !ret {
+          errors.push(fmt!("Block %u has no successors and doesn't end \
+                            with return",
+                           block.id.to_uint()));
+        }
+      }
+
+      // Edges should be recorded on both sides
+      for succ in block.successors.iter() {
+        if !self.get_block(succ).predecessors.contains(&block.id) {
+          errors.push(fmt!("Block %u is not a predecessor of its successor %u",
+                           block.id.to_uint(),
+                           succ.to_uint()));
+        }
+      }
+      for pred in block.predecessors.iter() {
+        if !self.get_block(pred).successors.contains(&block.id) {
+          errors.push(fmt!("Block %u is not a successor of its predecessor %u",
+                           block.id.to_uint(),
+                           pred.to_uint()));
+        }
+      }
+
+      for id in block.instructions.iter() {
+        for input in self.get_instr(id).inputs.iter() {
+          if !self.instructions.contains_key(&input.to_uint()) ||
+             !self.get_instr(input).added {
+            errors.push(fmt!("Instruction %u uses unknown instruction %u",
+                             id.to_uint(),
+                             input.to_uint()));
+          } else if self.get_instr(input).output.is_none() {
+            errors.push(fmt!("Instruction %u uses instruction %u without \
+                              output",
+                             id.to_uint(),
+                             input.to_uint()));
+          }
+        }
+      }
+    }
+
+    for phi in self.phis.iter() {
+      if self.get_instr(phi).inputs.len() == 0 {
+        errors.push(fmt!("Phi %u has no inputs", phi.to_uint()));
+        loop;
+      }
+
+      // Inputs should be coming from all predecessors of the block, which
+      // is a successor of every input's block
+      let block = match self.phi_block(phi) {
+        Some(block) => block,
+        None => {
+          errors.push(fmt!("Phi %u is not attached to any block",
+                           phi.to_uint()));
+          loop;
+        }
+      };
+
+      // Each predecessor gives exactly one input
+      let mut seen = ~[];
+      for input in self.get_instr(phi).inputs.iter() {
+        let pred = self.get_instr(input).block;
+        if seen.contains(&pred) {
+          errors.push(fmt!("Phi %u has several inputs from block %u",
+                           phi.to_uint(),
+                           pred.to_uint()));
+        } else {
+          seen.push(pred);
+        }
+      }
+      for pred in self.get_block(&block).predecessors.iter() {
+        if !seen.contains(pred) {
+          errors.push(fmt!("Phi %u has no input from block %u",
+                           phi.to_uint(),
+                           pred.to_uint()));
+        }
+      }
+    }
+
+    return if errors.len() == 0 { Ok(()) } else { Err(errors) };
+  }
+
   /// Mutable instruction getter
   pub fn get_mut_instr<'r>(&'r mut self,
                            id: &InstrId) -> &'r mut ~Instruction<K, G> {
@@ -193,6 +416,40 @@ impl<G: GroupHelper<R>,
     self.instructions.get(&id.to_uint()).output.expect("Instruction output")
   }
 
+  /// Return interval holding instruction's input at the instruction itself,
+  /// it differs from input's output for uses in other register groups, and
+  /// for tied inputs that are copied into output's register
+  pub fn get_input(&self, id: &InstrId, i: uint) -> IntervalId {
+    let instr = self.get_instr(id);
+    match (instr.kind.tied_input(), instr.output) {
+      (Some(t), Some(output)) if t == i => { return output; },
+      _ => ()
+    }
+    for &(index, interval) in instr.cross_inputs.iter() {
+      if index == i {
+        return interval;
+      }
+    }
+    return self.get_output(&instr.inputs[i]);
+  }
+
+  /// Translate block id given at construction to the flattened one, return
+  /// None if block was removed (i.e. by `skip_unreachable`)
+  pub fn flat_block(&self, id: &BlockId) -> Option<BlockId> {
+    if !self.prepared {
+      return Some(*id);
+    }
+    self.block_map.find(&id.to_uint()).map(|block| **block)
+  }
+
+  /// Translate instruction id given at construction to the flattened one
+  pub fn flat_instr(&self, id: &InstrId) -> InstrId {
+    if !self.prepared {
+      return *id;
+    }
+    *self.instr_map.find(&id.to_uint()).expect("Known instruction")
+  }
+
   /// Mutable interval getter
   pub fn get_mut_interval<'r>(&'r mut self,
                               id: &IntervalId) -> &'r mut ~Interval<G, R> {
@@ -203,6 +460,76 @@ impl<G: GroupHelper<R>,
     self.intervals.get(&id.to_uint())
   }
 
+  /// Return table of virtual intervals with their values, ranges and uses,
+  /// one interval per line
+  pub fn dump_intervals(&self) -> ~str {
+    let mut res = ~"";
+    for (_, interval) in self.intervals.iter() {
+      if interval.fixed {
+        loop;
+      }
+
+      res.push_str(fmt!("i%u %?", interval.id.to_uint(), interval.value));
+      match interval.parent {
+        Some(parent) => res.push_str(fmt!(" parent: i%u", parent.to_uint())),
+        None => ()
+      }
+      if interval.children.len() != 0 {
+        let children = do interval.children.map() |child| {
+          fmt!("i%u", child.to_uint())
+        };
+        res.push_str(fmt!(" children: [%s]", children.connect(", ")));
+      }
+
+      res.push_str(" ranges:");
+      for range in interval.ranges.iter() {
+        res.push_str(fmt!(" [%u,%u)",
+                          range.start.to_uint(),
+                          range.end.to_uint()));
+      }
+
+      res.push_str(" uses:");
+      for u in interval.uses.iter() {
+        let kind = match u.kind {
+          UseAny(_) => ~"any",
+          UseRegister(_) => ~"reg",
+          UsePrefer(_) => ~"prefer",
+          UseFixed(ref r) => fmt!("fixed(%u)", r.to_uint())
+        };
+        res.push_str(fmt!(" %s@%u", kind, u.pos.to_uint()));
+      }
+      res.push_char('\n');
+    }
+    return res;
+  }
+
+  /// Iterate through all intervals (including split children and physical
+  /// ones) in order of their ids
+  pub fn intervals_iter<'r>(&'r self)
+      -> iterator::Map<'r,
+                       (uint, &'r ~Interval<G, R>),
+                       (IntervalId, &'r ~Interval<G, R>),
+                       SmallIntMapIterator<'r, ~Interval<G, R> > > {
+    self.intervals.iter().map(|(id, interval)| (IntervalId(id), interval))
+  }
+
+  /// Mutable getter of group's allocation statistics
+  pub fn get_mut_stats<'r>(&'r mut self, group: &G) -> &'r mut Statistics {
+    if !self.stats.contains_key(&group.to_uint()) {
+      self.stats.insert(group.to_uint(), Statistics::new());
+    }
+    self.stats.find_mut(&group.to_uint()).unwrap()
+  }
+
+  /// Return statistics of the last allocation summed over all groups
+  pub fn statistics(&self) -> Statistics {
+    let mut res = Statistics::new();
+    for (_, stats) in self.stats.iter() {
+      res.add(stats);
+    }
+    return res;
+  }
+
   /// Mutable gap state getter
   pub fn get_mut_gap<'r>(&'r mut self, id: &InstrId) -> &'r mut ~GapState {
     if !self.gaps.contains_key(&id.to_uint()) {
@@ -215,30 +542,703 @@ impl<G: GroupHelper<R>,
   pub fn get_intersection(&self,
                           a: &IntervalId,
                           b: &IntervalId) -> Option<InstrId> {
-    let int_a = self.get_interval(a);
-    let int_b = self.get_interval(b);
-
-    for a in int_a.ranges.iter() {
-      for b in int_b.ranges.iter() {
-        match a.get_intersection(b) {
-          Some(pos) => {
-            return Some(pos)
-          },
-          _ => ()
-        }
+    let ranges_a = &self.get_interval(a).ranges;
+    let ranges_b = &self.get_interval(b).ranges;
+
+    // Both lists are sorted, walk them in parallel
+    let mut i = 0;
+    let mut j = 0;
+    while i < ranges_a.len() && j < ranges_b.len() {
+      match ranges_a[i].get_intersection(&ranges_b[j]) {
+        Some(pos) => {
+          return Some(pos)
+        },
+        _ => ()
+      }
+
+      // Skip range that ends first, it can't intersect anything else
+      if ranges_a[i].end <= ranges_b[j].end {
+        i += 1;
+      } else {
+        j += 1;
       }
     }
 
     return None;
   }
 
+  /// Find next intersection of two intervals at or after `pos`
+  pub fn get_intersection_after(&self,
+                                a: &IntervalId,
+                                b: &IntervalId,
+                                pos: InstrId) -> Option<InstrId> {
+    for range in self.get_interval(a).ranges.iter() {
+      if range.end <= pos {
+        loop;
+      }
+
+      // Only the part of range after `pos` is interesting
+      let rest = LiveRange {
+        start: if range.start < pos { pos } else { range.start },
+        end: range.end
+      };
+      for other in self.get_interval(b).ranges.iter() {
+        match rest.get_intersection(other) {
+          Some(at) => { return Some(at); },
+          None => ()
+        }
+      }
+    }
+    return None;
+  }
+
+  /// Return interference graph of values: every top-level interval with
+  /// list of intervals whose split families are alive at the same time.
+  /// Should be invoked after allocation.
+  pub fn interference_graph(&self) -> ~[(IntervalId, ~[IntervalId])] {
+    // Values with bounds of their families, sorted by start
+    let mut list: ~[(IntervalId, InstrId, InstrId)] = ~[];
+    for (_, interval) in self.intervals.iter() {
+      if interval.fixed || interval.parent.is_some() ||
+         interval.ranges.len() == 0 {
+        loop;
+      }
+      let mut start = interval.start();
+      let mut end = interval.end();
+      for child in interval.children.iter() {
+        let child = self.get_interval(child);
+        if child.start() < start { start = child.start(); }
+        if child.end() > end { end = child.end(); }
+      }
+
+      let mut i = 0;
+      while i < list.len() {
+        match list[i] {
+          (_, s, _) if s > start => break,
+          _ => i += 1
+        }
+      }
+      list.insert(i, (interval.id, start, end));
+    }
+
+    // Sweep through values, checking only ones that are not yet ended
+    let mut res = do list.map() |&(id, _, _)| { (id, ~[]) };
+    let mut active: ~[uint] = ~[];
+    for (i, &(id, start, _)) in list.iter().enumerate() {
+      do active.retain |&j| {
+        match list[j] {
+          (_, _, end) => end > start
+        }
+      };
+      for &j in active.iter() {
+        let (other, _, _) = list[j];
+        if self.interferes(id, other) {
+          match res[i] { (_, ref mut adj) => adj.push(other) }
+          match res[j] { (_, ref mut adj) => adj.push(id) }
+        }
+      }
+      active.push(i);
+    }
+    return res;
+  }
+
+  /// Return all overlapping parts of two intervals as [start, end) pairs
+  pub fn intersections(&self,
+                       a: &IntervalId,
+                       b: &IntervalId) -> ~[(InstrId, InstrId)] {
+    let ranges_a = &self.get_interval(a).ranges;
+    let ranges_b = &self.get_interval(b).ranges;
+    let mut res = ~[];
+
+    // Same walk as in `get_intersection`, but without early exit
+    let mut i = 0;
+    let mut j = 0;
+    while i < ranges_a.len() && j < ranges_b.len() {
+      let start = if ranges_a[i].start > ranges_b[j].start {
+        ranges_a[i].start
+      } else {
+        ranges_b[j].start
+      };
+      let end = if ranges_a[i].end < ranges_b[j].end {
+        ranges_a[i].end
+      } else {
+        ranges_b[j].end
+      };
+      if start < end {
+        res.push((start, end));
+      }
+
+      if ranges_a[i].end <= ranges_b[j].end {
+        i += 1;
+      } else {
+        j += 1;
+      }
+    }
+
+    return res;
+  }
+
+  /// Reassign stack slots, so that spilled values of the same group which
+  /// are never alive at the same time are sharing slots. Should be invoked
+  /// after allocation with its result, spill area of each group stays at its
+  /// offset and sizes of areas and frame are updated.
+  pub fn coalesce_spills(&mut self, result: &mut AllocatorResult) {
+    // Intervals that are already sharing a slot are moved together
+    let mut slots = ~SmallIntMap::new();
+    for (_, interval) in self.intervals.iter() {
+      let slot = match interval.value {
+        StackVal(_, StackId(slot)) if !interval.fixed => slot,
+        _ => loop
+      };
+      let group = interval.value.group();
+      let mut size = group.spill_size();
+      if interval.paired {
+        size *= 2;
+      }
+
+      if !slots.contains_key(&slot) {
+        slots.insert(slot, (group.to_uint(), size, ~[]));
+      }
+      match slots.find_mut(&slot) {
+        Some(&(_, ref mut max, ref mut members)) => {
+          *max = uint::max(*max, size);
+          members.push(interval.id);
+        },
+        None => ()
+      }
+    }
+
+    // Scratch slots are used by any move, they are never shared
+    let mut pinned = ~BitvSet::new();
+    for (_, id) in self.scratch.iter() {
+      match self.get_interval(id).value {
+        StackVal(_, StackId(slot)) => { pinned.insert(slot); },
+        _ => ()
+      }
+    }
+
+    // Order slots by start of their first value
+    let mut units: ~[(InstrId, uint)] = ~[];
+    for (&slot, &(_, _, ref members)) in slots.iter() {
+      let mut start = InstrId(uint::max_value);
+      for id in members.iter() {
+        let interval = self.get_interval(id);
+        if interval.ranges.len() != 0 && interval.start() < start {
+          start = interval.start();
+        }
+      }
+      let mut i = 0;
+      while i < units.len() {
+        let (other, _) = units[i];
+        if other > start {
+          break;
+        }
+        i += 1;
+      }
+      units.insert(i, (start, slot));
+    }
+
+    // Greedily give each unit the first compatible slot of its group:
+    // (group, size, pinned, members of all units)
+    let mut colors: ~[(uint, uint, bool, ~[IntervalId])] = ~[];
+    let mut mapping = ~SmallIntMap::new();
+    for &(_, slot) in units.iter() {
+      let &(group, size, ref members) = slots.find(&slot).unwrap();
+      let is_pinned = pinned.contains(&slot);
+
+      let mut found = None;
+      if !is_pinned {
+        for (i, &(color_group, color_size, color_pinned, ref others)) in
+            colors.iter().enumerate() {
+          if color_group != group || color_size != size || color_pinned {
+            loop;
+          }
+          // NOTE: values meeting at the same gap are conflicting too, gap
+          // moves were ordered with their old slots in mind
+          let conflict = do members.iter().any() |a| {
+            do others.iter().any() |b| {
+              do self.get_interval(a).ranges.iter().any() |ra| {
+                do self.get_interval(b).ranges.iter().any() |rb| {
+                  ra.start <= rb.end && rb.start <= ra.end
+                }
+              }
+            }
+          };
+          if !conflict {
+            found = Some(i);
+            break;
+          }
+        }
+      }
+
+      let color = match found {
+        Some(i) => i,
+        None => {
+          colors.push((group, size, is_pinned, ~[]));
+          colors.len() - 1
+        }
+      };
+      match colors[color] {
+        (_, _, _, ref mut others) => others.push_all(*members)
+      }
+      mapping.insert(slot, color);
+    }
+
+    // Lay out slots of each group in its own area, keeping each of them
+    // aligned to its size. Areas are placed as in `allocate`.
+    let groups: ~[G] = GroupHelper::groups();
+    let mut offsets = vec::from_elem(colors.len(), 0u);
+    let mut spill_base = 0;
+    let mut frame_end = 0;
+    let mut frame_align = 1;
+    for (i, group) in groups.iter().enumerate() {
+      let slot_size = group.spill_size();
+      spill_base = (spill_base + slot_size - 1) / slot_size * slot_size;
+      if slot_size > frame_align {
+        frame_align = slot_size;
+      }
+
+      let base = spill_base;
+      let mut offset = base;
+      let mut count = 0;
+      for (j, &(color_group, size, _, _)) in colors.iter().enumerate() {
+        if color_group != group.to_uint() {
+          loop;
+        }
+        offset = (offset + size - 1) / size * size;
+        offsets[j] = offset;
+        offset += size;
+        count += size / slot_size;
+      }
+
+      // Area keeps its place, only its size is reduced
+      spill_base = base + result.spill_bytes[i];
+      result.spill_bytes[i] = offset - base;
+      result.spill_count[i] = count;
+      if offset > frame_end {
+        frame_end = offset;
+      }
+    }
+    result.frame_size = (frame_end + frame_align - 1) / frame_align *
+                        frame_align;
+
+    // Rewrite locations
+    for (&slot, &(_, _, ref members)) in slots.iter() {
+      let offset = offsets[*mapping.find(&slot).unwrap()];
+      for id in members.iter() {
+        let group = self.get_interval(id).value.group();
+        self.get_mut_interval(id).value = StackVal(group, StackId(offset));
+      }
+    }
+  }
+
+  /// Return true if `a` (or any of its children) intersects with `b` (or any
+  /// of its children)
+  pub fn interferes(&self, a: IntervalId, b: IntervalId) -> bool {
+    let mut left = ~[a];
+    left.push_all(self.get_interval(&a).children);
+    let mut right = ~[b];
+    right.push_all(self.get_interval(&b).children);
+
+    return do left.iter().any() |l| {
+      do right.iter().any() |r| {
+        self.get_intersection(l, r).is_some()
+      }
+    };
+  }
+
   /// Return `true` if `pos` is either some block's start or end
   pub fn block_boundary(&self, pos: InstrId) -> bool {
-    let block = self.get_block(&self.get_instr(&pos).block);
+    let block = self.get_block(&self.block_of(&pos));
     return block.start() == pos || block.end() == pos;
   }
 
-  /// Find optimal split position between two instructions
+  /// Build position => block lookup tables.
+  /// NOTE: tables are valid only for instructions that are present in
+  /// blocks at the time of the call and only while blocks aren't
+  /// reordered. Instructions inserted later should be registered with
+  /// `set_instr_block`.
+  pub fn build_block_index(&mut self) {
+    self.instr_to_block.clear();
+    self.block_ends.clear();
+    let list = self.get_block_list();
+    for id in list.iter() {
+      let instructions = self.get_block(id).instructions.clone();
+      for instr in instructions.iter() {
+        self.instr_to_block.insert(instr.to_uint(), *id);
+      }
+      if instructions.len() != 0 {
+        let end = self.get_block(id).end();
+        self.block_ends.push((end, *id));
+      }
+    }
+  }
+
+  /// Register instruction inserted into block after `build_block_index`
+  pub fn set_instr_block(&mut self, instr: &InstrId, block: &BlockId) {
+    self.instr_to_block.insert(instr.to_uint(), *block);
+  }
+
+  /// Return block containing instruction
+  pub fn block_of(&self, pos: &InstrId) -> BlockId {
+    match self.instr_to_block.find(&pos.to_uint()) {
+      Some(block) => *block,
+      None => self.get_instr(pos).block
+    }
+  }
+
+  /// Insert empty block on the edge between `from` and `to`, return its id
+  pub fn split_edge(&mut self, from: BlockId, to: BlockId) -> BlockId {
+    let mut block = ~Block::new(self);
+    let id = block.id;
+    block.add_successor(to);
+    block.add_predecessor(from);
+    block.ended = true;
+    self.blocks.insert(id.to_uint(), block);
+
+    // Redirect edge through new block
+    for succ in self.get_mut_block(&from).successors.mut_iter() {
+      if *succ == to {
+        *succ = id;
+      }
+    }
+    if self.get_block(&from).fallthrough == Some(to) {
+      self.get_mut_block(&from).fallthrough = Some(id);
+    }
+    for pred in self.get_mut_block(&to).predecessors.mut_iter() {
+      if *pred == from {
+        *pred = id;
+      }
+    }
+    return id;
+  }
+
+  /// Return list of blocks that can't be reached from root
+  pub fn unreachable_blocks(&self) -> ~[BlockId] {
+    let mut visited = ~BitvSet::new();
+    match self.root {
+      Some(root) => {
+        let mut queue = ~[root];
+        visited.insert(root.to_uint());
+        while queue.len() > 0 {
+          let cur = queue.pop();
+          for succ in self.get_block(&cur).successors.iter() {
+            if visited.insert(succ.to_uint()) {
+              queue.push(*succ);
+            }
+          }
+        }
+      },
+      None => ()
+    }
+
+    let mut res = ~[];
+    for (_, block) in self.blocks.iter() {
+      if !visited.contains(&block.id.to_uint()) {
+        res.push(block.id);
+      }
+    }
+    return res;
+  }
+
+  /// Remove unreachable blocks together with their instructions
+  pub fn remove_unreachable(&mut self) {
+    let list = self.unreachable_blocks();
+    for id in list.iter() {
+      let block = self.blocks.pop(&id.to_uint()).unwrap();
+
+      // Forget edges to reachable blocks
+      for succ in block.successors.iter() {
+        match self.blocks.find_mut(&succ.to_uint()) {
+          Some(s) => {
+            let pos = s.predecessors.position_elem(id);
+            match pos {
+              Some(i) => {
+                s.predecessors.remove(i);
+                s.incoming_forward_branches -= 1;
+              },
+              None => ()
+            }
+          },
+          None => ()
+        }
+      }
+
+      for instr in block.instructions.iter() {
+        self.instructions.pop(&instr.to_uint());
+      }
+    }
+
+    // Phis can't get values from removed blocks
+    let phis = self.phis.clone();
+    for phi in phis.iter() {
+      let inputs = do self.get_instr(phi).inputs.filtered |input| {
+        self.instructions.contains_key(&input.to_uint())
+      };
+      self.get_mut_instr(phi).inputs = inputs;
+    }
+  }
+
+  /// Remove instructions without side effects, whose outputs are not used
+  /// by any other instruction
+  pub fn eliminate_dead(&mut self) {
+    loop {
+      let mut used = ~BitvSet::new();
+      for (_, instr) in self.instructions.iter() {
+        for input in instr.inputs.iter() {
+          used.insert(input.to_uint());
+        }
+      }
+
+      let mut dead = ~[];
+      for (_, instr) in self.instructions.iter() {
+        if !used.contains(&instr.id.to_uint()) &&
+           !instr.kind.has_side_effects() {
+          dead.push(instr.id);
+        }
+      }
+
+      // Removal might make inputs of removed instructions dead too
+      if dead.len() == 0 {
+        break;
+      }
+      for id in dead.iter() {
+        let instr = self.instructions.pop(&id.to_uint()).unwrap();
+        do self.get_mut_block(&instr.block).instructions.retain |i| {
+          i != id
+        };
+        match instr.output {
+          Some(output) => { self.intervals.pop(&output.to_uint()); },
+          None => ()
+        }
+      }
+    }
+  }
+
+  /// Remove instruction from its block together with its intervals.
+  /// NOTE: should be called before allocation, instruction should not be
+  /// used by other instructions
+  pub fn remove_instr(&mut self, id: InstrId) -> Result<(), ~str> {
+    assert!(!self.prepared);
+    if !self.instructions.contains_key(&id.to_uint()) {
+      return Err(fmt!("Unknown instruction %u", id.to_uint()));
+    }
+    for (_, instr) in self.instructions.iter() {
+      if instr.inputs.contains(&id) {
+        return Err(fmt!("Instruction %u is used by instruction %u",
+                        id.to_uint(),
+                        instr.id.to_uint()));
+      }
+    }
+
+    let instr = self.instructions.pop(&id.to_uint()).unwrap();
+    if instr.added {
+      match self.blocks.find_mut(&instr.block.to_uint()) {
+        Some(block) => do block.instructions.retain |i| { *i != id },
+        None => ()
+      }
+    }
+    do self.phis.retain |i| { *i != id };
+    self.phi_blocks.pop(&id.to_uint());
+
+    match instr.output {
+      Some(output) => {
+        assert!(self.get_interval(&output).uses.len() == 0);
+        self.intervals.pop(&output.to_uint());
+      },
+      None => ()
+    }
+    for tmp in instr.temporary.iter() {
+      self.intervals.pop(&tmp.to_uint());
+    }
+    return Ok(());
+  }
+
+  /// Insert empty block on every edge from a block with multiple successors
+  /// to a block with multiple predecessors. Moves on such edges can't be
+  /// placed in either of blocks without affecting other paths.
+  pub fn split_critical_edges(&mut self) {
+    let mut edges = ~[];
+    for (_, block) in self.blocks.iter() {
+      if block.successors.len() < 2 {
+        loop;
+      }
+      for succ in block.successors.iter() {
+        if self.get_block(succ).predecessors.len() > 1 &&
+           !edges.contains(&(block.id, *succ)) {
+          edges.push((block.id, *succ));
+        }
+      }
+    }
+    for &(from, to) in edges.iter() {
+      let block = self.split_edge(from, to);
+      self.split_blocks.push(block);
+    }
+  }
+
+  /// Merge conditionally defined outputs with their previous values: every
+  /// other predecessor of defining block's successor, where the output is
+  /// live and the previous value is available, gets a phi movement from the
+  /// previous value to the output (an implicit phi), so the previous value
+  /// is live-in on paths not defining the output. Graphs without `maybe_def`
+  /// instructions are left untouched.
+  /// NOTE: critical edges should be split, see `split_critical_edges`
+  pub fn merge_conditional_defs(&mut self) {
+    let mut moves = ~[];
+    for (_, instr) in self.instructions.iter() {
+      if !instr.added {
+        loop;
+      }
+      match (instr.kind.maybe_def(), instr.output) {
+        (Some(i), Some(output)) => {
+          let block = instr.block;
+          let input = instr.inputs[i];
+          let input_block = match self.get_instr(&input).kind {
+            Phi(_) => self.phi_block(&input),
+            _ => Some(self.get_instr(&input).block)
+          };
+          for succ in self.get_block(&block).successors.iter() {
+            // Output is not used on this path
+            if !self.is_live_at(&instr.id, succ) {
+              loop;
+            }
+            for pred in self.get_block(succ).predecessors.iter() {
+              let available = match input_block {
+                Some(ref def) => self.dominates(def, pred),
+                None => false
+              };
+              if *pred != block && available &&
+                 !moves.contains(&(*pred, input, output)) {
+                moves.push((*pred, input, output));
+              }
+            }
+          }
+        },
+        _ => ()
+      }
+    }
+
+    for &(block, input, output) in moves.iter() {
+      let group = self.get_interval(&output).value.group();
+      let res = Instruction::new_empty(self, ToPhi(group), ~[input]);
+      {
+        let instr = self.get_mut_instr(&res);
+        instr.output = Some(output);
+        instr.added = true;
+        instr.block = block;
+      }
+
+      // Movement goes before instruction passing control to the successor
+      let index = {
+        let instructions = &self.get_block(&block).instructions;
+        match instructions.last_opt() {
+          Some(last) if self.get_instr(last).output.is_none() => {
+            instructions.len() - 1
+          },
+          _ => instructions.len()
+        }
+      };
+      self.get_mut_block(&block).instructions.insert(index, res);
+    }
+  }
+
+  // Return true if output of `instr` could be used on some path starting at
+  // the beginning of `block` before being redefined
+  fn is_live_at(&self, instr: &InstrId, block: &BlockId) -> bool {
+    let mut queue = ~[*block];
+    let mut visited = ~BitvSet::new();
+    visited.insert(block.to_uint());
+    while queue.len() > 0 {
+      let cur = queue.pop();
+      let mut killed = false;
+      for id in self.get_block(&cur).instructions.iter() {
+        if id == instr {
+          killed = true;
+          break;
+        }
+        if self.get_instr(id).inputs.contains(instr) {
+          return true;
+        }
+      }
+      if killed {
+        loop;
+      }
+      for succ in self.get_block(&cur).successors.iter() {
+        if visited.insert(succ.to_uint()) {
+          queue.push(*succ);
+        }
+      }
+    }
+    return false;
+  }
+
+  // Return true if every path from root to `block` passes through `dom`
+  fn dominates(&self, dom: &BlockId, block: &BlockId) -> bool {
+    if dom == block {
+      return true;
+    }
+    let root = self.root.expect("Root block");
+    if root == *dom {
+      return true;
+    }
+    let mut queue = ~[root];
+    let mut visited = ~BitvSet::new();
+    visited.insert(root.to_uint());
+    visited.insert(dom.to_uint());
+    while queue.len() > 0 {
+      let cur = queue.pop();
+      for succ in self.get_block(&cur).successors.iter() {
+        if succ == block {
+          return false;
+        }
+        if visited.insert(succ.to_uint()) {
+          queue.push(*succ);
+        }
+      }
+    }
+    return true;
+  }
+
+  /// Return position of the gap for moves on the edge between blocks:
+  /// predecessor's end if it has the only successor, or successor's start if
+  /// it has the only predecessor. If both are possible, the one with fewer
+  /// moves already placed is chosen.
+  /// NOTE: critical edges should be split, see `split_critical_edges`
+  pub fn edge_move_pos(&self, from: &BlockId, to: &BlockId) -> InstrId {
+    let block_end = self.get_block(from).end().prev();
+    let succ_start = self.get_block(to).start();
+    let single_succ = self.get_block(from).successors.len() == 1;
+    let single_pred = self.get_block(to).predecessors.len() == 1;
+
+    if single_succ && single_pred {
+      if self.gap_moves(&succ_start) < self.gap_moves(&block_end) {
+        succ_start
+      } else {
+        block_end
+      }
+    } else if single_succ {
+      block_end
+    } else if single_pred {
+      succ_start
+    } else {
+      fail!(fmt!("Critical edge %u => %u is not split",
+                 from.to_uint(),
+                 to.to_uint()))
+    }
+  }
+
+  fn gap_moves(&self, pos: &InstrId) -> uint {
+    match self.gaps.find(&pos.to_uint()) {
+      Some(state) => state.actions.len(),
+      None => 0
+    }
+  }
+
+  /// Find optimal split position between two instructions.
+  /// NOTE: if `config.align_splits` is set and the range spans a block
+  /// boundary, the split is placed on the boundary of the least frequently
+  /// executed block, so the move is inserted on the edge by data-flow
+  /// resolution instead of the middle of a block.
   pub fn optimal_split_pos(&self,
                            group: &G,
                            start: InstrId,
@@ -248,25 +1248,67 @@ impl<G: GroupHelper<R>,
       return end;
     }
 
+    // Find first block ending after `start`
+    let mut lo = 0;
+    let mut hi = self.block_ends.len();
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      let (block_to, _) = self.block_ends[mid];
+      if block_to <= start {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    if !self.config.align_splits {
+      // Ignore block boundaries and split as late as possible
+      lo = self.block_ends.len();
+    }
+
     let mut best_pos = end;
-    let mut best_depth = uint::max_value;
-    for (_, block) in self.blocks.iter() {
-      if best_depth >= block.loop_depth {
-        let block_to = block.end();
+    let mut best_cost = None;
+    for &(block_to, id) in self.block_ends.slice_from(lo).iter() {
+      if block_to > end {
+        break;
+      }
+
+      // Choose the most rarely executed block
+      let cost = self.get_block(&id).cost();
+      let better = match best_cost {
+        Some(best) => best >= cost,
+        None => true
+      };
+      if better {
+        best_pos = block_to;
+        best_cost = Some(cost);
+      }
+    }
 
-        // Choose the most shallow block
-        if start < block_to && block_to <= end {
-          best_pos = block_to;
-          best_depth = block.loop_depth;
+    // Always split at gap
+    if !self.is_gap(&best_pos) && !self.clobbers(group, &best_pos) {
+      assert!(best_pos.to_uint() >= start.next().to_uint());
+      best_pos = best_pos.prev();
+    }
+
+    // Don't separate instruction from the next one, if it was requested
+    if self.sticky_gap(&best_pos) {
+      let mut pos = best_pos.prev();
+      while start < pos && (!self.is_gap(&pos) || self.sticky_gap(&pos)) {
+        pos = pos.prev();
+      }
+      if start < pos {
+        best_pos = pos;
+      } else {
+        // Try searching forward
+        pos = best_pos.next();
+        while pos <= end && (!self.is_gap(&pos) || self.sticky_gap(&pos)) {
+          pos = pos.next();
+        }
+        if pos <= end {
+          best_pos = pos;
         }
       }
     }
-
-    // Always split at gap
-    if !self.is_gap(&best_pos) && !self.clobbers(group, &best_pos) {
-      assert!(best_pos.to_uint() >= start.next().to_uint());
-      best_pos = best_pos.prev();
-    }
     assert!(start < best_pos && best_pos <= end);
     return best_pos;
   }
@@ -337,7 +1379,7 @@ impl<G: GroupHelper<R>,
 
     // Move out uses
     let mut child_uses =  ~[];
-    let split_on_call = self.get_instr(&pos).kind.clobbers(&group);
+    let split_on_call = self.clobbers(&group, &pos);
 
     // XXX: Wait for rust bug to be fixed and use filter_mapped
     let mut parent_uses = self.get_interval(&split_parent).uses.clone();
@@ -367,6 +1409,62 @@ impl<G: GroupHelper<R>,
     return child;
   }
 
+  /// Return next register use of interval's value (in any of its split
+  /// children) at or after `after`
+  pub fn next_register_use(&self,
+                           id: IntervalId,
+                           after: InstrId) -> Option<Use<G, R> > {
+    return self.nearest_use(id, after, |i, pos| i.next_use(pos), true);
+  }
+
+  /// Return next fixed use of interval's value at or after `after`
+  pub fn next_fixed_register_use(&self,
+                                 id: IntervalId,
+                                 after: InstrId) -> Option<Use<G, R> > {
+    return self.nearest_use(id, after, |i, pos| i.next_fixed_use(pos), true);
+  }
+
+  /// Return last register use of interval's value at or before `before`
+  pub fn last_register_use(&self,
+                           id: IntervalId,
+                           before: InstrId) -> Option<Use<G, R> > {
+    return self.nearest_use(id, before, |i, pos| i.last_use(pos), false);
+  }
+
+  // Apply `f` to every interval in split family, and return the use that
+  // is the earliest (or the latest if `earliest` is false)
+  fn nearest_use(&self,
+                 id: IntervalId,
+                 pos: InstrId,
+                 f: &fn(&Interval<G, R>, InstrId) -> Option<Use<G, R> >,
+                 earliest: bool) -> Option<Use<G, R> > {
+    let parent = match self.get_interval(&id).parent {
+      Some(parent) => parent,
+      None => id
+    };
+    let mut res: Option<Use<G, R> > = None;
+    do self.iterate_children(&parent) |interval| {
+      match f(&**interval, pos) {
+        Some(u) => {
+          let better = match res {
+            Some(ref best) => if earliest {
+              u.pos < best.pos
+            } else {
+              u.pos > best.pos
+            },
+            None => true
+          };
+          if better {
+            res = Some(u);
+          }
+        },
+        None => ()
+      }
+      true
+    };
+    return res;
+  }
+
   /// Helper function
   /// TODO: Use iterators
   pub fn iterate_children(&self,
@@ -385,6 +1483,108 @@ impl<G: GroupHelper<R>,
     true
   }
 
+  /// Return instruction that can recompute interval's value, if any
+  pub fn remat_instr(&self, id: &IntervalId) -> Option<InstrId> {
+    let parent = match self.get_interval(id).parent {
+      Some(parent) => parent,
+      None => *id
+    };
+    return self.get_interval(&parent).remat;
+  }
+
+  /// Return true if interval's value occupies a pair of registers
+  pub fn is_paired(&self, id: &IntervalId) -> bool {
+    let parent = match self.get_interval(id).parent {
+      Some(parent) => parent,
+      None => *id
+    };
+    return self.get_interval(&parent).paired;
+  }
+
+  /// Return true if interval's value must not be spilled
+  pub fn is_no_spill(&self, id: &IntervalId) -> bool {
+    let parent = match self.get_interval(id).parent {
+      Some(parent) => parent,
+      None => *id
+    };
+    return self.get_interval(&parent).no_spill;
+  }
+
+  /// Merge consecutive split children that are holding the same value into
+  /// one interval, and remove moves between them. Returns number of merges.
+  /// NOTE: should be called before data-flow resolution
+  pub fn merge_redundant_splits(&mut self) -> uint {
+    let mut parents = ~[];
+    for (_, interval) in self.intervals.iter() {
+      if interval.parent.is_none() && interval.children.len() != 0 {
+        parents.push(interval.id);
+      }
+    }
+
+    let mut count = 0;
+    for parent in parents.iter() {
+      // Children are not necessarily ordered, sort the whole family by start
+      let mut family: ~[IntervalId] = ~[];
+      let mut members = ~[*parent];
+      members.push_all(self.get_interval(parent).children);
+      for id in members.iter() {
+        let start = self.get_interval(id).start();
+        let mut i = 0;
+        while i < family.len() &&
+              self.get_interval(&family[i]).start() <= start {
+          i += 1;
+        }
+        family.insert(i, *id);
+      }
+
+      let mut i = 0;
+      while i + 1 < family.len() {
+        let (a, b) = (family[i], family[i + 1]);
+        let mergeable = {
+          let a_int = self.get_interval(&a);
+          let b_int = self.get_interval(&b);
+          a_int.end() == b_int.start() && a_int.value == b_int.value &&
+          !a_int.value.is_virtual()
+        };
+        if !mergeable {
+          i += 1;
+          loop;
+        }
+
+        // Move ranges and uses of `b` to `a`
+        let b_int = self.intervals.pop(&b.to_uint()).expect("Split child");
+        for range in b_int.ranges.iter() {
+          self.get_mut_interval(&a).add_range(range.start, range.end);
+        }
+        self.get_mut_interval(&a).uses.push_all(b_int.uses);
+        do self.get_mut_interval(parent).children.retain |c| { *c != b };
+        family.remove(i + 1);
+
+        // Drop move between them and redirect everything else to `a`
+        for (_, state) in self.gaps.mut_iter() {
+          do state.actions.retain |action| {
+            !(action.from == a && action.to == b)
+          };
+          for action in state.actions.mut_iter() {
+            if action.from == b {
+              action.from = a;
+            }
+            if action.to == b {
+              action.to = a;
+            }
+          }
+        }
+        for (_, interval) in self.intervals.mut_iter() {
+          if interval.hint == Some(b) {
+            interval.hint = Some(a);
+          }
+        }
+        count += 1;
+      }
+    }
+    return count;
+  }
+
   /// Find child interval, that covers specified position
   pub fn child_at(&self,
                   parent: &IntervalId,
@@ -432,6 +1632,296 @@ impl<G: GroupHelper<R>,
     }
   }
 
+  /// Return allocated value of instruction's output right after its
+  /// definition, or None if instruction has no output.
+  /// NOTE: `instr` is an id returned at graph construction
+  pub fn register_at(&self, instr: InstrId) -> Option<Value<G, R> > {
+    let instr = self.get_instr(&self.flat_instr(&instr));
+    let output = match instr.output {
+      Some(output) => output,
+      None => return None
+    };
+
+    match instr.kind {
+      // Phi is defined at the start of its block
+      Phi(_) => {
+        let start = match self.phi_block(&instr.id) {
+          Some(block) => self.get_block(&block).start(),
+          None => return None
+        };
+        match self.child_at(&output, start) {
+          Some(child) => Some(self.get_interval(&child).value.clone()),
+          None => None
+        }
+      },
+      _ => {
+        // Call instructions are defining their value after the call
+        let group = self.get_interval(&output).value.group();
+        self.get_value(&output, if instr.clobbers(&group) {
+          instr.id.next()
+        } else {
+          instr.id
+        })
+      }
+    }
+  }
+
+  /// Return group and stack slot of the interval's child covering `pos`, or
+  /// None if value is not spilled there.
+  /// NOTE: `pos` is a flattened instruction id. Slots are reused, so the same
+  /// slot might hold other values at other positions.
+  pub fn stack_slot_at(&self,
+                       interval: IntervalId,
+                       pos: InstrId) -> Option<(G, StackId)> {
+    let parent = match self.get_interval(&interval).parent {
+      Some(parent) => parent,
+      None => interval
+    };
+    match self.child_at(&parent, pos) {
+      Some(child) => match self.get_interval(&child).value {
+        StackVal(ref group, slot) => Some((group.clone(), slot)),
+        _ => None
+      },
+      None => None
+    }
+  }
+
+  /// Return locations of value over its whole split family as ranges sorted
+  /// by start, adjacent ranges with the same location are merged.
+  /// NOTE: positions are flattened instruction ids
+  pub fn locations(&self, parent: IntervalId) -> ~[(LiveRange, Value<G, R>)] {
+    let parent = match self.get_interval(&parent).parent {
+      Some(parent) => parent,
+      None => parent
+    };
+    let mut family = ~[parent];
+    family.push_all(self.get_interval(&parent).children);
+
+    // Insert every range keeping list sorted
+    let mut res: ~[(LiveRange, Value<G, R>)] = ~[];
+    for id in family.iter() {
+      let interval = self.get_interval(id);
+      for range in interval.ranges.iter() {
+        let mut i = 0;
+        while i < res.len() {
+          match res[i] {
+            (ref r, _) if r.start >= range.start => break,
+            _ => i += 1
+          }
+        }
+        res.insert(i, (*range, interval.value.clone()));
+      }
+    }
+
+    // Merge adjacent ranges in the same location
+    let mut merged: ~[(LiveRange, Value<G, R>)] = ~[];
+    for &(range, ref value) in res.iter() {
+      let len = merged.len();
+      let adjacent = len > 0 && match merged[len - 1] {
+        (ref last, ref last_value) => {
+          last.end == range.start && *last_value == *value
+        }
+      };
+      if adjacent {
+        match merged[len - 1] {
+          (ref mut last, _) => last.end = range.end
+        }
+      } else {
+        merged.push((range, value.clone()));
+      }
+    }
+    return merged;
+  }
+
+  /// Return true if allocator has swapped inputs of commutative instruction.
+  /// NOTE: `instr` is an id returned at graph construction
+  pub fn swapped_inputs(&self, instr: InstrId) -> bool {
+    return self.get_instr(&self.flat_instr(&instr)).swapped;
+  }
+
+  /// Return blocks in the order they were laid out by flattener.
+  /// NOTE: ids are the ones returned at graph construction, blocks inserted
+  /// on critical edges by allocator are not listed
+  pub fn block_order(&self) -> ~[BlockId] {
+    assert!(self.prepared);
+    let mut res = vec::from_elem(self.block_map.len(), BlockId(0));
+    for (&original, flat) in self.block_map.iter() {
+      res[flat.to_uint()] = BlockId(original);
+    }
+    do res.retain |block| {
+      !self.split_blocks.contains(block)
+    };
+    return res;
+  }
+
+  /// Return every instruction (including gaps) with its block, in the order
+  /// of flattened positions.
+  /// NOTE: ids are flat ones, the same as used in allocation results. Moves
+  /// materialized by `flush_moves` are listed too, but their ids are not
+  /// positions
+  pub fn linear_instructions(&self) -> ~[(InstrId, BlockId)] {
+    assert!(self.prepared);
+    let mut res = ~[];
+    let blocks = self.get_block_list();
+    for block in blocks.iter() {
+      for instr in self.get_block(block).instructions.iter() {
+        res.push((*instr, *block));
+      }
+    }
+    return res;
+  }
+
+  /// Return (block, loop index, loop depth) for every block, blocks outside
+  /// of loops have zero depth.
+  /// NOTE: ids are the ones returned at graph construction, blocks inserted
+  /// on critical edges by allocator are not listed
+  pub fn loop_info(&self) -> ~[(BlockId, uint, uint)] {
+    assert!(self.prepared);
+    let mut res = ~[];
+    for (&original, flat) in self.block_map.iter() {
+      if self.split_blocks.contains(&BlockId(original)) {
+        loop;
+      }
+      let block = self.get_block(flat);
+      res.push((BlockId(original), block.loop_index, block.loop_depth));
+    }
+    return res;
+  }
+
+  /// Return header of the innermost loop containing block.
+  /// NOTE: ids are the ones returned at graph construction
+  pub fn loop_header(&self, block: BlockId) -> Option<BlockId> {
+    assert!(self.prepared);
+    let flat = self.flat_block(&block).expect("Known block");
+    let block = self.get_block(&flat);
+    if block.loop_depth == 0 {
+      return None;
+    }
+    let header = self.loop_headers.find(&block.loop_index)
+                                  .expect("Loop header");
+    for (&original, flat) in self.block_map.iter() {
+      if flat == header {
+        return Some(BlockId(original));
+      }
+    }
+    return None;
+  }
+
+  /// Return intervals that are live at the start of block.
+  /// NOTE: `block` is an id returned at graph construction
+  pub fn live_in(&self, block: BlockId) -> ~[IntervalId] {
+    assert!(self.prepared);
+    let mut res = ~[];
+    let flat = self.flat_block(&block).expect("Known block");
+    for interval in self.get_block(&flat).live_in.iter() {
+      res.push(IntervalId(interval));
+    }
+    return res;
+  }
+
+  /// Return intervals that are live at the end of block.
+  /// NOTE: `block` is an id returned at graph construction
+  pub fn live_out(&self, block: BlockId) -> ~[IntervalId] {
+    assert!(self.prepared);
+    let mut res = ~[];
+    let flat = self.flat_block(&block).expect("Known block");
+    for interval in self.get_block(&flat).live_out.iter() {
+      res.push(IntervalId(interval));
+    }
+    return res;
+  }
+
+  /// Return peak number of simultaneously live values in each group,
+  /// computed from liveness by walking every block backwards.
+  /// NOTE: available after `prepare`
+  pub fn max_pressure(&self) -> SmallIntMap<uint> {
+    assert!(self.prepared);
+    let mut res = SmallIntMap::new();
+    let groups: ~[G] = GroupHelper::groups();
+    for group in groups.iter() {
+      res.insert(group.to_uint(), 0);
+    }
+
+    let blocks = self.get_block_list();
+    for block in blocks.iter() {
+      let mut live = BitvSet::new();
+      live.union_with(self.get_block(block).live_out);
+      self.update_pressure(&live, &[], &mut res);
+
+      for id in self.get_block(block).instructions.rev_iter() {
+        let instr = self.get_instr(id);
+
+        // Output and temporaries are live right after instruction starts
+        match instr.output {
+          Some(output) => { live.insert(output.to_uint()); },
+          None => ()
+        }
+        self.update_pressure(&live, instr.temporary, &mut res);
+        match instr.output {
+          Some(output) => { live.remove(&output.to_uint()); },
+          None => ()
+        }
+
+        // Inputs are live right before it
+        for input in instr.inputs.iter() {
+          live.insert(self.get_output(input).to_uint());
+        }
+        self.update_pressure(&live, &[], &mut res);
+      }
+    }
+    return res;
+  }
+
+  /// Estimate allocation outcome without running it, `register_count`
+  /// limits registers of groups like `Config::register_count` does.
+  /// NOTE: available after `prepare`, spills caused by calls and fixed
+  /// uses are not predicted
+  pub fn estimate(&self, register_count: &SmallIntMap<uint>) -> AllocEstimate {
+    let pressure = self.max_pressure();
+    let mut spills = 0;
+    let groups: ~[G] = GroupHelper::groups();
+    for group in groups.iter() {
+      let mut registers = group.register_count();
+      match register_count.find(&group.to_uint()) {
+        Some(&limit) if limit < registers => { registers = limit; },
+        _ => ()
+      }
+      let peak = *pressure.get(&group.to_uint());
+      if peak > registers {
+        spills += peak - registers;
+      }
+    }
+
+    AllocEstimate {
+      pressure: pressure,
+      spills: spills,
+      needs_spills: spills != 0
+    }
+  }
+
+  // Raise group's maximum if live values and temporaries outnumber it
+  fn update_pressure(&self,
+                     live: &BitvSet,
+                     temporary: &[IntervalId],
+                     res: &mut SmallIntMap<uint>) {
+    let mut counts = SmallIntMap::new();
+    for id in live.iter() {
+      let group = self.get_interval(&IntervalId(id)).value.group().to_uint();
+      let count = match counts.find(&group) { Some(&c) => c, None => 0 };
+      counts.insert(group, count + 1);
+    }
+    for id in temporary.iter() {
+      let group = self.get_interval(id).value.group().to_uint();
+      let count = match counts.find(&group) { Some(&c) => c, None => 0 };
+      counts.insert(group, count + 1);
+    }
+    for (group, &count) in counts.iter() {
+      if count > *res.get(&group) {
+        res.insert(group, count);
+      }
+    }
+  }
+
   /// Return true if instruction at specified position is Gap
   pub fn is_gap(&self, pos: &InstrId) -> bool {
     match self.get_instr(pos).kind {
@@ -440,10 +1930,44 @@ impl<G: GroupHelper<R>,
     }
   }
 
+  /// Return true if instruction at specified position is Gap that is
+  /// following instruction which shouldn't be separated from the next one
+  pub fn sticky_gap(&self, pos: &InstrId) -> bool {
+    if pos.to_uint() == 0 || !self.is_gap(pos) {
+      return false;
+    }
+    return self.get_instr(&pos.prev()).kind.no_spill_after();
+  }
+
+  /// Move split position of interval out of sticky gap to the closest
+  /// preceding gap, if uses of interval are staying on the same side of
+  /// the split. Otherwise position is returned as is.
+  pub fn unsticky_split_pos(&self, id: &IntervalId, pos: InstrId) -> InstrId {
+    if !self.sticky_gap(&pos) {
+      return pos;
+    }
+    let child = match self.child_at(id, pos) {
+      Some(child) => child,
+      None => return pos
+    };
+    let interval = self.get_interval(&child);
+
+    let mut prev = pos.prev();
+    while interval.start() < prev &&
+          (!self.is_gap(&prev) || self.sticky_gap(&prev)) {
+      prev = prev.prev();
+    }
+    if interval.start() < prev &&
+       !interval.uses.iter().any(|u| prev <= u.pos && u.pos < pos) {
+      return prev;
+    }
+    return pos;
+  }
+
   /// Return true if instruction at specified position contains
   /// register-clobbering call.
   pub fn clobbers(&self, group: &G, pos: &InstrId) -> bool {
-    return self.get_instr(pos).kind.clobbers(group);
+    return self.get_instr(pos).clobbers(group);
   }
 
   /// Return next block id, used at graph construction
@@ -482,6 +2006,8 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G>, K: KindHelper<G, R> > Block<K> {
       loop_index: 0,
       loop_depth: 0,
       incoming_forward_branches: 0,
+      frequency: None,
+      fallthrough: None,
       live_gen: ~BitvSet::new(),
       live_kill: ~BitvSet::new(),
       live_in: ~BitvSet::new(),
@@ -490,14 +2016,29 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G>, K: KindHelper<G, R> > Block<K> {
     }
   }
 
+  /// Return estimated execution frequency of block, if it wasn't given by
+  /// user - assume that each loop is executed ten times
+  pub fn cost(&self) -> f64 {
+    match self.frequency {
+      Some(f) => f,
+      None => {
+        let mut res = 1f64;
+        let mut i = 0;
+        while i < self.loop_depth {
+          res *= 10f64;
+          i += 1;
+        }
+        res
+      }
+    }
+  }
+
   pub fn add_successor<'r>(&'r mut self, succ: BlockId) -> &'r mut Block<K> {
-    assert!(self.successors.len() <= 2);
     self.successors.push(succ);
     return self;
   }
 
   pub fn add_predecessor(&mut self, pred: BlockId) {
-    assert!(self.predecessors.len() <= 2);
     self.predecessors.push(pred);
     // NOTE: we'll decrease them later in flatten.rs
     self.incoming_forward_branches += 1;
@@ -525,7 +2066,11 @@ impl<G: GroupHelper<R>,
       output: None,
       inputs: args.clone(),
       temporary: temporary,
-      added: false
+      added: false,
+      spill_all: false,
+      swapped: false,
+      cross_inputs: ~[],
+      fixed_inputs: ~[]
     };
     graph.instructions.insert(r.id.to_uint(), ~r);
     return id;
@@ -545,6 +2090,12 @@ impl<G: GroupHelper<R>,
     graph.get_mut_instr(&instr).output = output;
     return instr;
   }
+
+  /// Return true if instruction is clobbering registers, either because of
+  /// its kind or because it was marked as spill-all point
+  pub fn clobbers(&self, group: &G) -> bool {
+    return self.spill_all || self.kind.clobbers(group);
+  }
 }
 
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
@@ -559,25 +2110,53 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
       parent: None,
       uses: ~[],
       children: ~[],
-      fixed: false
+      fixed: false,
+      remat: None,
+      paired: false,
+      no_spill: false,
+      reg_hint: None
     };
     let id = r.id;
     graph.intervals.insert(r.id.to_uint(), ~r);
     return id;
   }
 
-  /// Add range to interval's live range list.
+  /// Add range to interval's live range list, ranges that overlap or
+  /// touch it are merged into one.
   /// NOTE: Ranges are ordered by start position
   pub fn add_range(&mut self, start: InstrId, end: InstrId) {
-    assert!(self.ranges.len() == 0 || self.ranges.head().start >= end);
+    assert!(start <= end);
 
-    // Extend last range
-    if self.ranges.len() > 0 && self.ranges.head().start == end {
-      self.ranges[0].start = start;
-    } else {
-      // Insert new range
+    // Common case: ranges are added in reverse order
+    if self.ranges.len() == 0 || self.ranges.head().start > end {
       self.ranges.unshift(LiveRange { start: start, end: end });
+      return;
+    }
+    if self.ranges.head().start == end {
+      self.ranges[0].start = start;
+      return;
+    }
+
+    let mut merged = LiveRange { start: start, end: end };
+    let mut before = ~[];
+    let mut after = ~[];
+    for range in self.ranges.iter() {
+      if range.end < merged.start {
+        before.push(LiveRange { start: range.start, end: range.end });
+      } else if range.start > end {
+        after.push(LiveRange { start: range.start, end: range.end });
+      } else {
+        if range.start < merged.start {
+          merged.start = range.start;
+        }
+        if range.end > merged.end {
+          merged.end = range.end;
+        }
+      }
     }
+    before.push(merged);
+    before.push_all_move(after);
+    self.ranges = before;
   }
 
   /// Return mutable first range
@@ -600,9 +2179,18 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
 
   /// Return true if one of the ranges contains `pos`
   pub fn covers(&self, pos: InstrId) -> bool {
-    return do self.ranges.any() |range| {
-      range.covers(pos)
-    };
+    // Find the last range starting at or before `pos`
+    let mut lo = 0;
+    let mut hi = self.ranges.len();
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      if self.ranges[mid].start <= pos {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    return lo != 0 && self.ranges[lo - 1].covers(pos);
   }
 
   /// Add use to the interval's use list.
@@ -635,6 +2223,27 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
     return None;
   }
 
+  /// Return next use of any kind (including UseAny) after `after` position
+  pub fn next_any_kind_use(&self, after: InstrId) -> Option<Use<G, R> > {
+    for u in self.uses.iter() {
+      if u.pos >= after {
+        return Some(u.clone());
+      }
+    };
+    return None;
+  }
+
+  /// Return next UseFixed(...) or UseRegister after `after` position,
+  /// ignoring uses that only prefer register
+  pub fn next_required_use(&self, after: InstrId) -> Option<Use<G, R> > {
+    for u in self.uses.iter() {
+      if u.pos >= after && !u.kind.is_any() && !u.kind.is_prefer() {
+        return Some(u.clone());
+      }
+    };
+    return None;
+  }
+
   /// Return last UseFixed(...) or UseRegister before `before` position
   pub fn last_use(&self, before: InstrId) -> Option<Use<G, R> > {
     for u in self.uses.rev_iter() {
@@ -655,7 +2264,24 @@ impl<G: GroupHelper<R>,
       &User(ref k) => k.clobbers(group),
       &Gap => false,
       &ToPhi(_) => false,
-      &Phi(_) => false
+      &Phi(_) => false,
+      &Movement(_) => false
+    }
+  }
+
+  /// Return registers clobbered by instruction
+  pub fn clobbered_regs(&self, group: &G, registers: &[R]) -> ~[R] {
+    match self {
+      &User(ref k) => k.clobbered_regs(group, registers),
+      _ => ~[]
+    }
+  }
+
+  /// Return single registers written by instruction besides its output
+  pub fn fixed_clobbers(&self) -> ~[R] {
+    match self {
+      &User(ref k) => k.fixed_clobbers(),
+      _ => ~[]
     }
   }
 
@@ -665,7 +2291,16 @@ impl<G: GroupHelper<R>,
       &User(ref k) => k.temporary(),
       &Gap => ~[],
       &Phi(_) => ~[],
-      &ToPhi(_) => ~[]
+      &ToPhi(_) => ~[],
+      &Movement(_) => ~[]
+    }
+  }
+
+  /// Return use kind of instruction's `i`th temporary operand
+  pub fn temporary_kind(&self, i: uint) -> UseKind<G, R> {
+    match self {
+      &User(ref k) => k.temporary_kind(i),
+      _ => fail!("Only user instructions have temporary operands")
     }
   }
 
@@ -674,6 +2309,7 @@ impl<G: GroupHelper<R>,
     match self {
       &User(ref k) => k.use_kind(i),
       &Gap => fail!("Gap can't have any input"),
+      &Movement(_) => fail!("Movement can't have any input"),
       &Phi(ref g) => UseAny(g.clone()),
       &ToPhi(ref g) => UseAny(g.clone())
     }
@@ -684,10 +2320,106 @@ impl<G: GroupHelper<R>,
     match self {
       &User(ref k) => k.result_kind(),
       &Gap => None,
+      &Movement(_) => None,
       &Phi(ref g) => Some(UseAny(g.clone())),
       &ToPhi(ref g) => Some(UseAny(g.clone()))
     }
   }
+
+  /// Return true if instruction may read any register of `group`
+  pub fn reads_all(&self, group: &G) -> bool {
+    match self {
+      &User(ref k) => k.reads_all(group),
+      _ => false
+    }
+  }
+
+  /// Return true if no moves should be inserted right after instruction
+  pub fn no_spill_after(&self) -> bool {
+    match self {
+      &User(ref k) => k.no_spill_after(),
+      _ => false
+    }
+  }
+
+  /// Return index of input tied to instruction's output
+  pub fn tied_input(&self) -> Option<uint> {
+    match self {
+      &User(ref k) => k.tied_input(),
+      _ => None
+    }
+  }
+
+  /// Return index of input holding output's value on paths that don't
+  /// pass through instruction's block
+  pub fn maybe_def(&self) -> Option<uint> {
+    match self {
+      &User(ref k) => k.maybe_def(),
+      _ => None
+    }
+  }
+
+  /// Return true if instruction could end block without successors
+  pub fn is_return(&self) -> bool {
+    match self {
+      &User(ref k) => k.is_return(),
+      _ => false
+    }
+  }
+
+  /// Return true if instruction could be cheaply recomputed at any position
+  pub fn is_rematerializable(&self) -> bool {
+    match self {
+      &User(ref k) => k.is_rematerializable(),
+      _ => false
+    }
+  }
+
+  /// Return true if instruction's output occupies a pair of registers
+  pub fn is_paired(&self) -> bool {
+    match self {
+      &User(ref k) => k.is_paired(),
+      _ => false
+    }
+  }
+
+  /// Return true if order of instruction's inputs doesn't matter
+  pub fn is_commutative(&self) -> bool {
+    match self {
+      &User(ref k) => k.is_commutative(),
+      _ => false
+    }
+  }
+
+  /// Return true if instruction can't be removed, even if it's unused
+  pub fn has_side_effects(&self) -> bool {
+    match self {
+      &User(ref k) => k.has_side_effects(),
+      _ => true
+    }
+  }
+}
+
+impl Statistics {
+  pub fn new() -> Statistics {
+    Statistics {
+      splits: 0,
+      moves: 0,
+      swaps: 0,
+      reloads: 0,
+      remats: 0,
+      fast_groups: 0
+    }
+  }
+
+  pub fn add(&mut self, other: &Statistics) {
+    self.splits += other.splits;
+    self.moves += other.moves;
+    self.swaps += other.swaps;
+    self.reloads += other.reloads;
+    self.remats += other.remats;
+    self.fast_groups += other.fast_groups;
+  }
 }
 
 impl LiveRange {
@@ -715,11 +2447,39 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Value<G, R> {
     }
   }
 
+  /// Return location of the high half of a paired value
+  pub fn pair_high(&self) -> Value<G, R> {
+    match self {
+      &RegisterVal(ref r) => {
+        RegisterVal(RegisterHelper::from_uint(&r.group(), r.to_uint() + 1))
+      },
+      &StackVal(ref g, StackId(offset)) => {
+        StackVal(g.clone(), StackId(offset + g.spill_size()))
+      },
+      _ => fail!("Unexpected paired value")
+    }
+  }
+
+  pub fn is_stack(&self) -> bool {
+    match self {
+      &StackVal(_, _) => true,
+      _ => false
+    }
+  }
+
+  pub fn is_remat(&self) -> bool {
+    match self {
+      &RematVal(_, _) => true,
+      _ => false
+    }
+  }
+
   pub fn group(&self) -> G {
     match self {
       &VirtualVal(ref g) => g.clone(),
       &RegisterVal(ref r) => r.group(),
-      &StackVal(ref g, _) => g.clone()
+      &StackVal(ref g, _) => g.clone(),
+      &RematVal(ref g, _) => g.clone()
     }
   }
 }
@@ -739,10 +2499,18 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > UseKind<G, R> {
     }
   }
 
+  pub fn is_prefer(&self) -> bool {
+    match self {
+      &UsePrefer(_) => true,
+      _ => false
+    }
+  }
+
   pub fn group(&self) -> G {
     match self {
       &UseRegister(ref g) => g.clone(),
       &UseAny(ref g) => g.clone(),
+      &UsePrefer(ref g) => g.clone(),
       &UseFixed(ref r) => r.group(),
     }
   }
@@ -754,14 +2522,18 @@ impl GapState {
   }
 }
 
+// NOTE: every block (even an empty one) has gaps at its start and end after
+// flattening, so positions are available since then
 impl<K> Block<K> {
   pub fn start(&self) -> InstrId {
-    assert!(self.instructions.len() != 0);
+    assert!(self.instructions.len() != 0,
+            "Empty block has no positions until graph is flattened");
     return *self.instructions.head();
   }
 
   pub fn end(&self) -> InstrId {
-    assert!(self.instructions.len() != 0);
+    assert!(self.instructions.len() != 0,
+            "Empty block has no positions until graph is flattened");
     return self.instructions.last().next();
   }
 }
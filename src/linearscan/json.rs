@@ -1,10 +1,35 @@
+use extra::json;
 use extra::json::{ToJson, Json, Object, List, String, Number, Boolean, Null};
+use extra::smallintmap::SmallIntMap;
 use std::hashmap::HashMap;
-use linearscan::{KindHelper, GroupHelper, RegisterHelper};
+use linearscan::{KindHelper, GroupHelper, RegisterHelper, GraphAPI};
 use linearscan::graph::{Graph, Block, Instruction, Interval, LiveRange,
-                        User, Gap, GapState, Move, Swap, ToPhi, Phi,
-                        Use, UseAny, UseRegister, UseFixed,
-                        Value, VirtualVal, RegisterVal, StackVal};
+                        User, Gap, GapState, Move, Swap, CrossMove, ToPhi, Phi,
+                        Movement, StackId,
+                        Use, UseAny, UseRegister, UsePrefer, UseFixed,
+                        Value, VirtualVal, RegisterVal, StackVal, RematVal};
+use linearscan::generator::RewriteTable;
+
+pub trait AllocationJson {
+  // Export allocated graph: blocks, instructions with locations of their
+  // operands, gap moves and spill slots.
+  //
+  // Schema (version 1):
+  //   root: block id
+  //   blocks: [{ id, successors, predecessors, instructions, frequency }]
+  //   instructions: [{ id, block, kind, group, inputs, phi, registers }]
+  //   gaps: [{ pos, actions: [{ type, from, to }] }]
+  //   spills: [{ interval, group, slot }]
+  // where `kind` is the user kind's string or `~phi`/`~to_phi`, `phi` is the
+  // target of `~to_phi`, and locations are `{ type, group, register|slot }`.
+  //
+  // Limitations:
+  //   `group` is set only for `~phi`/`~to_phi`, it is null for user kinds
+  //   (their groups are given by `KindHelper`)
+  //   pre-colored outputs are exported only as their locations, `from_json`
+  //   doesn't restore them
+  fn allocation_to_json(&self) -> Json;
+}
 
 trait JsonHelper {
   fn get_blocks(&self) -> Json;
@@ -12,6 +37,128 @@ trait JsonHelper {
   fn get_instructions(&self) -> Json;
 }
 
+trait AllocationJsonHelper {
+  fn alloc_blocks(&self) -> Json;
+  fn alloc_instructions(&self) -> Json;
+  fn alloc_gaps(&self) -> Json;
+  fn alloc_spills(&self) -> Json;
+}
+
+// Plain description of exported block
+struct BlockDesc {
+  id: uint,
+  successors: ~[uint],
+  predecessors: ~[uint],
+  instructions: ~[uint],
+  frequency: Option<f64>
+}
+
+// Plain description of exported instruction
+struct InstrDesc {
+  id: uint,
+  block: Option<uint>,
+  kind: ~str,
+  group: Option<uint>,
+  inputs: ~[uint],
+  phi: Option<uint>
+}
+
+fn json_field<'r>(json: &'r Json, key: &str) -> Option<&'r Json> {
+  match *json {
+    Object(ref obj) => obj.find(&key.to_owned()),
+    _ => None
+  }
+}
+
+fn json_uint(json: &Json, key: &str) -> Option<uint> {
+  match json_field(json, key) {
+    Some(&Number(n)) if n >= 0f => Some(n as uint),
+    _ => None
+  }
+}
+
+fn json_ids(json: &Json, key: &str) -> Option<~[uint]> {
+  let list = match json_field(json, key) {
+    Some(&List(ref list)) => list,
+    _ => { return None; }
+  };
+  let mut result = ~[];
+  for item in list.iter() {
+    match *item {
+      Number(n) if n >= 0f => result.push(n as uint),
+      _ => { return None; }
+    }
+  }
+  return Some(result);
+}
+
+fn decode_block(json: &Json) -> Option<BlockDesc> {
+  match (json_uint(json, "id"),
+         json_ids(json, "successors"),
+         json_ids(json, "predecessors"),
+         json_ids(json, "instructions")) {
+    (Some(id), Some(successors), Some(predecessors), Some(instructions)) => {
+      Some(BlockDesc {
+        id: id,
+        successors: successors,
+        predecessors: predecessors,
+        instructions: instructions,
+        frequency: match json_field(json, "frequency") {
+          Some(&Number(f)) => Some(f as f64),
+          _ => None
+        }
+      })
+    },
+    _ => None
+  }
+}
+
+fn decode_instr(json: &Json) -> Option<InstrDesc> {
+  let kind = match json_field(json, "kind") {
+    Some(&String(ref kind)) => kind.clone(),
+    _ => { return None; }
+  };
+  match (json_uint(json, "id"), json_ids(json, "inputs")) {
+    (Some(id), Some(inputs)) => Some(InstrDesc {
+      id: id,
+      block: json_uint(json, "block"),
+      kind: kind,
+      group: json_uint(json, "group"),
+      inputs: inputs,
+      phi: json_uint(json, "phi")
+    }),
+    _ => None
+  }
+}
+
+fn id_list(ids: &[uint]) -> Json {
+  List(do ids.map() |id| { Number(*id as float) })
+}
+
+fn location_to_json<G: GroupHelper<R>+ToStr,
+                    R: RegisterHelper<G>+ToStr>(value: &Value<G, R>) -> Json {
+  let mut obj = ~HashMap::new();
+
+  obj.insert(~"group", String(value.group().to_str()));
+  match value {
+    &VirtualVal(_) => { obj.insert(~"type", String(~"virtual")); },
+    &RegisterVal(ref r) => {
+      obj.insert(~"type", String(~"register"));
+      obj.insert(~"register", String(r.to_str()));
+    },
+    &StackVal(_, StackId(slot)) => {
+      obj.insert(~"type", String(~"stack"));
+      obj.insert(~"slot", Number(slot as float));
+    },
+    &RematVal(_, id) => {
+      obj.insert(~"type", String(~"remat"));
+      obj.insert(~"instr", Number(id.to_uint() as float));
+    }
+  }
+
+  return Object(obj);
+}
+
 impl<G: GroupHelper<R>+ToStr,
      R: RegisterHelper<G>+ToStr,
      K: KindHelper<G, R>+ToStr> ToJson for Block<K> {
@@ -43,7 +190,12 @@ impl<G: GroupHelper<R>+ToStr,
       User(ref kind) => kind.to_str(),
       Gap => ~"~gap",
       ToPhi(_) => ~"~to_phi",
-      Phi(_) => ~"~phi"
+      Phi(_) => ~"~phi",
+      Movement(ref act) => match act.kind {
+        Move => ~"~move",
+        Swap => ~"~swap",
+        CrossMove => ~"~cross_move"
+      }
     }));
     obj.insert(~"inputs", List(do self.inputs.map() |input| {
       Number(input.to_uint() as float)
@@ -68,7 +220,8 @@ impl ToJson for GapState {
       let mut obj = ~HashMap::new();
       obj.insert(~"type", String(match act.kind {
         Move => ~"move",
-        Swap => ~"swap"
+        Swap => ~"swap",
+        CrossMove => ~"cross_move"
       }));
       obj.insert(~"from", Number(act.from.to_uint() as float));
       obj.insert(~"to", Number(act.to.to_uint() as float));
@@ -120,6 +273,7 @@ impl<G: GroupHelper<R>+ToStr,
     match self.kind {
       UseAny(_) => kind.insert(~"type", String(~"any")),
       UseRegister(_) => kind.insert(~"type", String(~"reg")),
+      UsePrefer(_) => kind.insert(~"type", String(~"prefer")),
       UseFixed(ref val) => {
         kind.insert(~"type", String(~"fixed"));
         kind.insert(~"value", String(val.to_str()))
@@ -139,7 +293,8 @@ impl<G: GroupHelper<R>+ToStr,
     return String(match self {
       &VirtualVal(ref g) => ~"v{" + g.to_str() + "}",
       &RegisterVal(ref id) => id.to_str(),
-      &StackVal(ref g, id) => ~"s{" + g.to_str() + "}" + id.to_str()
+      &StackVal(ref g, id) => ~"s{" + g.to_str() + "}" + id.to_str(),
+      &RematVal(ref g, id) => ~"r{" + g.to_str() + "}" + id.to_uint().to_str()
     });
   }
 }
@@ -214,3 +369,337 @@ impl<G: GroupHelper<R>+ToStr,
     return result.to_json();
   }
 }
+
+impl<G: GroupHelper<R>+ToStr,
+     R: RegisterHelper<G>+ToStr,
+     K: KindHelper<G, R>+ToStr> AllocationJsonHelper for Graph<K, G, R> {
+  fn alloc_blocks(&self) -> Json {
+    let mut result = ~[];
+
+    for (_, block) in self.blocks.iter() {
+      let mut obj = ~HashMap::new();
+
+      // Only instructions given by user, gaps and moves are exported apart
+      let mut instructions = ~[];
+      for id in block.instructions.iter() {
+        match self.get_instr(id).kind {
+          User(_) | ToPhi(_) => instructions.push(id.to_uint()),
+          _ => ()
+        }
+      }
+
+      obj.insert(~"id", Number(block.id.to_uint() as float));
+      obj.insert(~"successors", id_list(do block.successors.map() |succ| {
+        succ.to_uint()
+      }));
+      obj.insert(~"predecessors", id_list(do block.predecessors.map() |p| {
+        p.to_uint()
+      }));
+      obj.insert(~"instructions", id_list(instructions));
+      obj.insert(~"frequency", match block.frequency {
+        Some(f) => Number(f as float),
+        None => Null
+      });
+      result.push(Object(obj));
+    }
+
+    return List(result);
+  }
+
+  fn alloc_instructions(&self) -> Json {
+    let mut result = ~[];
+
+    for (_, instr) in self.instructions.iter() {
+      let mut obj = ~HashMap::new();
+      let (kind, group) = match instr.kind {
+        User(ref k) => (k.to_str(), Null),
+        Phi(ref g) => (~"~phi", Number(g.to_uint() as float)),
+        ToPhi(ref g) => (~"~to_phi", Number(g.to_uint() as float)),
+        Gap | Movement(_) => loop
+      };
+
+      // Phi's block is the one it is attached to
+      let block = match instr.kind {
+        Phi(_) => self.phi_block(&instr.id),
+        _ => Some(instr.block)
+      };
+      obj.insert(~"id", Number(instr.id.to_uint() as float));
+      obj.insert(~"block", match block {
+        Some(block) => Number(block.to_uint() as float),
+        None => Null
+      });
+      obj.insert(~"kind", String(kind));
+      obj.insert(~"group", group);
+      obj.insert(~"inputs", id_list(do instr.inputs.map() |i| {
+        i.to_uint()
+      }));
+
+      // Phi that receives value of `~to_phi`
+      let mut phi = Null;
+      match instr.kind {
+        ToPhi(_) => for id in self.phis.iter() {
+          if self.get_instr(id).inputs.contains(&instr.id) {
+            phi = Number(id.to_uint() as float);
+          }
+        },
+        _ => ()
+      }
+      obj.insert(~"phi", phi);
+
+      // Phis are not executed, their locations are given by `~to_phi`
+      match instr.kind {
+        Phi(_) => (),
+        _ => {
+          let assignment = self.get_assignment(&instr.id);
+          let mut registers = ~HashMap::new();
+          registers.insert(~"output", match assignment.output {
+            Some(ref out) => location_to_json(out),
+            None => Null
+          });
+          registers.insert(~"inputs", List(do assignment.inputs.map() |i| {
+            location_to_json(i)
+          }));
+          registers.insert(~"temporary", List(do assignment.temps.map() |t| {
+            location_to_json(t)
+          }));
+          obj.insert(~"registers", Object(registers));
+        }
+      }
+
+      result.push(Object(obj));
+    }
+
+    return List(result);
+  }
+
+  fn alloc_gaps(&self) -> Json {
+    let mut result = ~[];
+
+    for (pos, state) in self.gaps.iter() {
+      let mut obj = ~HashMap::new();
+      obj.insert(~"pos", Number(pos as float));
+      obj.insert(~"actions", List(do state.actions.map() |act| {
+        let mut obj = ~HashMap::new();
+        obj.insert(~"type", String(match act.kind {
+          Move => ~"move",
+          Swap => ~"swap",
+          CrossMove => ~"cross_move"
+        }));
+        obj.insert(~"from",
+                   location_to_json(&self.get_interval(&act.from).value));
+        obj.insert(~"to", location_to_json(&self.get_interval(&act.to).value));
+        Object(obj)
+      }));
+      result.push(Object(obj));
+    }
+
+    return List(result);
+  }
+
+  fn alloc_spills(&self) -> Json {
+    let mut result = ~[];
+
+    for (_, interval) in self.intervals.iter() {
+      match interval.value {
+        StackVal(ref g, StackId(slot)) if interval.ranges.len() > 0 => {
+          let mut obj = ~HashMap::new();
+          obj.insert(~"interval", Number(interval.id.to_uint() as float));
+          obj.insert(~"group", String(g.to_str()));
+          obj.insert(~"slot", Number(slot as float));
+          result.push(Object(obj));
+        },
+        _ => ()
+      }
+    }
+
+    return List(result);
+  }
+}
+
+impl<G: GroupHelper<R>+ToStr,
+     R: RegisterHelper<G>+ToStr,
+     K: KindHelper<G, R>+ToStr> AllocationJson for Graph<K, G, R> {
+  fn allocation_to_json(&self) -> Json {
+    assert!(self.prepared);
+    let mut result = ~HashMap::new();
+
+    result.insert(~"version", Number(1f));
+    result.insert(~"root", match self.root {
+      Some(root) => Number(root.to_uint() as float),
+      None => Null
+    });
+    result.insert(~"blocks", self.alloc_blocks());
+    result.insert(~"instructions", self.alloc_instructions());
+    result.insert(~"gaps", self.alloc_gaps());
+    result.insert(~"spills", self.alloc_spills());
+
+    return Object(result);
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > Graph<K, G, R> {
+  /// Rebuild graph from the output of `allocation_to_json`, `kind` should
+  /// return user kind given its string representation.
+  /// NOTE: pre-colored outputs are not restored
+  pub fn from_json(source: &str,
+                   kind: &fn(&str) -> Option<K>)
+      -> Result<Graph<K, G, R>, ~str> {
+    let json = match json::from_str(source) {
+      Ok(json) => json,
+      Err(err) => { return Err(fmt!("Invalid JSON: %?", err)); }
+    };
+    let mut g = Graph::new();
+    match g.load_json(&json, kind) {
+      Ok(_) => Ok(g),
+      Err(reason) => Err(reason)
+    }
+  }
+
+  fn load_json(&mut self,
+               json: &Json,
+               kind: &fn(&str) -> Option<K>) -> Result<(), ~str> {
+    let mut blocks = ~[];
+    let mut instructions = ~[];
+    match (json_field(json, "blocks"), json_field(json, "instructions")) {
+      (Some(&List(ref b)), Some(&List(ref i))) => {
+        for block in b.iter() {
+          match decode_block(block) {
+            Some(desc) => blocks.push(desc),
+            None => { return Err(~"Malformed block"); }
+          }
+        }
+        for instr in i.iter() {
+          match decode_instr(instr) {
+            Some(desc) => instructions.push(desc),
+            None => { return Err(~"Malformed instruction"); }
+          }
+        }
+      },
+      _ => { return Err(~"Expected lists of blocks and instructions"); }
+    }
+
+    // Exported ids => new ones
+    let mut block_map = ~SmallIntMap::new();
+    let mut instr_map = ~SmallIntMap::new();
+    let mut descs = ~SmallIntMap::new();
+    for block in blocks.iter() {
+      block_map.insert(block.id, self.empty_block());
+    }
+
+    // Create phis and user instructions, inputs are filled later
+    for (i, instr) in instructions.iter().enumerate() {
+      descs.insert(instr.id, i);
+      let id = if instr.kind == ~"~phi" {
+        match instr.group {
+          Some(group) => self.phi(GroupHelper::from_uint(group)),
+          None => { return Err(fmt!("Phi %u has no group", instr.id)); }
+        }
+      } else if instr.kind == ~"~to_phi" {
+        loop
+      } else {
+        match kind(instr.kind.as_slice()) {
+          Some(k) => self.new_instr(k, ~[]),
+          None => {
+            return Err(fmt!("Unknown instruction kind `%s`", instr.kind));
+          }
+        }
+      };
+      instr_map.insert(instr.id, id);
+    }
+
+    // Check that every referenced id is known
+    for block in blocks.iter() {
+      let mut edges = block.successors.clone();
+      edges.push_all(block.predecessors);
+      for id in edges.iter() {
+        if !block_map.contains_key(id) {
+          return Err(fmt!("Unknown block %u", *id));
+        }
+      }
+      for id in block.instructions.iter() {
+        if !descs.contains_key(id) {
+          return Err(fmt!("Unknown instruction %u", *id));
+        }
+      }
+    }
+    for instr in instructions.iter() {
+      let mut uses = instr.inputs.clone();
+      match instr.phi {
+        Some(phi) => uses.push(phi),
+        None => ()
+      }
+      for id in uses.iter() {
+        if !instr_map.contains_key(id) {
+          return Err(fmt!("Instruction %u uses unknown instruction %u",
+                          instr.id,
+                          *id));
+        }
+      }
+      if instr.kind == ~"~to_phi" && (instr.inputs.len() != 1 ||
+                                      instr.phi.is_none()) {
+        return Err(fmt!("Malformed phi move %u", instr.id));
+      }
+    }
+
+    // Attach phis to their blocks
+    for instr in instructions.iter() {
+      if instr.kind != ~"~phi" {
+        loop;
+      }
+      match instr.block {
+        Some(block) if block_map.contains_key(&block) => {
+          self.attach_phi(*instr_map.get(&instr.id), *block_map.get(&block));
+        },
+        _ => { return Err(fmt!("Phi %u has unknown block", instr.id)); }
+      }
+    }
+
+    // Fill inputs of user instructions, phis get them from moves
+    for instr in instructions.iter() {
+      if instr.kind.starts_with("~") {
+        loop;
+      }
+      let id = *instr_map.get(&instr.id);
+      self.get_mut_instr(&id).inputs = do instr.inputs.map() |input| {
+        *instr_map.get(input)
+      };
+    }
+
+    // Fill blocks and edges
+    for block in blocks.iter() {
+      let id = *block_map.get(&block.id);
+      do self.with_block(id) |b| {
+        for instr_id in block.instructions.iter() {
+          let instr = &instructions[*descs.get(instr_id)];
+          if instr.kind == ~"~to_phi" {
+            b.to_phi(*instr_map.get(&instr.inputs[0]),
+                     *instr_map.get(&instr.phi.unwrap()));
+          } else {
+            b.add_existing(*instr_map.get(instr_id));
+          }
+        }
+      };
+
+      for succ in block.successors.iter() {
+        self.get_mut_block(&id).add_successor(*block_map.get(succ));
+      }
+      for pred in block.predecessors.iter() {
+        self.get_mut_block(&id).add_predecessor(*block_map.get(pred));
+      }
+      self.get_mut_block(&id).frequency = block.frequency;
+
+      // Blocks on split edges have no instructions, `end` can't be used
+      self.get_mut_block(&id).ended = true;
+    }
+
+    match json_uint(json, "root") {
+      Some(root) if block_map.contains_key(&root) => {
+        self.root = Some(*block_map.get(&root));
+      },
+      _ => { return Err(~"Unknown root block"); }
+    }
+    return Ok(());
+  }
+}
@@ -4,6 +4,12 @@ use extra::bitv::BitvSet;
 
 pub trait Liveness {
   fn liveness_analysis(&mut self);
+
+  // Recompute liveness after blocks or edges were added, `dirty` should
+  // contain new blocks and blocks whose successors were changed.
+  // NOTE: live sets are only growing here, use `liveness_analysis` after
+  // removing edges or instructions
+  fn relive(&mut self, dirty: &[BlockId]);
 }
 
 trait LivenessHelper {
@@ -12,6 +18,9 @@ trait LivenessHelper {
 
   // Build live_in, live_out
   fn build_global(&mut self, blocks: &[BlockId]);
+
+  // Update block's live_out and live_in, return true if any has changed
+  fn update_block(&mut self, block: &BlockId) -> bool;
 }
 
 impl<G: GroupHelper<R>,
@@ -19,9 +28,39 @@ impl<G: GroupHelper<R>,
      K: KindHelper<G, R> > Liveness for Graph<K, G, R> {
   fn liveness_analysis(&mut self) {
     let blocks = self.get_block_list();
+    for block in blocks.iter() {
+      let block = self.get_mut_block(block);
+      block.live_gen = ~BitvSet::new();
+      block.live_kill = ~BitvSet::new();
+      block.live_in = ~BitvSet::new();
+      block.live_out = ~BitvSet::new();
+    }
     self.build_local(blocks);
     self.build_global(blocks);
   }
+
+  fn relive(&mut self, dirty: &[BlockId]) {
+    // Local sets of new blocks
+    for block in dirty.iter() {
+      let block = self.get_mut_block(block);
+      block.live_gen = ~BitvSet::new();
+      block.live_kill = ~BitvSet::new();
+    }
+    self.build_local(dirty);
+
+    // Propagate changes to predecessors until nothing changes
+    let mut queue = dirty.to_owned();
+    while queue.len() > 0 {
+      let block = queue.pop();
+      if self.update_block(&block) {
+        for pred in self.get_block(&block).predecessors.iter() {
+          if !queue.contains(pred) {
+            queue.push(*pred);
+          }
+        }
+      }
+    }
+  }
 }
 
 impl<G: GroupHelper<R>,
@@ -57,31 +96,39 @@ impl<G: GroupHelper<R>,
       change = false;
 
       for block in blocks.rev_iter() {
-        let successors = self.get_block(block).successors.clone();
-
-        let mut tmp = ~BitvSet::new();
-        for succ in successors.iter() {
-          tmp.union_with(self.get_block(succ).live_in);
-        }
-
-        // Propagate succ.live_in to block.live_out
-        if self.get_block(block).live_out != tmp {
-          self.get_mut_block(block).live_out = tmp;
-          change = true;
-        }
-
-        // Propagate:
-        // `union(diff(block.live_out, block.live_kill), block.live_gen)`
-        // to block.live_in
-        let mut old = self.get_block(block).live_out.clone();
-        old.difference_with(self.get_block(block).live_kill);
-        old.union_with(self.get_block(block).live_gen);
-        if old != self.get_block(block).live_in {
-          self.get_mut_block(block).live_in = old;
+        if self.update_block(block) {
           change = true;
         }
       }
     }
   }
 
+  fn update_block(&mut self, block: &BlockId) -> bool {
+    let mut change = false;
+    let successors = self.get_block(block).successors.clone();
+
+    let mut tmp = ~BitvSet::new();
+    for succ in successors.iter() {
+      tmp.union_with(self.get_block(succ).live_in);
+    }
+
+    // Propagate succ.live_in to block.live_out
+    if self.get_block(block).live_out != tmp {
+      self.get_mut_block(block).live_out = tmp;
+      change = true;
+    }
+
+    // Propagate:
+    // `union(diff(block.live_out, block.live_kill), block.live_gen)`
+    // to block.live_in
+    let mut old = self.get_block(block).live_out.clone();
+    old.difference_with(self.get_block(block).live_kill);
+    old.union_with(self.get_block(block).live_gen);
+    if old != self.get_block(block).live_in {
+      self.get_mut_block(block).live_in = old;
+      change = true;
+    }
+    return change;
+  }
+
 }
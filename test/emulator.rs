@@ -5,42 +5,66 @@ use extra::smallintmap::SmallIntMap;
 pub enum Kind {
   Increment,
   Sum,
+  CommutativeSum,
+  Add,
   DoubleSum,
   MultAdd,
   BranchIfBigger,
+  Compare,
+  BranchIfTrue,
+  Switch,
   JustUse,
   FixedUse,
   Nop,
   Print,
   Number(uint),
+  Constant(uint),
   DoubleNumber(float),
   ToDouble,
   Return,
-  ReturnDouble
+  ReturnDouble,
+  PairNumber(uint),
+  PairSum,
+  FromPair,
+  WideNumber(uint),
+  FromWide,
+  PartialCall,
+  Shift,
+  Prefer,
+  FixedDouble,
+  ReadAll,
+  ReturnAny,
+  Mul,
+  Redefine
 }
 
 // Register groups
 #[deriving(Clone, Eq, ToStr)]
 pub enum Group {
   Normal,
-  Double
+  Double,
+  Pair
 }
 
 // Registers
 #[deriving(Clone, Eq, ToStr)]
 pub enum Register {
   rax, rbx, rcx, rdx,
-  xmm1, xmm2, xmm3, xmm4
+  xmm1, xmm2, xmm3, xmm4,
+
+  // Aliasing pairs: `ax` overlaps with `eax`, `bx` overlaps with `ebx`
+  ax, eax, bx, ebx
 }
 
 impl GroupHelper<Register> for Group {
   fn groups() -> ~[Group] {
-    ~[Normal, Double]
+    ~[Normal, Double, Pair]
   }
   fn registers(&self) -> ~[Register] {
     match *self {
       Normal => ~[rax, rbx, rcx, rdx],
-      Double => ~[xmm1, xmm2, xmm3, xmm4]
+      Double => ~[xmm1, xmm2, xmm3, xmm4],
+      Pair => ~[ax, eax, bx, ebx]
     }
   }
   fn to_uint(&self) -> uint { *self as uint }
@@ -48,23 +72,33 @@ impl GroupHelper<Register> for Group {
     match i {
       0 => Normal,
       1 => Double,
+      2 => Pair,
       _ => fail!()
     }
   }
+  fn spill_size(&self) -> uint {
+    match *self {
+      Normal => 4,
+      Double => 8,
+      Pair => 2
+    }
+  }
 }
 
 impl RegisterHelper<Group> for Register {
   fn group(&self) -> Group {
     match *self {
       rax => Normal, rbx => Normal, rcx => Normal, rdx => Normal,
-      xmm1 => Double, xmm2 => Double, xmm3 => Double, xmm4 => Double
+      xmm1 => Double, xmm2 => Double, xmm3 => Double, xmm4 => Double,
+      ax => Pair, eax => Pair, bx => Pair, ebx => Pair
     }
   }
 
   fn to_uint(&self) -> uint {
     match self.group() {
       Normal => *self as uint,
-      Double => *self as uint - 4
+      Double => *self as uint - 4,
+      Pair => *self as uint - 8
     }
   }
 
@@ -75,26 +109,63 @@ impl RegisterHelper<Group> for Register {
       },
       &Double => match i {
         0 => xmm1, 1 => xmm2, 2 => xmm3, 3 => xmm4, _ => fail!()
+      },
+      &Pair => match i {
+        0 => ax, 1 => eax, 2 => bx, 3 => ebx, _ => fail!()
       }
     }
   }
+
+  fn aliases(&self) -> ~[Register] {
+    match *self {
+      ax => ~[eax], eax => ~[ax],
+      bx => ~[ebx], ebx => ~[bx],
+      _ => ~[]
+    }
+  }
+
+  fn is_callee_saved(&self) -> bool {
+    match *self {
+      xmm4 => true,
+      _ => false
+    }
+  }
 }
 
 impl KindHelper<Group, Register> for Kind {
-  fn clobbers(&self, _: &Group) -> bool {
+  fn clobbers(&self, group: &Group) -> bool {
     match self {
       &Print => true,
+      &PartialCall => *group == Normal,
       _ => false
     }
   }
 
+  fn clobbered_regs(&self, group: &Group,
+                    registers: &[Register]) -> ~[Register] {
+    match self {
+      &PartialCall if *group == Normal => ~[rax, rbx],
+      _ if self.clobbers(group) => registers.to_owned(),
+      _ => ~[]
+    }
+  }
+
   fn temporary(&self) -> ~[Group] {
     match self {
       &BranchIfBigger => ~[Normal],
+      &Shift => ~[Normal],
       _ => ~[]
     }
   }
 
+  fn temporary_kind(&self, i: uint) -> UseKind<Group, Register> {
+    match self {
+      // Like shift count in `cl`
+      &Shift => rdx.use_fixed(),
+      _ => self.temporary()[i].use_reg()
+    }
+  }
+
   fn use_kind(&self, i: uint) -> UseKind<Group, Register> {
     match self {
       &BranchIfBigger if i == 0 => rcx.use_fixed(),
@@ -108,6 +179,16 @@ impl KindHelper<Group, Register> for Kind {
       &ReturnDouble => xmm1.use_fixed(),
       &DoubleSum => Double.use_reg(),
       &ToDouble => Normal.use_reg(),
+      &BranchIfTrue => Normal.use_reg(),
+      &Switch => Normal.use_reg(),
+      &PairSum => Pair.use_reg(),
+      &FromPair => Pair.use_reg(),
+      &FromWide => Normal.use_reg(),
+      &Add => Normal.use_reg(),
+      &Redefine => Normal.use_reg(),
+      &Shift => Normal.use_reg(),
+      &Prefer => Normal.use_prefer(),
+      &FixedDouble => xmm2.use_fixed(),
       _ => Normal.use_any()
     }
   }
@@ -117,15 +198,100 @@ impl KindHelper<Group, Register> for Kind {
       &Return => None,
       &ReturnDouble => None,
       &BranchIfBigger => None,
+      &BranchIfTrue => None,
+      &Switch => None,
       &JustUse => None,
       &FixedUse => None,
+      &PartialCall => None,
+      &Prefer => None,
+      &FixedDouble => None,
+      &ReadAll => None,
+      &ReturnAny => None,
       &Nop => None,
       &DoubleNumber(_) => Some(Double.use_any()),
       &DoubleSum => Some(Double.use_reg()),
       &ToDouble => Some(Double.use_reg()),
+      &PairNumber(_) => Some(Pair.use_reg()),
+      &PairSum => Some(Pair.use_reg()),
       _ => Some(Normal.use_reg())
     }
   }
+
+  fn fixed_clobbers(&self) -> ~[Register] {
+    match self {
+      // High half of the product
+      &Mul => ~[rdx],
+      _ => ~[]
+    }
+  }
+
+  fn reads_all(&self, group: &Group) -> bool {
+    match self {
+      // Like inline assembly
+      &ReadAll => *group == Normal,
+      _ => false
+    }
+  }
+
+  fn no_spill_after(&self) -> bool {
+    match self {
+      &Compare => true,
+      _ => false
+    }
+  }
+
+  fn tied_input(&self) -> Option<uint> {
+    match self {
+      &Add => Some(0),
+      _ => None
+    }
+  }
+
+  fn maybe_def(&self) -> Option<uint> {
+    match self {
+      // Previous value is kept on paths not passing through instruction
+      &Redefine => Some(0),
+      _ => None
+    }
+  }
+
+  fn is_rematerializable(&self) -> bool {
+    match self {
+      &Constant(_) => true,
+      _ => false
+    }
+  }
+
+  fn is_paired(&self) -> bool {
+    match self {
+      &WideNumber(_) => true,
+      _ => false
+    }
+  }
+
+  fn is_return(&self) -> bool {
+    match self {
+      &Return | &ReturnDouble | &ReturnAny => true,
+      _ => false
+    }
+  }
+
+  fn is_commutative(&self) -> bool {
+    match self {
+      &CommutativeSum => true,
+      _ => false
+    }
+  }
+
+  fn has_side_effects(&self) -> bool {
+    match self {
+      &Number(_) | &Constant(_) | &DoubleNumber(_) | &PairNumber(_) |
+      &WideNumber(_) | &Increment | &Sum | &CommutativeSum | &Add |
+      &DoubleSum | &MultAdd | &Compare | &ToDouble | &PairSum | &FromPair |
+      &FromWide | &Redefine => false,
+      _ => true
+    }
+  }
 }
 
 pub struct Emulator {
@@ -136,13 +302,18 @@ pub struct Emulator {
   registers: ~SmallIntMap<uint>,
   double_registers: ~SmallIntMap<float>,
   stack: ~SmallIntMap<uint>,
-  double_stack: ~SmallIntMap<float>
+  double_stack: ~SmallIntMap<float>,
+
+  // NOTE: aliasing registers share the same storage
+  pair_registers: ~SmallIntMap<uint>,
+  pair_stack: ~SmallIntMap<uint>
 }
 
 #[deriving(Clone)]
 enum Instruction {
   Move(Value<Group, Register>, Value<Group, Register>),
   Swap(Value<Group, Register>, Value<Group, Register>),
+  CrossMove(Value<Group, Register>, Value<Group, Register>),
   UnexpectedEnd,
   Block(BlockId),
   Goto(BlockId),
@@ -179,6 +350,12 @@ impl GeneratorFunctions<Kind, Group, Register> for Emulator {
     self.instructions.push(Move(from.clone(), to.clone()));
   }
 
+  fn cross_move(&mut self,
+                from: &Value<Group, Register>,
+                to: &Value<Group, Register>) {
+    self.instructions.push(CrossMove(from.clone(), to.clone()));
+  }
+
   fn block(&mut self, id: BlockId) {
     let ip = self.instructions.len();
     self.blocks.insert(id.to_uint(), ip);
@@ -206,13 +383,28 @@ impl GeneratorFunctions<Kind, Group, Register> for Emulator {
 }
 
 pub fn run_test(expected: Either<uint, float>,
-                body: &fn(b: &mut Graph<Kind, Group, Register>)) {
+                body: &fn(b: &mut Graph<Kind, Group, Register>))
+    -> ~Graph<Kind, Group, Register> {
+  let (g, _) = run_test_with(Config::new(), expected, body);
+  return g;
+}
+
+pub fn run_test_with(config: Config<Kind, Group, Register>,
+                     expected: Either<uint, float>,
+                     body: &fn(b: &mut Graph<Kind, Group, Register>))
+    -> (~Graph<Kind, Group, Register>, AllocatorResult) {
   let mut g = ~Graph::new();
 
   body(&mut *g);
 
-  g.allocate().get();
+  let res = g.allocate(config).get();
+  check_result(g, expected);
+
+  return (g, res);
+}
 
+pub fn check_result(g: &Graph<Kind, Group, Register>,
+                    expected: Either<uint, float>) {
   let mut emu = Emulator::new();
   let got = emu.run(g);
   if got != expected {
@@ -220,6 +412,59 @@ pub fn run_test(expected: Either<uint, float>,
   }
 }
 
+pub fn count_swaps(g: &Graph<Kind, Group, Register>) -> uint {
+  let mut emu = Emulator::new();
+  g.generate(&mut emu);
+  let mut count = 0;
+  for instr in emu.instructions.iter() {
+    match *instr {
+      Swap(_, _) => count += 1,
+      _ => ()
+    }
+  }
+  return count;
+}
+
+pub fn count_moves(g: &Graph<Kind, Group, Register>) -> uint {
+  let mut emu = Emulator::new();
+  g.generate(&mut emu);
+  let mut count = 0;
+  for instr in emu.instructions.iter() {
+    match *instr {
+      Move(ref from, ref to) if from != to => count += 1,
+      _ => ()
+    }
+  }
+  return count;
+}
+
+pub fn count_memory_moves(g: &Graph<Kind, Group, Register>) -> uint {
+  let mut emu = Emulator::new();
+  g.generate(&mut emu);
+  let mut count = 0;
+  for instr in emu.instructions.iter() {
+    match *instr {
+      Move(StackVal(_, _), StackVal(_, _)) => count += 1,
+      Swap(StackVal(_, _), StackVal(_, _)) => count += 1,
+      _ => ()
+    }
+  }
+  return count;
+}
+
+pub fn count_cross_moves(g: &Graph<Kind, Group, Register>) -> uint {
+  let mut emu = Emulator::new();
+  g.generate(&mut emu);
+  let mut count = 0;
+  for instr in emu.instructions.iter() {
+    match *instr {
+      CrossMove(_, _) => count += 1,
+      _ => ()
+    }
+  }
+  return count;
+}
+
 impl Emulator {
   fn new() -> Emulator {
     Emulator {
@@ -230,7 +475,9 @@ impl Emulator {
       registers: ~SmallIntMap::new(),
       double_registers: ~SmallIntMap::new(),
       stack: ~SmallIntMap::new(),
-      double_stack: ~SmallIntMap::new()
+      double_stack: ~SmallIntMap::new(),
+      pair_registers: ~SmallIntMap::new(),
+      pair_stack: ~SmallIntMap::new()
     }
   }
 
@@ -254,6 +501,14 @@ impl Emulator {
           self.put(to, v);
           self.ip += 1;
         },
+        CrossMove(from, to) => {
+          let v = match self.get(from) {
+            Left(n) => Right(n as float),
+            Right(n) => Left(n as uint)
+          };
+          self.put(to, v);
+          self.ip += 1;
+        },
         Swap(left, right) => {
           let t = self.get(left);
           let v = self.get(right);
@@ -281,6 +536,10 @@ impl Emulator {
         Right(*self.double_registers.find(&r.to_uint())
                    .expect("Defined double register"))
       },
+      RegisterVal(r) if r.group() == Pair => {
+        Left(*self.pair_registers.find(&(r.to_uint() / 2))
+                  .expect("Defined pair register"))
+      },
       StackVal(Normal, s) => {
         Left(*self.stack.find(&s.to_uint())
                   .expect("Defined stack slot"))
@@ -289,6 +548,10 @@ impl Emulator {
         Right(*self.double_stack.find(&s.to_uint())
                    .expect("Defined double stack slot"))
       },
+      StackVal(Pair, s) => {
+        Left(*self.pair_stack.find(&s.to_uint())
+                  .expect("Defined pair stack slot"))
+      },
       _ => fail!()
     }
   }
@@ -301,12 +564,18 @@ impl Emulator {
       RegisterVal(r) if r.group() == Double => {
         self.double_registers.insert(r.to_uint(), value.unwrap_right())
       },
+      RegisterVal(r) if r.group() == Pair => {
+        self.pair_registers.insert(r.to_uint() / 2, value.unwrap_left())
+      },
       StackVal(Normal, s) => {
         self.stack.insert(s.to_uint(), value.unwrap_left())
       },
       StackVal(Double, s) => {
         self.double_stack.insert(s.to_uint(), value.unwrap_right())
       },
+      StackVal(Pair, s) => {
+        self.pair_stack.insert(s.to_uint(), value.unwrap_left())
+      },
       _ => fail!()
     };
   }
@@ -321,12 +590,46 @@ impl Emulator {
                             Left(inputs[0].unwrap_left() + 1)),
       JustUse => (), // nop
       FixedUse => (), // nop
+      Prefer => (), // nop
+      FixedDouble => {
+        // Integer value is converted by cross-group move
+        assert!(instr.inputs[0] == RegisterVal(xmm2));
+        assert!(inputs[0].is_right());
+      },
       Nop => (), // nop
-      Print => self.put(out.expect("Print out"), Left(0)),
+      ReadAll => (), // nop
+      Print => {
+        // Call preserves only callee-saved registers
+        let groups: ~[Group] = GroupHelper::groups();
+        for group in groups.iter() {
+          for r in group.registers().iter() {
+            if r.is_callee_saved() {
+              loop;
+            }
+            let garbage = match *group {
+              Double => Right(0xdead as float),
+              _ => Left(0xdead)
+            };
+            self.put(RegisterVal(*r), garbage);
+          }
+        }
+        self.put(out.expect("Print out"), Left(0))
+      },
       Number(n) => self.put(out.expect("Number out"), Left(n)),
+      Constant(n) => self.put(out.expect("Constant out"), Left(n)),
       DoubleNumber(n) => self.put(out.expect("Double Number out"), Right(n)),
       Sum => self.put(out.expect("Sum out"),
                       Left(inputs[0].unwrap_left() + inputs[1].unwrap_left())),
+      CommutativeSum => self.put(out.expect("Commutative sum out"),
+                                 Left(inputs[0].unwrap_left() +
+                                      inputs[1].unwrap_left())),
+      Redefine => self.put(out.expect("Redefine out"), inputs[1]),
+      Add => {
+        // Two-address instruction: output overwrites first input
+        assert!(out.expect("Add out") == instr.inputs[0]);
+        self.put(out.unwrap(),
+                 Left(inputs[0].unwrap_left() + inputs[1].unwrap_left()))
+      },
       MultAdd => self.put(out.expect("Mult add out"),
                           Left(inputs[0].unwrap_left() *
                                  inputs[1].unwrap_left() +
@@ -336,11 +639,49 @@ impl Emulator {
                                   inputs[1].unwrap_right())),
       ToDouble => self.put(out.expect("ToDouble out"),
                            Right(inputs[0].unwrap_left() as float)),
+      PairNumber(n) => self.put(out.expect("PairNumber out"), Left(n)),
+      PairSum => self.put(out.expect("PairSum out"),
+                          Left(inputs[0].unwrap_left() +
+                               inputs[1].unwrap_left())),
+      FromPair => self.put(out.expect("FromPair out"), inputs[0]),
+      WideNumber(n) => {
+        // Low and high halves are stored separately
+        let out = out.expect("WideNumber out");
+        self.put(out, Left(n % 1000));
+        self.put(out.pair_high(), Left(n / 1000));
+      },
+      Mul => {
+        self.put(out.expect("Mul out"),
+                 Left(inputs[0].unwrap_left() * inputs[1].unwrap_left()));
+        self.put(RegisterVal(rdx), Left(0xdead));
+      },
+      PartialCall => {
+        // Only first two registers are clobbered
+        self.put(RegisterVal(rax), Left(0xdead));
+        self.put(RegisterVal(rbx), Left(0xdead));
+      },
+      Shift => {
+        assert!(tmp[0] == RegisterVal(rdx));
+        self.put(tmp[0], Left(1));
+        let count = self.get(tmp[0]).unwrap_left();
+        self.put(out.expect("Shift out"),
+                 Left(inputs[0].unwrap_left() << count));
+      },
+      FromWide => {
+        let high = self.get(instr.inputs[0].pair_high());
+        self.put(out.expect("FromWide out"),
+                 Left(high.unwrap_left() * 1000 + inputs[0].unwrap_left()));
+      },
       Return => {
         assert!(inputs[0].is_left());
         self.result = Some(inputs[0]);
         return;
       },
+      ReturnAny => {
+        assert!(inputs[0].is_left());
+        self.result = Some(inputs[0]);
+        return;
+      },
       ReturnDouble => {
         assert!(inputs[0].is_right());
         self.result = Some(inputs[0]);
@@ -356,6 +697,28 @@ impl Emulator {
                                 .expect("branch false");
         }
         return;
+      },
+      Compare => self.put(out.expect("Compare out"),
+                          Left(if inputs[0].unwrap_left() >
+                                  inputs[1].unwrap_left() {
+                            1
+                          } else {
+                            0
+                          })),
+      BranchIfTrue => {
+        if inputs[0].unwrap_left() != 0 {
+          self.ip = *self.blocks.find(&instr.succ[0].to_uint())
+                                .expect("branch true");
+        } else {
+          self.ip = *self.blocks.find(&instr.succ[1].to_uint())
+                                .expect("branch false");
+        }
+        return;
+      },
+      Switch => {
+        let target = instr.succ[inputs[0].unwrap_left()];
+        self.ip = *self.blocks.find(&target.to_uint()).expect("switch target");
+        return;
       }
     }
 
@@ -1,13 +1,84 @@
 extern mod extra;
 
-use extra::json::ToJson;
-use std::iterator;
+use extra::json::{ToJson, Json, Object, List, String, Null};
+use std::{iterator, uint, vec};
 use linearscan::*;
 use emulator::*;
+use testutil::*;
 
 #[path="../src/linearscan.rs"]
 mod linearscan;
 mod emulator;
+mod testutil;
+
+struct Realword {
+  root: BlockId,
+  cond: BlockId,
+  left: BlockId,
+  after_left: BlockId,
+  right: BlockId,
+  phi: InstrId,
+  counter: InstrId,
+  exit: InstrId
+}
+
+fn realword_graph(g: &mut Graph<Kind, Group, Register>) -> Realword {
+  let phi = g.phi(Normal);
+
+  let cond = g.empty_block();
+  let left = g.empty_block();
+  let after_left = g.empty_block();
+  let right = g.empty_block();
+  let ret = g.new_instr(Number(10), ~[]);
+  let counter = g.new_instr(Increment, ~[phi]);
+  let sum = g.new_instr(Sum, ~[ret, phi]);
+  let exit = g.new_instr(Return, ~[sum]);
+
+  let root = do g.block() |b| {
+    b.make_root();
+
+    b.add_existing(ret);
+    let zero = b.add(Number(0), ~[]);
+    b.to_phi(zero, phi);
+    b.goto(cond);
+  };
+
+  do g.with_block(cond) |b| {
+    let ten = b.add(Number(10), ~[]);
+    b.add(JustUse, ~[phi]);
+    b.add(BranchIfBigger, ~[phi, ten]);
+    b.branch(right, left);
+  };
+
+  do g.with_block(left) |b| {
+    let print_res = b.add(Print, ~[phi]);
+    b.add(Increment, ~[print_res]);
+    b.goto(after_left);
+  };
+
+  do g.with_block(after_left) |b| {
+    b.add_existing(counter);
+    b.to_phi(counter, phi);
+    b.goto(cond);
+  };
+
+  do g.with_block(right) |b| {
+    b.add_existing(sum);
+    b.add_existing(exit);
+    b.end();
+  };
+
+  Realword {
+    root: root,
+    cond: cond,
+    left: left,
+    after_left: after_left,
+    right: right,
+    phi: phi,
+    counter: counter,
+    exit: exit
+  }
+}
 
 #[test]
 fn realword_example() {
@@ -220,3 +291,4097 @@ fn parallel_move_cycles() {
     };
   };
 }
+
+#[test]
+fn interval_interference() {
+  let mut outputs = ~[];
+  let g = do run_test(Left(9)) |g| {
+    let x = g.new_instr(Number(5), ~[]);
+    let y = g.new_instr(Increment, ~[x]);
+    let a = g.new_instr(Number(1), ~[]);
+    let c = g.new_instr(Number(2), ~[]);
+
+    do g.block() |b| {
+      b.make_root();
+
+      b.add_existing(x);
+      b.add_existing(y);
+      b.add_existing(a);
+
+      // `a` is split around the call
+      b.add(Print, ~[a]);
+      b.add_existing(c);
+      let s = b.add(Sum, ~[a, c]);
+      let t = b.add(Sum, ~[s, y]);
+      b.add(Return, ~[t]);
+      b.end();
+    };
+
+    outputs = ~[g.get_output(&x), g.get_output(&a), g.get_output(&c)];
+  };
+
+  let (x, a, c) = (outputs[0], outputs[1], outputs[2]);
+
+  // Overlapping (`c` intersects only with `a`'s children)
+  assert!(g.interferes(a, c));
+  assert!(g.interferes(c, a));
+
+  // Disjoint
+  assert!(!g.interferes(x, a));
+  assert!(!g.interferes(x, c));
+}
+
+#[test]
+fn spill_all_at_safepoint() {
+  let g = do run_test(Left(5)) |g| {
+    let one = g.new_instr(Number(1), ~[]);
+    let safepoint = g.new_instr(Increment, ~[one]);
+    g.spill_all_at(safepoint);
+
+    do g.block() |b| {
+      b.make_root();
+
+      b.add_existing(one);
+      let two = b.add(Number(2), ~[]);
+      b.add_existing(safepoint);
+      let sum = b.add(Sum, ~[one, two]);
+      let res = b.add(Sum, ~[sum, safepoint]);
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  let mut pos = None;
+  for (id, instr) in g.instructions.iter() {
+    if instr.spill_all {
+      pos = Some(InstrId(*id));
+    }
+  }
+  let pos = pos.expect("Spill-all instruction");
+
+  // No virtual register value should be live across spill-all point
+  for (_, interval) in g.intervals.iter() {
+    match interval.value {
+      RegisterVal(_) if !interval.fixed => assert!(!interval.covers(pos)),
+      _ => ()
+    }
+  }
+}
+
+#[test]
+fn no_spill_after_compare() {
+  let mut flag = None;
+  let g = do run_test(Left(15)) |g| {
+    let left = g.empty_block();
+    let right = g.empty_block();
+    let n1 = g.new_instr(Number(1), ~[]);
+    let n2 = g.new_instr(Number(2), ~[]);
+    let n3 = g.new_instr(Number(3), ~[]);
+    let n4 = g.new_instr(Number(4), ~[]);
+    let n5 = g.new_instr(Number(5), ~[]);
+    let cmp = g.new_instr(Compare, ~[n1, n2]);
+
+    do g.block() |b| {
+      b.make_root();
+
+      b.add_existing(n1);
+      b.add_existing(n2);
+      b.add_existing(n3);
+      b.add_existing(n4);
+      b.add_existing(n5);
+      b.add_existing(cmp);
+      b.add(BranchIfTrue, ~[cmp]);
+      b.branch(left, right);
+    };
+
+    do g.with_block(left) |b| {
+      b.add(Return, ~[n1]);
+      b.end();
+    };
+
+    do g.with_block(right) |b| {
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      sum = b.add(Sum, ~[sum, n4]);
+      sum = b.add(Sum, ~[sum, n5]);
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+
+    flag = Some(g.get_output(&cmp));
+  };
+
+  let flag = flag.unwrap();
+  for (id, instr) in g.instructions.iter() {
+    if instr.output == Some(flag) {
+      // Gap between compare and branch should be empty
+      match g.gaps.find(&(*id + 1)) {
+        Some(gap) => assert!(gap.actions.len() == 0),
+        None => ()
+      }
+    }
+  }
+}
+
+#[test]
+fn no_reload_after_compare() {
+  let mut flag = None;
+  let g = do run_test(Left(7)) |g| {
+    let left = g.empty_block();
+    let right = g.empty_block();
+    let x = g.new_instr(Number(7), ~[]);
+
+    do g.block() |b| {
+      b.make_root();
+
+      // `x` is spilled by the call and needs a register right after compare
+      b.add_existing(x);
+      b.add(Print, ~[x]);
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let cmp = b.add(Compare, ~[n1, n2]);
+      b.add(JustUse, ~[x]);
+      b.add(BranchIfTrue, ~[cmp]);
+      b.branch(left, right);
+      flag = Some(cmp);
+    };
+
+    do g.with_block(left) |b| {
+      b.add(Return, ~[x]);
+      b.end();
+    };
+
+    do g.with_block(right) |b| {
+      b.add(Return, ~[x]);
+      b.end();
+    };
+  };
+
+  // Reload is placed before compare
+  let cmp = g.flat_instr(&flag.unwrap());
+  match g.gaps.find(&cmp.next().to_uint()) {
+    Some(gap) => assert!(gap.actions.len() == 0),
+    None => ()
+  }
+}
+
+#[test]
+fn rewrite_table() {
+  let mut counter = None;
+  let g = do run_test(Left(21)) |g| {
+    let r = realword_graph(g);
+    counter = Some(g.get_output(&r.counter));
+  };
+  let counter = counter.unwrap();
+
+  let mut found = false;
+  for a in g.rewrite_table().iter() {
+    let instr = g.get_instr(&a.id);
+
+    // Every operand is reported as allocated at instruction
+    assert!(a.inputs.len() == instr.inputs.len());
+    for (i, input) in a.inputs.iter().enumerate() {
+      let expected = g.get_value(&g.get_input(&a.id, i), a.id);
+      assert!(Some(input.clone()) == expected);
+    }
+    assert!(a.temps.len() == instr.temporary.len());
+    for (tmp, value) in instr.temporary.iter().zip(a.temps.iter()) {
+      assert!(Some(value.clone()) == g.get_value(tmp, a.id));
+    }
+    match instr.output {
+      Some(ref out) => {
+        // Call's output is defined after the call
+        let group = instr.kind.result_kind().unwrap().group();
+        let pos = if instr.clobbers(&group) { a.id.next() } else { a.id };
+        assert!(a.output == g.get_value(out, pos));
+      },
+      None => assert!(a.output.is_none())
+    }
+
+    if instr.output != Some(counter) {
+      loop;
+    }
+    found = true;
+
+    // Phi is spilled across the call, so Increment reads it from stack
+    // slot and puts its output in register
+    let input = g.get_value(&g.get_input(&a.id, 0), a.id);
+    let output = g.get_value(&counter, a.id);
+    match (a.inputs[0].clone(), a.output.clone()) {
+      (StackVal(group, slot), Some(RegisterVal(o))) => {
+        assert!(Some(StackVal(group, slot)) == input);
+        assert!(Some(RegisterVal(o)) == output);
+      },
+      _ => fail!("Expected stack input and register output")
+    }
+    assert!(a.temps.len() == 0);
+  }
+  assert!(found);
+}
+
+#[test]
+fn sorted_unhandled() {
+  do run_test(Left(78)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Every call splits all live values
+      let mut values = ~[];
+      for i in iterator::range(0u, 12) {
+        values.push(b.add(Number(i + 1), ~[]));
+        b.add(Print, ~[values[i]]);
+      }
+
+      let mut sum = b.add(Number(0), ~[]);
+      for v in values.iter() {
+        sum = b.add(Sum, ~[sum, *v]);
+      }
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn register_at() {
+  let mut r = None;
+  let g = do run_test(Left(21)) |g| {
+    r = Some(realword_graph(g));
+  };
+  let r = r.unwrap();
+
+  // Phi's location is the one at the start of its block
+  let phi = g.register_at(r.phi).expect("Phi output");
+  let start = g.get_block(&g.flat_block(&r.cond).unwrap()).start();
+  let child = g.child_at(&g.get_output(&g.flat_instr(&r.phi)), start).unwrap();
+  assert!(!phi.is_virtual());
+  assert!(phi == g.get_interval(&child).value);
+  match g.register_at(r.counter) {
+    Some(RegisterVal(_)) => (),
+    _ => fail!("Expected register")
+  }
+  assert!(g.register_at(r.exit).is_none());
+}
+
+#[test]
+fn flush_moves() {
+  let mut g = ~Graph::new();
+  realword_graph(g);
+  g.allocate(Config::new()).get();
+  g.flush_moves();
+
+  // All moves are now instructions
+  assert!(g.gaps.len() == 0);
+
+  // And no value was clobbered by them
+  check_result(g, Left(21));
+}
+
+#[test]
+fn dot_export() {
+  let mut g = ~Graph::new();
+  let r = realword_graph(g);
+  assert!(g.to_dot().contains("unprepared"));
+
+  g.allocate(Config::new()).get();
+  let dot = g.to_dot();
+  assert!(!dot.contains("unprepared"));
+
+  // Collect all nodes and edges
+  let mut nodes = ~[];
+  let mut edges = ~[];
+  for line in dot.line_iter() {
+    let words: ~[&str] = line.word_iter().collect();
+    if words.len() >= 3 && words[1] == "->" {
+      edges.push((words[0].to_owned(), words[2].to_owned()));
+    } else if words.len() >= 2 && words[1].starts_with("[label") {
+      nodes.push(words[0].to_owned());
+    }
+  }
+
+  let blocks = ~[r.root, r.cond, r.left, r.after_left, r.right];
+  assert!(nodes.len() == blocks.len());
+  for block in blocks.iter() {
+    let name = fmt!("b%u", g.flat_block(block).unwrap().to_uint());
+    assert!(nodes.contains(&name));
+  }
+
+  let expected = ~[(r.root, r.cond),
+                   (r.cond, r.right),
+                   (r.cond, r.left),
+                   (r.left, r.after_left),
+                   (r.after_left, r.cond)];
+  assert!(edges.len() == expected.len());
+  for &(from, to) in expected.iter() {
+    let edge = (fmt!("b%u", g.flat_block(&from).unwrap().to_uint()),
+                fmt!("b%u", g.flat_block(&to).unwrap().to_uint()));
+    assert!(edges.contains(&edge));
+  }
+}
+
+#[test]
+fn per_group_register_count() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+
+  let (_, res) = do run_test_with(config, Right(9f)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Three live values in each group
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let d1 = b.add(DoubleNumber(0.5f), ~[]);
+      let d2 = b.add(DoubleNumber(1f), ~[]);
+      let d3 = b.add(DoubleNumber(1.5f), ~[]);
+
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      let mut dsum = b.add(DoubleSum, ~[d1, d2]);
+      dsum = b.add(DoubleSum, ~[dsum, d3]);
+
+      let total = b.add(ToDouble, ~[sum]);
+      let total = b.add(DoubleSum, ~[dsum, total]);
+      b.add(ReturnDouble, ~[total]);
+      b.end();
+    };
+  };
+
+  // Only normal group is over-subscribed
+  assert!(res.spill_count[Normal.to_uint()] > 0);
+  assert!(res.spill_count[Double.to_uint()] == 0);
+}
+
+#[test]
+fn malformed_graphs() {
+  // No root
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    let x = b.add(Number(1), ~[]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  assert!(g.allocate(Config::new()).is_err());
+
+  // Block without terminator
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    b.add(Nop, ~[]);
+  };
+  assert!(g.allocate(Config::new()).is_err());
+
+  // Input without output
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    let nop = b.add(Nop, ~[]);
+    let x = b.add(Increment, ~[nop]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  assert!(g.allocate(Config::new()).is_err());
+
+  // Input that wasn't added to any block
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let orphan = g.new_instr(Number(1), ~[]);
+  do g.block() |b| {
+    b.make_root();
+    let x = b.add(Increment, ~[orphan]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  assert!(g.allocate(Config::new()).is_err());
+
+  // Phi without inputs
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let phi = g.phi(Normal);
+  do g.block() |b| {
+    b.make_root();
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+  assert!(g.allocate(Config::new()).is_err());
+
+  // Phi with fewer inputs than predecessors of its block
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let phi = g.phi(Normal);
+  let left = g.empty_block();
+  let right = g.empty_block();
+  let join = g.empty_block();
+  do g.block() |b| {
+    b.make_root();
+    let cond = b.add(Number(0), ~[]);
+    b.add(BranchIfTrue, ~[cond]);
+    b.branch(left, right);
+  };
+  do g.with_block(left) |b| {
+    let n = b.add(Number(1), ~[]);
+    b.to_phi(n, phi);
+    b.goto(join);
+  };
+  do g.with_block(right) |b| {
+    b.goto(join);
+  };
+  do g.with_block(join) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+  let err = g.allocate(Config::new()).get_err();
+  assert!(err.contains("no input from block"));
+}
+
+#[test]
+fn tied_input() {
+  // Tied input dies at instruction, output should reuse its register
+  let mut add = None;
+  let g = do run_test(Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let sum = b.add(Add, ~[n1, n2]);
+      add = Some(sum);
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+  let a = g.get_assignment(&g.flat_instr(&add.unwrap()));
+  assert!(a.output == Some(a.inputs[0]));
+
+  // Tied input is used later, allocator should copy it
+  let mut add = None;
+  let g = do run_test(Left(4)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let sum = b.add(Add, ~[n1, n2]);
+      add = Some(sum);
+      let total = b.add(Sum, ~[sum, n1]);
+      b.add(Return, ~[total]);
+      b.end();
+    };
+  };
+  let add = g.flat_instr(&add.unwrap());
+  let a = g.get_assignment(&add);
+  assert!(a.output == Some(a.inputs[0]));
+  assert!(a.output != Some(a.inputs[1]));
+
+  // Copy is placed in the gap right before instruction
+  let out = g.get_output(&add);
+  let gap = g.gaps.find(&add.prev().to_uint()).expect("Tied input copy");
+  assert!(gap.actions.iter().any(|action| action.to == out));
+}
+
+#[test]
+fn rematerialization() {
+  let (g, res) = do run_test_with(Config::new(), Left(23)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Constants are live across calls
+      let c1 = b.add(Constant(10), ~[]);
+      let c2 = b.add(Constant(13), ~[]);
+      b.add(Print, ~[c1]);
+      b.add(Print, ~[c2]);
+      let sum = b.add(Sum, ~[c1, c2]);
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+
+  // Constants should be recomputed instead of being spilled
+  assert!(res.spill_count[Normal.to_uint()] == 0);
+  let mut remat = ~[];
+  for (_, interval) in g.intervals.iter() {
+    if interval.value.is_remat() {
+      // Even uses accepting any location are getting a reloaded value
+      assert!(interval.uses.len() == 0);
+      if !remat.contains(&interval.value) {
+        remat.push(interval.value.clone());
+      }
+    }
+  }
+
+  // Values recomputed by different instructions are different
+  assert!(remat.len() == 2);
+}
+
+#[test]
+fn coalescing() {
+  // Loop example stays correct after coalescing and has less moves
+  let mut moves = ~[];
+  let mut coalesced = ~[];
+  for &enabled in [false, true].iter() {
+    let mut config = Config::new();
+    config.coalesce = enabled;
+    let (g, res) = do run_test_with(config, Left(21)) |g| {
+      realword_graph(g);
+    };
+    moves.push(count_moves(g));
+    coalesced.push(res.coalesced_moves);
+  }
+  assert!(coalesced[0] == 0);
+  assert!(moves[1] <= moves[0]);
+
+  // Value is moved out of fixed register after its use
+  let mut config = Config::new();
+  config.coalesce = true;
+  let (_, res) = do run_test_with(config, Left(2)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(1), ~[]);
+      b.add(JustUse, ~[n]);
+      let inc = b.add(Increment, ~[n]);
+      b.add(Return, ~[inc]);
+      b.end();
+    };
+  };
+  assert!(res.coalesced_moves > 0);
+}
+
+#[test]
+fn register_aliases() {
+  let g = do run_test(Left(10)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Four live values, but only two non-aliasing registers
+      let n1 = b.add(PairNumber(1), ~[]);
+      let n2 = b.add(PairNumber(2), ~[]);
+      let n3 = b.add(PairNumber(3), ~[]);
+      let n4 = b.add(PairNumber(4), ~[]);
+      let mut sum = b.add(PairSum, ~[n1, n2]);
+      sum = b.add(PairSum, ~[sum, n3]);
+      sum = b.add(PairSum, ~[sum, n4]);
+      let res = b.add(FromPair, ~[sum]);
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  for (_, a) in g.intervals.iter() {
+    for (_, b) in g.intervals.iter() {
+      match (&a.value, &b.value) {
+        (&RegisterVal(ref ra), &RegisterVal(ref rb))
+            if ra.aliases().contains(rb) => {
+          assert!(g.get_intersection(&a.id, &b.id).is_none());
+        },
+        _ => ()
+      }
+    }
+  }
+}
+
+#[test]
+fn callee_saved() {
+  fn body(g: &mut Graph<Kind, Group, Register>) -> (InstrId, InstrId) {
+    let mut res = None;
+    do g.block() |b| {
+      b.make_root();
+      let d1 = b.add(DoubleNumber(1.5f), ~[]);
+      let n = b.add(Number(0), ~[]);
+      b.add(Print, ~[n]);
+      let d2 = b.add(DoubleNumber(2f), ~[]);
+      let sum = b.add(DoubleSum, ~[d1, d2]);
+      b.add(ReturnDouble, ~[sum]);
+      b.end();
+      res = Some((d1, d2));
+    };
+    return res.unwrap();
+  }
+
+  // Values are spilled around calls by default
+  let (_, res) = do run_test_with(Config::new(), Right(3.5f)) |g| {
+    body(g);
+  };
+  assert!(res.callee_saved[Double.to_uint()].len() == 0);
+
+  let mut config = Config::new();
+  config.preserve_callee_saved = true;
+  let mut ids = None;
+  let (g, res) = do run_test_with(config, Right(3.5f)) |g| {
+    ids = Some(body(g));
+  };
+  let (long, short) = ids.unwrap();
+
+  // Value that lives across the call is kept in callee-saved register
+  match g.register_at(long) {
+    Some(RegisterVal(r)) => assert!(r.is_callee_saved()),
+    _ => fail!("Expected register")
+  }
+  match g.register_at(short) {
+    Some(RegisterVal(r)) => assert!(!r.is_callee_saved()),
+    _ => fail!("Expected register")
+  }
+  assert!(res.callee_saved[Double.to_uint()] == ~[xmm4.to_uint()]);
+  assert!(res.callee_saved[Normal.to_uint()].len() == 0);
+}
+
+#[test]
+fn spill_slot_size() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.register_count.insert(Double.to_uint(), 2);
+
+  let (g, res) = do run_test_with(config, Right(12f)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Three live values in each group
+      let n1 = b.add(Number(1), ~[]);
+      let d1 = b.add(DoubleNumber(0.5f), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let d2 = b.add(DoubleNumber(1f), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let d3 = b.add(DoubleNumber(4.5f), ~[]);
+
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      let mut dsum = b.add(DoubleSum, ~[d1, d2]);
+      dsum = b.add(DoubleSum, ~[dsum, d3]);
+
+      let total = b.add(ToDouble, ~[sum]);
+      let total = b.add(DoubleSum, ~[dsum, total]);
+      b.add(ReturnDouble, ~[total]);
+      b.end();
+    };
+  };
+
+  // Spill areas are measured in bytes, and slots are counted separately
+  assert!(res.spill_count[Normal.to_uint()] > 0);
+  assert!(res.spill_bytes[Normal.to_uint()] ==
+          res.spill_count[Normal.to_uint()] * Normal.spill_size());
+  assert!(res.spill_count[Double.to_uint()] > 0);
+  assert!(res.spill_bytes[Double.to_uint()] ==
+          res.spill_count[Double.to_uint()] * Double.spill_size());
+
+  let mut slots = ~[];
+  for (_, interval) in g.intervals.iter() {
+    match interval.value {
+      StackVal(ref group, slot) => {
+        // Slots are aligned
+        assert!(slot.to_uint() % group.spill_size() == 0);
+        slots.push((slot.to_uint(), group.spill_size()));
+      },
+      _ => ()
+    }
+  }
+
+  // Distinct slots do not overlap
+  for &(a, a_size) in slots.iter() {
+    for &(b, b_size) in slots.iter() {
+      if a != b {
+        assert!(a + a_size <= b || b + b_size <= a);
+      }
+    }
+  }
+}
+
+#[test]
+fn fixed_output() {
+  let mut arg = None;
+  let g = do run_test(Left(6)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Argument is passed in `rcx`
+      let n = b.add_fixed(Number(5), ~[], rcx);
+      arg = Some(n);
+      let inc = b.add(Increment, ~[n]);
+      b.add(Return, ~[inc]);
+      b.end();
+    };
+  };
+  match g.register_at(arg.unwrap()) {
+    Some(RegisterVal(r)) => assert!(r == rcx),
+    _ => fail!("Expected register")
+  }
+}
+
+#[test]
+fn liveness_query() {
+  let mut r = None;
+  let mut phi = None;
+  let g = do run_test(Left(21)) |g| {
+    let res = realword_graph(g);
+    phi = Some(g.get_output(&res.phi));
+    r = Some(res);
+  };
+  let r = r.unwrap();
+  let phi = phi.unwrap();
+
+  // Phi is live during the whole loop
+  assert!(g.live_in(r.cond).contains(&phi));
+  assert!(g.live_out(r.left).contains(&phi));
+  assert!(!g.live_in(r.root).contains(&phi));
+}
+
+fn switch_graph(g: &mut Graph<Kind, Group, Register>, selector: uint) {
+  let phi = g.phi(Normal);
+  let left = g.empty_block();
+  let right = g.empty_block();
+  let exit = g.empty_block();
+
+  do g.block() |b| {
+    b.make_root();
+    let sel = b.add(Number(selector), ~[]);
+    let one = b.add(Number(1), ~[]);
+    b.to_phi(one, phi);
+    b.add(Switch, ~[sel]);
+    b.switch(~[left, right, exit]);
+  };
+
+  do g.with_block(left) |b| {
+    let ten = b.add(Number(10), ~[]);
+    b.to_phi(ten, phi);
+    b.goto(exit);
+  };
+
+  do g.with_block(right) |b| {
+    let print = b.add(Print, ~[phi]);
+    let two = b.add(Increment, ~[print]);
+    let three = b.add(Increment, ~[two]);
+    b.to_phi(three, phi);
+    b.goto(exit);
+  };
+
+  do g.with_block(exit) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+}
+
+#[test]
+fn switch() {
+  run_test(Left(10), |g| switch_graph(g, 0));
+  run_test(Left(2), |g| switch_graph(g, 1));
+  let g = run_test(Left(1), |g| switch_graph(g, 2));
+
+  // Edge from switch to the exit block was split
+  let root = g.get_block(&g.root.unwrap());
+  assert!(root.successors.len() == 3);
+  for succ in root.successors.iter() {
+    assert!(g.get_block(succ).predecessors.len() == 1);
+  }
+}
+
+fn critical_edge_graph(g: &mut Graph<Kind, Group, Register>, c: uint) {
+  let phi = g.phi(Normal);
+  let left = g.empty_block();
+  let exit = g.empty_block();
+
+  do g.block() |b| {
+    b.make_root();
+    let cond = b.add(Number(c), ~[]);
+    let one = b.add(Number(1), ~[]);
+    b.to_phi(one, phi);
+    b.add(BranchIfTrue, ~[cond]);
+    b.branch(left, exit);
+  };
+
+  do g.with_block(left) |b| {
+    let ten = b.add(Number(10), ~[]);
+    b.to_phi(ten, phi);
+    b.goto(exit);
+  };
+
+  do g.with_block(exit) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+}
+
+#[test]
+fn critical_edges() {
+  // Move on `root -> exit` edge should not run on `left -> exit` path
+  run_test(Left(10), |g| critical_edge_graph(g, 1));
+  let g = run_test(Left(1), |g| critical_edge_graph(g, 0));
+
+  let root = g.get_block(&g.root.unwrap());
+  for succ in root.successors.iter() {
+    assert!(g.get_block(succ).predecessors.len() == 1);
+  }
+
+  // Inserted block is not reported to user
+  assert!(g.block_order().len() == 3);
+  assert!(g.loop_info().len() == 3);
+}
+
+#[test]
+fn second_chance() {
+  fn count_slots(g: &Graph<Kind, Group, Register>) -> uint {
+    let mut slots = ~[];
+    for (_, interval) in g.intervals.iter() {
+      match interval.value {
+        StackVal(_, slot) if !slots.contains(&slot.to_uint()) => {
+          slots.push(slot.to_uint());
+        },
+        _ => ()
+      }
+    }
+    return slots.len();
+  }
+
+  fn body(g: &mut Graph<Kind, Group, Register>) {
+    do g.block() |b| {
+      b.make_root();
+
+      // Three live values with only two registers available, `a` is spilled
+      let a = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let mut s = b.add(Sum, ~[n2, n3]);
+      s = b.add(Sum, ~[s, a]);
+
+      // And again after `a` has ended, `s` is spilled
+      let n4 = b.add(Number(4), ~[]);
+      let n5 = b.add(Number(5), ~[]);
+      let mut t = b.add(Sum, ~[n4, n5]);
+      t = b.add(Sum, ~[t, s]);
+      b.add(Return, ~[t]);
+      b.end();
+    };
+  }
+
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.reuse_spill_slots = true;
+  let (plain, _) = run_test_with(config, Left(15), body);
+
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.reuse_spill_slots = true;
+  config.second_chance = true;
+  let (g, _) = run_test_with(config, Left(15), body);
+
+  // Slot of `a` is released when it ends, and reused for `s`
+  assert!(count_slots(g) < count_slots(plain));
+}
+
+#[test]
+fn loop_depth_spill_weight() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 3);
+  config.use_weight = loop_depth_weight;
+
+  let mut step = None;
+  let (g, _) = do run_test_with(config, Left(13)) |g| {
+    let i = g.phi(Normal);
+    let header = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+    let mut x = None;
+    let mut outside = None;
+
+    do g.block() |b| {
+      b.make_root();
+
+      // `x` is used only in the loop, `y` only before it
+      x = Some(b.add(Number(2), ~[]));
+      let y = b.add(Number(7), ~[]);
+      let p = b.add(Number(1), ~[]);
+      let q = b.add(Number(1), ~[]);
+      let s = b.add(Sum, ~[y, p]);
+      outside = Some(b.add(Sum, ~[s, q]));
+      let zero = b.add(Number(0), ~[]);
+      b.to_phi(zero, i);
+      b.goto(header);
+    };
+
+    do g.with_block(header) |b| {
+      let three = b.add(Number(3), ~[]);
+      let cmp = b.add(Compare, ~[i, three]);
+      b.add(BranchIfTrue, ~[cmp]);
+      b.branch(exit, body);
+    };
+
+    do g.with_block(body) |b| {
+      let next = b.add(Sum, ~[i, x.unwrap()]);
+      b.to_phi(next, i);
+      b.goto(header);
+    };
+
+    do g.with_block(exit) |b| {
+      let res = b.add(Sum, ~[i, outside.unwrap()]);
+      b.add(Return, ~[res]);
+      b.end();
+    };
+
+    step = Some(g.get_output(&x.unwrap()));
+  };
+
+  // Value used in the loop is never spilled
+  let step = step.unwrap();
+  let mut intervals = ~[step];
+  intervals.push_all(g.get_interval(&step).children);
+  for id in intervals.iter() {
+    match g.get_interval(id).value {
+      StackVal(_, _) => fail!("Loop value is spilled"),
+      _ => ()
+    }
+  }
+
+  // Near uses in deep loops are not mistaken for blocked registers
+  assert!(loop_depth_weight(5, 1) == 1);
+  assert!(loop_depth_weight(5, 3) == 1);
+  assert!(loop_depth_weight(50, 1) == 5);
+}
+
+#[test]
+fn reverse_postorder() {
+  let mut config = Config::new();
+  config.ordering = ReversePostorder;
+
+  let mut blocks = ~[];
+  let (g, _) = do run_test_with(config, Left(1)) |g| {
+    let left = g.empty_block();
+    let right = g.empty_block();
+    let exit = g.empty_block();
+    let mut one = None;
+
+    let root = do g.block() |b| {
+      b.make_root();
+      one = Some(b.add(Number(1), ~[]));
+      let cond = b.add(Number(0), ~[]);
+      b.add(BranchIfTrue, ~[cond]);
+      b.branch(left, right);
+    };
+
+    do g.with_block(left) |b| {
+      b.add(Nop, ~[]);
+      b.goto(exit);
+    };
+
+    do g.with_block(right) |b| {
+      b.add(Nop, ~[]);
+      b.goto(exit);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(Return, ~[one.unwrap()]);
+      b.end();
+    };
+
+    blocks = ~[root, right, left, exit];
+  };
+
+  assert!(g.block_order() == blocks);
+}
+
+fn cycles_graph(g: &mut Graph<Kind, Group, Register>) {
+  do g.block() |b| {
+    b.make_root();
+
+    let n1 = b.add(Number(1), ~[]);
+    let n2 = b.add(Number(2), ~[]);
+    let n3 = b.add(Number(3), ~[]);
+
+    // 3-cycle
+    b.add(FixedUse, ~[n1, n2, n3]);
+    b.add(FixedUse, ~[n3, n1, n2]);
+
+    let ten = b.add(Number(10), ~[]);
+    let mut res = b.add(Number(0), ~[]);
+    res = b.add(MultAdd, ~[res, ten, n1]);
+    res = b.add(MultAdd, ~[res, ten, n2]);
+    res = b.add(MultAdd, ~[res, ten, n3]);
+
+    b.add(Return, ~[res]);
+    b.end();
+  };
+}
+
+#[test]
+fn swapless_cycles() {
+  // Cycles are broken through scratch register
+  let mut config = Config::new();
+  config.swap = false;
+  config.scratch.insert(Normal.to_uint(), rdx.to_uint());
+  let (g, _) = do run_test_with(config, Left(123)) |g| {
+    cycles_graph(g);
+  };
+  assert!(count_swaps(g) == 0);
+
+  // Or through spill slot, several cycles in one gap
+  let mut config = Config::new();
+  config.swap = false;
+  let (g, _) = do run_test_with(config, Left(1234)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let n4 = b.add(Number(4), ~[]);
+
+      // 1 <=> 2, 3 <=> 4
+      b.add(FixedUse, ~[n1, n2, n3, n4]);
+      b.add(FixedUse, ~[n2, n1, n4, n3]);
+
+      // shift
+      b.add(FixedUse, ~[n4, n1, n2, n3]);
+
+      let ten = b.add(Number(10), ~[]);
+      let mut res = b.add(Number(0), ~[]);
+      res = b.add(MultAdd, ~[res, ten, n1]);
+      res = b.add(MultAdd, ~[res, ten, n2]);
+      res = b.add(MultAdd, ~[res, ten, n3]);
+      res = b.add(MultAdd, ~[res, ten, n4]);
+
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+  assert!(count_swaps(g) == 0);
+}
+
+#[test]
+fn memory_moves() {
+  let mut counts = ~[];
+  for &memory_moves in [true, false].iter() {
+    // Values are living in stack slots, so phi moves are between slots
+    let mut config = Config::new();
+    config.memory_moves = memory_moves;
+    config.spill_everywhere = true;
+    config.scratch.insert(Normal.to_uint(), rbx.to_uint());
+    config.scratch.insert(Double.to_uint(), xmm4.to_uint());
+    config.register_count.insert(Double.to_uint(), 2);
+
+    let (g, _) = do run_test_with(config, Right(4.5f)) |g| {
+      memory_moves_graph(g);
+    };
+    counts.push(count_memory_moves(g));
+  }
+
+  // Stack slots are moved directly, unless target can't do it
+  assert!(counts[0] > 0);
+  assert!(counts[1] == 0);
+}
+
+fn memory_moves_graph(g: &mut Graph<Kind, Group, Register>) {
+  let counter = g.phi(Normal);
+  let sum = g.phi(Double);
+
+  let cond = g.empty_block();
+  let body = g.empty_block();
+  let exit = g.empty_block();
+
+  // Both phis are live across calls in loop body
+  do g.block() |b| {
+    b.make_root();
+    let zero = b.add(Number(0), ~[]);
+    let half = b.add(DoubleNumber(0.5f), ~[]);
+    b.to_phi(zero, counter);
+    b.to_phi(half, sum);
+    b.goto(cond);
+  };
+
+  do g.with_block(cond) |b| {
+    let three = b.add(Number(3), ~[]);
+    b.add(BranchIfBigger, ~[counter, three]);
+    b.branch(exit, body);
+  };
+
+  do g.with_block(body) |b| {
+    let one = b.add(DoubleNumber(1f), ~[]);
+    b.add(Print, ~[counter]);
+    let next_sum = b.add(DoubleSum, ~[sum, one]);
+    b.add(Print, ~[counter]);
+    let next = b.add(Increment, ~[counter]);
+    b.to_phi(next, counter);
+    b.to_phi(next_sum, sum);
+    b.goto(cond);
+  };
+
+  do g.with_block(exit) |b| {
+    b.add(ReturnDouble, ~[sum]);
+    b.end();
+  };
+}
+
+#[test]
+fn register_pairs() {
+  let mut wide = None;
+  let (g, res) = do run_test_with(Config::new(), Left(42013)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Paired value and three other live values don't fit in registers
+      let w = b.add(WideNumber(42007), ~[]);
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      let n = b.add(FromWide, ~[w]);
+      sum = b.add(Sum, ~[sum, n]);
+      b.add(Return, ~[sum]);
+      b.end();
+
+      wide = Some(w);
+    };
+  };
+
+  // Adjacent registers or double-width slot
+  match g.register_at(wide.unwrap()).unwrap() {
+    RegisterVal(r) => assert!(r.to_uint() % 2 == 0),
+    StackVal(_, _) => assert!(res.spill_count[Normal.to_uint()] >= 2),
+    _ => fail!("Unexpected value")
+  }
+}
+
+#[test]
+fn partial_clobber() {
+  let mut n1 = None;
+  let mut n2 = None;
+  let (g, res) = do run_test_with(Config::new(), Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let a = b.add(Number(1), ~[]);
+      let c = b.add(Number(2), ~[]);
+      b.add(PartialCall, ~[]);
+      let sum = b.add(Sum, ~[a, c]);
+      b.add(Return, ~[sum]);
+      b.end();
+
+      n1 = Some(a);
+      n2 = Some(c);
+    };
+  };
+
+  // Values live across the call in registers that it preserves
+  for id in [n1.unwrap(), n2.unwrap()].iter() {
+    match g.register_at(*id) {
+      Some(RegisterVal(rcx)) | Some(RegisterVal(rdx)) => (),
+      _ => fail!("Expected preserved register")
+    }
+  }
+  assert!(res.spill_count[Normal.to_uint()] == 0);
+}
+
+fn frequency_graph(g: &mut Graph<Kind, Group, Register>,
+                   profile: bool) -> (InstrId, InstrId, InstrId) {
+  let cold = g.empty_block();
+  let hot = g.empty_block();
+
+  // `z` takes register of either `x` or `y`
+  let x = g.new_instr(Number(1), ~[]);
+  let y = g.new_instr(Number(2), ~[]);
+  let z = g.new_instr(Number(3), ~[]);
+  let zi = g.new_instr(Increment, ~[z]);
+  let xs = g.new_instr(Add, ~[x, zi]);
+  let r = g.new_instr(Add, ~[xs, y]);
+
+  do g.block() |b| {
+    b.make_root();
+    b.add_existing(x);
+    b.add_existing(y);
+    b.add_existing(z);
+    b.add_existing(zi);
+    b.goto(cold);
+  };
+
+  do g.with_block(cold) |b| {
+    if profile {
+      b.set_frequency(0.01f64);
+    }
+    b.add_existing(xs);
+    b.goto(hot);
+  };
+
+  do g.with_block(hot) |b| {
+    if profile {
+      b.set_frequency(100f64);
+    }
+    b.add_existing(r);
+    b.add(Return, ~[r]);
+    b.end();
+  };
+
+  return (x, y, z);
+}
+
+#[test]
+fn block_frequency() {
+  // Without profile value with the farthest use is spilled
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let mut ids = (InstrId(0), InstrId(0), InstrId(0));
+  let (g, _) = do run_test_with(config, Left(7)) |g| {
+    ids = frequency_graph(g, false);
+  };
+  let (_, y, z) = ids;
+  assert!(g.register_at(z) == g.register_at(y));
+
+  // Value used only in the cold block is spilled instead
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let (g, _) = do run_test_with(config, Left(7)) |g| {
+    ids = frequency_graph(g, true);
+  };
+  let (x, _, z) = ids;
+  assert!(g.register_at(z) == g.register_at(x));
+}
+
+#[test]
+fn intervals_iter() {
+  let (g, res) = do run_test_with(Config::new(), Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut prev = None;
+  let mut registers = 0;
+  let mut slots = ~[];
+  for (id, interval) in g.intervals_iter() {
+    // Ordered by id
+    match prev {
+      Some(p) => assert!(p < id.to_uint()),
+      None => ()
+    }
+    prev = Some(id.to_uint());
+
+    if interval.fixed || interval.ranges.len() == 0 {
+      loop;
+    }
+    match interval.value {
+      RegisterVal(_) => registers += 1,
+      StackVal(Normal, slot) => if !slots.contains(&slot) {
+        slots.push(slot);
+      },
+      _ => ()
+    }
+  }
+
+  assert!(registers > 0);
+  assert!(slots.len() == res.spill_count[Normal.to_uint()]);
+}
+
+fn dead_graph(g: &mut Graph<Kind, Group, Register>) -> InstrId {
+  let mut res = InstrId(0);
+  do g.block() |b| {
+    b.make_root();
+    let d = b.add(Number(5), ~[]);
+    let x = b.add(Number(1), ~[]);
+    let y = b.add(Number(2), ~[]);
+    let s = b.add(Sum, ~[x, y]);
+
+    // Unused, and its only use of `d`
+    b.add(Sum, ~[s, d]);
+    b.add(Return, ~[s]);
+    b.end();
+
+    res = d;
+  };
+  return res;
+}
+
+#[test]
+fn dead_elimination() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let (_, before) = do run_test_with(config, Left(3)) |g| {
+    dead_graph(g);
+  };
+
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.eliminate_dead = true;
+  let mut d = InstrId(0);
+  let (g, after) = do run_test_with(config, Left(3)) |g| {
+    d = dead_graph(g);
+  };
+
+  assert!(!g.instr_map.contains_key(&d.to_uint()));
+  assert!(after.spill_count[Normal.to_uint()] <
+          before.spill_count[Normal.to_uint()]);
+}
+
+#[test]
+fn validate() {
+  // Well-formed graph
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  realword_graph(&mut *g);
+  assert!(g.validate().is_ok());
+
+  // No root
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    let x = b.add(Number(1), ~[]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  assert!(g.validate().get_err().len() == 1);
+
+  // Block without terminator
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    b.add(Nop, ~[]);
+  };
+  assert!(g.validate().get_err().len() == 1);
+
+  // Successor without matching predecessor
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let exit = g.empty_block();
+  do g.block() |b| {
+    b.make_root();
+    b.add(Nop, ~[]);
+    b.goto(exit);
+  };
+  do g.with_block(exit) |b| {
+    let x = b.add(Number(1), ~[]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  g.get_mut_block(&exit).predecessors.clear();
+  assert!(g.validate().get_err().len() == 1);
+
+  // Unknown input
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    let x = b.add(Increment, ~[InstrId(100)]);
+    b.add(Return, ~[x]);
+    b.end();
+  };
+  assert!(g.validate().get_err().len() == 1);
+
+  // Exit block without return
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    b.add(Number(1), ~[]);
+    b.end();
+  };
+  assert!(g.validate().get_err().len() == 1);
+
+  // Phi with less inputs than predecessors
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  critical_edge_graph(&mut *g, 0);
+  let phi = g.phis[0];
+  g.get_mut_instr(&phi).inputs.shift();
+  assert!(g.validate().get_err().len() == 1);
+}
+
+#[test]
+fn phi_blocks() {
+  fn build(g: &mut Graph<Kind, Group, Register>, attach: bool) -> BlockId {
+    let phi = g.phi(Normal);
+    let left = g.empty_block();
+    let right = g.empty_block();
+    let other = g.empty_block();
+    let join = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let cond = b.add(Number(0), ~[]);
+      b.add(BranchIfTrue, ~[cond]);
+      b.branch(left, right);
+    };
+
+    // Both inputs are coming from blocks with two join successors
+    do g.with_block(left) |b| {
+      let one = b.add(Number(1), ~[]);
+      b.to_phi(one, phi);
+      let cond = b.add(Number(0), ~[]);
+      b.add(BranchIfTrue, ~[cond]);
+      b.branch(other, join);
+    };
+    do g.with_block(right) |b| {
+      let two = b.add(Number(2), ~[]);
+      b.to_phi(two, phi);
+      let cond = b.add(Number(0), ~[]);
+      b.add(BranchIfTrue, ~[cond]);
+      b.branch(other, join);
+    };
+
+    do g.with_block(other) |b| {
+      let five = b.add(Number(5), ~[]);
+      b.add(Return, ~[five]);
+      b.end();
+    };
+    do g.with_block(join) |b| {
+      b.add(Return, ~[phi]);
+      b.end();
+    };
+
+    if attach {
+      g.attach_phi(phi, join);
+    }
+    return join;
+  }
+
+  // Block can't be guessed from inputs
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  build(&mut *g, false);
+  let errors = g.validate().get_err();
+  assert!(errors.len() == 1);
+  assert!(errors[0].contains("not attached"));
+
+  let mut join = None;
+  let g = do run_test(Left(2)) |g| {
+    join = Some(build(g, true));
+  };
+  let phi = g.phis[0];
+  assert!(g.phi_block(&phi) == g.flat_block(&join.unwrap()));
+}
+
+#[test]
+fn unreachable_blocks() {
+  let mut config = Config::new();
+  config.skip_unreachable = true;
+
+  let mut lost = None;
+  let (g, _) = do run_test_with(config, Left(21)) |g| {
+    let r = realword_graph(g);
+
+    // Nothing jumps into this block
+    let block = g.empty_block();
+    do g.with_block(block) |b| {
+      let n = b.add(Number(5), ~[]);
+      b.to_phi(n, r.phi);
+      b.goto(r.cond);
+    };
+
+    assert!(g.unreachable_blocks() == ~[block]);
+    lost = Some(block);
+  };
+
+  // Block was excluded from allocation
+  assert!(g.flat_block(&lost.unwrap()).is_none());
+}
+
+#[test]
+fn commutative_inputs() {
+  let mut add = None;
+  let mut dying = None;
+  let (g, _) = do run_test_with(Config::new(), Left(4)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+
+      // `n1` lives after addition, and `n2` dies
+      let sum = b.add(CommutativeSum, ~[n1, n2]);
+      let res = b.add(Sum, ~[sum, n1]);
+      b.add(Return, ~[res]);
+      b.end();
+
+      add = Some(sum);
+      dying = Some(n2);
+    };
+  };
+
+  // Output is hinted to the dying input and reuses its register
+  let out = g.get_output(&g.flat_instr(&add.unwrap()));
+  let input = g.get_output(&g.flat_instr(&dying.unwrap()));
+  assert!(g.get_interval(&out).hint == Some(input));
+  assert!(g.swapped_inputs(add.unwrap()));
+  assert!(g.register_at(add.unwrap()) == g.register_at(dying.unwrap()));
+}
+
+#[test]
+fn cross_group_moves() {
+  let (g, _) = do run_test_with(Config::new(), Right(3f)) |g| {
+    let phi = g.phi(Double);
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+
+      // Integer flows into double phi
+      let n = b.add(Number(3), ~[]);
+      b.to_phi(n, phi);
+      b.goto(exit);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(ReturnDouble, ~[phi]);
+      b.end();
+    };
+  };
+  assert!(count_cross_moves(g) > 0);
+}
+
+#[test]
+fn allocation_statistics() {
+  let (g, res) = do run_test_with(Config::new(), Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut total = Statistics::new();
+  for stats in res.stats.iter() {
+    total.add(stats);
+  }
+  assert!(total == g.statistics());
+  assert!(total.splits > 0);
+  assert!(total.moves > 0);
+  assert!(total.swaps == 0);
+}
+
+fn ranges_graph(g: &mut Graph<Kind, Group, Register>,
+                count: uint) -> (IntervalId, IntervalId) {
+  let a = g.new_instr(Number(0), ~[]);
+  let a = g.get_instr(&a).output.unwrap();
+  let b = g.new_instr(Number(0), ~[]);
+  let b = g.get_instr(&b).output.unwrap();
+
+  // Ranges are added in reverse order, as in `build_ranges`
+  let mut i = count;
+  while i > 0 {
+    i -= 1;
+    g.get_mut_interval(&a).add_range(InstrId(i * 10), InstrId(i * 10 + 5));
+    g.get_mut_interval(&b).add_range(InstrId(i * 7 + 3), InstrId(i * 7 + 6));
+  }
+  return (a, b);
+}
+
+#[test]
+fn range_lookup() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let (a, b) = ranges_graph(&mut *g, 100);
+
+  // Compare against linear scan over all ranges
+  let mut pos = 0;
+  while pos < 1100 {
+    let linear = do g.get_interval(&a).ranges.iter().any() |r| {
+      r.covers(InstrId(pos))
+    };
+    assert!(g.get_interval(&a).covers(InstrId(pos)) == linear);
+    pos += 1;
+  }
+
+  let mut linear = None;
+  for ra in g.get_interval(&a).ranges.iter() {
+    for rb in g.get_interval(&b).ranges.iter() {
+      if linear.is_none() {
+        linear = ra.get_intersection(rb);
+      }
+    }
+  }
+  assert!(linear.is_some());
+  assert!(g.get_intersection(&a, &b) == linear);
+  assert!(g.get_intersection(&b, &a) == linear);
+
+  // Ranges that touch but never overlap
+  let c = g.new_instr(Number(0), ~[]);
+  let c = g.get_instr(&c).output.unwrap();
+  let mut i = 100;
+  while i > 0 {
+    i -= 1;
+    g.get_mut_interval(&c).add_range(InstrId(i * 10 + 5),
+                                     InstrId(i * 10 + 10));
+  }
+  assert!(g.get_intersection(&a, &c).is_none());
+  assert!(g.get_intersection(&c, &a).is_none());
+}
+
+#[test]
+fn range_lookup_many() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let (a, b) = ranges_graph(&mut *g, 10000);
+
+  // Linear lookup would take ~10^9 steps here
+  let mut pos = 0;
+  let mut covered = 0;
+  while pos < 100000 {
+    if g.get_interval(&a).covers(InstrId(pos)) {
+      covered += 1;
+    }
+    pos += 1;
+  }
+  assert!(covered == 50000);
+
+  let mut i = 0;
+  while i < 10000 {
+    assert!(g.get_intersection(&a, &b) == Some(InstrId(3)));
+    i += 1;
+  }
+}
+
+#[test]
+fn block_index() {
+  let mut g = ~Graph::new();
+  realword_graph(g);
+  g.allocate(Config::new()).get();
+
+  // Cached lookup matches block's instruction list
+  for (_, block) in g.blocks.iter() {
+    for id in block.instructions.iter() {
+      assert!(g.block_of(id) == block.id);
+    }
+  }
+
+  // Inserted moves are registered too
+  g.flush_moves();
+  for (_, block) in g.blocks.iter() {
+    for id in block.instructions.iter() {
+      assert!(g.block_of(id) == block.id);
+      assert!(g.instr_to_block.contains_key(&id.to_uint()));
+    }
+  }
+}
+
+#[test]
+fn fixed_temporary() {
+  let mut shift = None;
+  let g = do run_test(Left(36)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[]);
+      let a = b.add(Number(10), ~[]);
+      let c = b.add(Number(20), ~[]);
+
+      // Shift needs its scratch in `rdx`, while other values are alive
+      let s = b.add(Shift, ~[n]);
+      let r1 = b.add(Sum, ~[s, a]);
+      let r2 = b.add(Sum, ~[r1, c]);
+      b.add(Return, ~[r2]);
+      b.end();
+      shift = Some(s);
+    };
+  };
+
+  let a = g.get_assignment(&g.flat_instr(&shift.unwrap()));
+  assert!(a.temps == ~[RegisterVal(rdx)]);
+}
+
+fn pressure_graph(g: &mut Graph<Kind, Group, Register>) {
+  do g.block() |b| {
+    b.make_root();
+    let mut values = ~[];
+    let mut i = 1;
+    while i <= 5 {
+      values.push(b.add(Number(i), ~[]));
+      i += 1;
+    }
+
+    // All values are alive at once
+    let mut sum = values[0];
+    for v in values.slice_from(1).iter() {
+      sum = b.add(Sum, ~[sum, *v]);
+    }
+    b.add(Return, ~[sum]);
+    b.end();
+  };
+}
+
+#[test]
+fn reserved_registers() {
+  let mut config = Config::new();
+  config.reserved = ~[(Normal.to_uint(), rdx.to_uint())];
+  let (g, _) = do run_test_with(config, Left(15)) |g| {
+    pressure_graph(g);
+  };
+
+  // Only three registers were used
+  for (_, interval) in g.intervals_iter() {
+    if interval.fixed {
+      loop;
+    }
+    assert!(interval.value != RegisterVal(rdx));
+  }
+
+  // Fixed use of reserved register is an error
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  pressure_graph(&mut *g);
+  let mut config = Config::new();
+  config.reserved = ~[(Normal.to_uint(), rax.to_uint())];
+  assert!(g.allocate(config).is_err());
+}
+
+#[test]
+fn prefer_register() {
+  // Free register is given to the use
+  let mut prefer = None;
+  let g = do run_test(Left(7)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(7), ~[]);
+      prefer = Some(b.add(Prefer, ~[n]));
+      b.add(Return, ~[n]);
+      b.end();
+    };
+  };
+  match g.get_assignment(&g.flat_instr(&prefer.unwrap())).inputs[0] {
+    RegisterVal(_) => (),
+    _ => fail!("Expected register")
+  }
+
+  // Stack slot is used when all registers are taken
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let (g, _) = do run_test_with(config, Left(11)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let p = b.add(Number(5), ~[]);
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let s = b.add(Add, ~[n1, n2]);
+      let n3 = b.add(Number(3), ~[]);
+
+      // Both registers are needed by `s` and `n3` after this use
+      prefer = Some(b.add(Prefer, ~[p]));
+      let t = b.add(Add, ~[s, n3]);
+      let res = b.add(Sum, ~[t, p]);
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+  match g.get_assignment(&g.flat_instr(&prefer.unwrap())).inputs[0] {
+    StackVal(Normal, _) => (),
+    _ => fail!("Expected stack slot")
+  }
+}
+
+fn json_field<'r>(json: &'r Json, key: &str) -> &'r Json {
+  match *json {
+    Object(ref obj) => obj.find(&key.to_owned()).expect("JSON field"),
+    _ => fail!("Expected JSON object")
+  }
+}
+
+#[test]
+fn allocation_json() {
+  let g = do run_test(Left(21)) |g| {
+    realword_graph(g);
+  };
+  let json = g.allocation_to_json();
+
+  // Every user instruction output has a location
+  let mut outputs = 0;
+  match *json_field(&json, "instructions") {
+    List(ref list) => for instr in list.iter() {
+      match *json_field(instr, "kind") {
+        String(ref kind) if !kind.starts_with("~") => (),
+        _ => loop
+      }
+      match *json_field(json_field(instr, "registers"), "output") {
+        Null => (),
+        ref out => {
+          assert!(*json_field(out, "type") != String(~"virtual"));
+          outputs += 1;
+        }
+      }
+    },
+    _ => fail!("Expected instruction list")
+  }
+  assert!(outputs > 0);
+
+  // Stack slots are labeled with their group
+  match *json_field(&json, "spills") {
+    List(ref list) => {
+      assert!(list.len() > 0);
+      for spill in list.iter() {
+        assert!(*json_field(spill, "group") == String(Normal.to_str()));
+      }
+    },
+    _ => fail!("Expected spill list")
+  }
+}
+
+fn kind_from_str(s: &str) -> Option<Kind> {
+  let simple = ~[Increment, Sum, Add, JustUse, BranchIfBigger, Print, Return];
+  for k in simple.iter() {
+    if k.to_str().as_slice() == s {
+      return Some(k.clone());
+    }
+  }
+  for n in iterator::range(0u, 100) {
+    if Number(n).to_str().as_slice() == s {
+      return Some(Number(n));
+    }
+  }
+  return None;
+}
+
+#[test]
+fn json_round_trip() {
+  let (g, res) = do run_test_with(Config::new(), Left(21)) |g| {
+    realword_graph(g);
+  };
+  let json = g.allocation_to_json().to_str();
+
+  let mut imported: ~Graph<Kind, Group, Register> =
+      ~Graph::from_json(json, |s| kind_from_str(s)).get();
+  let imported_res = imported.allocate(Config::new()).get();
+  check_result(imported, Left(21));
+
+  assert!(imported_res == res);
+
+  // Unknown kinds are reported
+  let bad: Result<Graph<Kind, Group, Register>, ~str> =
+      Graph::from_json(json, |_| None);
+  assert!(bad.is_err());
+}
+
+#[test]
+fn dump_intervals() {
+  let mut phi = None;
+  let mut cond = None;
+  let mut body = None;
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  {
+    let p = g.phi(Normal);
+    let c = g.empty_block();
+    let l = g.empty_block();
+    let r = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let zero = b.add(Number(0), ~[]);
+      b.to_phi(zero, p);
+      b.goto(c);
+    };
+    do g.with_block(c) |b| {
+      let ten = b.add(Number(10), ~[]);
+      b.add(BranchIfBigger, ~[p, ten]);
+      b.branch(r, l);
+    };
+    do g.with_block(l) |b| {
+      let counter = b.add(Increment, ~[p]);
+      b.to_phi(counter, p);
+      b.goto(c);
+    };
+    do g.with_block(r) |b| {
+      let n = b.add(Number(3), ~[]);
+      b.add(Return, ~[n]);
+      b.end();
+    };
+    phi = Some(p);
+    cond = Some(c);
+    body = Some(l);
+  }
+
+  // Values are virtual before allocation
+  let before = g.dump_intervals();
+  assert!(before.contains("VirtualVal"));
+
+  g.allocate(Config::new()).get();
+  check_result(g, Left(3));
+  let after = g.dump_intervals();
+
+  // Phi lives through the whole loop
+  let id = g.get_instr(&g.flat_instr(&phi.unwrap())).output.unwrap();
+  let cond = g.flat_block(&cond.unwrap()).unwrap();
+  let body = g.flat_block(&body.unwrap()).unwrap();
+  let start = g.get_block(&cond).start().to_uint();
+  let end = g.get_block(&body).end().to_uint();
+  let prefix = fmt!("i%u ", id.to_uint());
+  let line = after.line_iter().find_(|l| l.starts_with(prefix)).unwrap();
+  assert!(!line.contains("VirtualVal"));
+
+  let mut covered_start = false;
+  let mut covered_end = false;
+  for (i, piece) in line.split_iter('[').enumerate() {
+    if i == 0 || !piece.contains(")") {
+      loop;
+    }
+    let span = piece.slice_to(piece.find(')').unwrap());
+    let bounds: ~[uint] = span.split_iter(',').map(|s| {
+      uint::from_str(s).unwrap()
+    }).collect();
+    covered_start = covered_start || bounds[0] <= start && start < bounds[1];
+    covered_end = covered_end || bounds[0] < end && end <= bounds[1];
+  }
+  assert!(covered_start && covered_end);
+}
+
+#[test]
+fn incremental_liveness() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let r = realword_graph(g);
+  g.prepare().get();
+
+  // Put new block on the loop's back edge
+  let from = g.flat_block(&r.after_left).unwrap();
+  let to = g.flat_block(&r.cond).unwrap();
+  let new_block = g.split_edge(from, to);
+  g.relive([new_block, from]);
+
+  let snapshot = |g: &Graph<Kind, Group, Register>| {
+    do g.get_block_list().map() |id| {
+      let block = g.get_block(id);
+      (block.live_in.clone(), block.live_out.clone())
+    }
+  };
+  let incremental = snapshot(&*g);
+
+  // Empty block passes everything to its successor
+  assert!(g.get_block(&new_block).live_in == g.get_block(&to).live_in);
+
+  // Full recompute gives the same sets
+  g.liveness_analysis();
+  assert!(snapshot(&*g) == incremental);
+}
+
+#[test]
+fn spill_everywhere() {
+  let mut config = Config::new();
+  config.spill_everywhere = true;
+  let (g, _) = do run_test_with(config, Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut registers = 0;
+  for (_, interval) in g.intervals_iter() {
+    if interval.fixed || interval.ranges.len() == 0 {
+      loop;
+    }
+
+    // Only pieces around register uses are getting registers
+    let needs_reg = do interval.uses.iter().any() |u| {
+      !u.kind.is_any() && !u.kind.is_prefer()
+    };
+    match interval.value {
+      RegisterVal(_) => {
+        assert!(needs_reg);
+        registers += 1;
+      },
+      StackVal(_, _) => assert!(!needs_reg),
+      _ => fail!("Unexpected value")
+    }
+  }
+  assert!(registers > 0);
+}
+
+#[test]
+fn split_limit() {
+  let mut config = Config::new();
+  config.split_limit = 0;
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  realword_graph(g);
+  match g.allocate(config) {
+    Err(reason) => assert!(reason == ~"allocation did not converge"),
+    Ok(_) => fail!("Expected allocation failure")
+  }
+}
+
+#[test]
+fn loop_info() {
+  let mut r = None;
+  let g = do run_test(Left(21)) |g| {
+    r = Some(realword_graph(g));
+  };
+  let r = r.unwrap();
+
+  for &(id, index, depth) in g.loop_info().iter() {
+    if id == r.root || id == r.right {
+      assert!(depth == 0);
+    } else if id == r.cond || id == r.left || id == r.after_left {
+      assert!(depth == 1);
+      assert!(index != 0);
+    }
+  }
+
+  assert!(g.loop_header(r.root).is_none());
+  assert!(g.loop_header(r.left) == Some(r.cond));
+  assert!(g.loop_header(r.cond) == Some(r.cond));
+}
+
+fn merge_graph(g: &mut Graph<Kind, Group, Register>, selector: uint)
+    -> InstrId {
+  let phi = g.phi(Normal);
+  let exit = g.empty_block();
+  let cases = ~[g.empty_block(), g.empty_block(),
+                g.empty_block(), g.empty_block()];
+
+  do g.block() |b| {
+    b.make_root();
+    let sel = b.add(Number(selector), ~[]);
+    b.add(Switch, ~[sel]);
+    b.switch(cases.clone());
+  };
+
+  for (i, case) in cases.iter().enumerate() {
+    do g.with_block(*case) |b| {
+      let value = b.add(Number(i * 10), ~[]);
+      let value = b.add(Increment, ~[value]);
+      b.to_phi(value, phi);
+      b.goto(exit);
+    };
+  }
+
+  do g.with_block(exit) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+  return phi;
+}
+
+#[test]
+fn phi_many_inputs() {
+  for selector in iterator::range(0u, 4) {
+    let mut phi = None;
+    let g = do run_test(Left(selector * 10 + 1)) |g| {
+      phi = Some(merge_graph(g, selector));
+    };
+
+    // Every predecessor of the merge point supplies its own input
+    let phi = g.flat_instr(&phi.unwrap());
+    assert!(g.get_instr(&phi).inputs.len() == 4);
+  }
+}
+
+#[test]
+fn hint_chain() {
+  let mut x = None;
+  let g = do run_test(Left(3)) |g| {
+    let mut sum = None;
+    do g.block() |b| {
+      b.make_root();
+      let one = b.add(Number(1), ~[]);
+      let value = b.add(Sum, ~[one, one]);
+
+      // Every call splits the value, every use reloads it
+      for _ in iterator::range(0u, 3) {
+        b.add(Print, ~[one]);
+        b.add(ToDouble, ~[value]);
+      }
+
+      let res = b.add(Sum, ~[value, one]);
+      b.add(Return, ~[res]);
+      b.end();
+      sum = Some(value);
+    };
+    x = Some(g.get_output(&sum.unwrap()));
+  };
+
+  // All children in registers are using the register of the original value
+  let x = x.unwrap();
+  let mut intervals = ~[x];
+  intervals.push_all(g.get_interval(&x).children);
+
+  let mut registers = ~[];
+  for id in intervals.iter() {
+    match g.get_interval(id).value {
+      RegisterVal(r) => registers.push(r),
+      _ => ()
+    }
+  }
+  assert!(registers.len() >= 3);
+  for r in registers.iter() {
+    assert!(*r == registers[0]);
+  }
+}
+
+#[test]
+fn max_spill_slots() {
+  // Generous limit
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.max_spill_slots = Some(16);
+  let (_, res) = do run_test_with(config, Left(15)) |g| {
+    pressure_graph(g);
+  };
+  assert!(res.spill_count[Normal.to_uint()] > 0);
+
+  // No spill slots at all
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  pressure_graph(&mut *g);
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.max_spill_slots = Some(0);
+  match g.allocate(config) {
+    Err(reason) => {
+      assert!(reason == fmt!("Spill slot limit exceeded in group %u",
+                             Normal.to_uint()));
+    },
+    Ok(_) => fail!("Expected allocation failure")
+  }
+}
+
+#[test]
+fn stack_slot_at() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let (g, _) = do run_test_with(config, Left(15)) |g| {
+    pressure_graph(g);
+  };
+
+  // Slot is the same at every position of the spilled child
+  let mut spilled = 0;
+  for (_, interval) in g.intervals_iter() {
+    let slot = match interval.value {
+      StackVal(group, slot) if !interval.fixed => (group, slot),
+      _ => loop
+    };
+    let parent = match interval.parent {
+      Some(parent) => parent,
+      None => interval.id
+    };
+    for range in interval.ranges.iter() {
+      for pos in iterator::range(range.start.to_uint(), range.end.to_uint()) {
+        assert!(g.stack_slot_at(parent, InstrId(pos)) == Some(slot.clone()));
+        assert!(g.stack_slot_at(interval.id,
+                                InstrId(pos)) == Some(slot.clone()));
+      }
+    }
+    spilled += 1;
+  }
+  assert!(spilled > 0);
+}
+
+#[test]
+fn allocation_snapshot() {
+  let g = do run_test(Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let sum = b.add(Sum, ~[n1, n2]);
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+  let expected = ~[
+    "block 0 -> []",
+    "  1: Number(1) out: Some(RegisterVal(rax)) in: [] tmp: []",
+    "  3: Number(2) out: Some(RegisterVal(rbx)) in: [] tmp: []",
+    "  5: Sum out: Some(RegisterVal(rax)) \
+     in: [RegisterVal(rax), RegisterVal(rbx)] tmp: []",
+    "  7: Return out: None in: [RegisterVal(rax)] tmp: []"
+  ];
+  let snapshot = g.allocation_snapshot();
+  let lines: ~[&str] = snapshot.line_iter().collect();
+  if lines != expected {
+    fail!(fmt!("got:\n%s", snapshot));
+  }
+
+  // Loop example: phi is spilled across the call, gap moves are listed
+  // before instructions at the same position
+  let g = do run_test(Left(21)) |g| {
+    realword_graph(g);
+  };
+  let expected = ~[
+    "block 0 -> [1]",
+    "  1: Number(10) out: Some(RegisterVal(rax)) in: [] tmp: []",
+    "  3: Number(0) out: Some(RegisterVal(rbx)) in: [] tmp: []",
+    "  5: to_phi out: Some(RegisterVal(rbx)) in: [RegisterVal(rbx)] tmp: []",
+    "  6: move RegisterVal(rax) -> StackVal(Normal, StackId(0))",
+    "block 1 -> [4, 2]",
+    "  8: Number(10) out: Some(RegisterVal(rax)) in: [] tmp: []",
+    "  10: JustUse out: None in: [RegisterVal(rbx)] tmp: []",
+    "  11: move RegisterVal(rbx) -> RegisterVal(rcx)",
+    "  12: BranchIfBigger out: None \
+     in: [RegisterVal(rcx), RegisterVal(rax)] tmp: [RegisterVal(rax)]",
+    "block 2 -> [3]",
+    "  14: move RegisterVal(rcx) -> RegisterVal(rdx)",
+    "  15: move RegisterVal(rdx) -> StackVal(Normal, StackId(4))",
+    "  15: Print out: Some(RegisterVal(rax)) in: [RegisterVal(rdx)] tmp: []",
+    "  17: Increment out: Some(RegisterVal(rax)) \
+     in: [RegisterVal(rax)] tmp: []",
+    "block 3 -> [1]",
+    "  20: Increment out: Some(RegisterVal(rax)) \
+     in: [StackVal(Normal, StackId(4))] tmp: []",
+    "  22: to_phi out: Some(StackVal(Normal, StackId(4))) \
+     in: [RegisterVal(rax)] tmp: []",
+    "  23: move StackVal(Normal, StackId(4)) -> RegisterVal(rbx)",
+    "block 4 -> []",
+    "  24: move RegisterVal(rcx) -> StackVal(Normal, StackId(4))",
+    "  25: Sum out: Some(RegisterVal(rax)) \
+     in: [StackVal(Normal, StackId(0)), StackVal(Normal, StackId(4))] tmp: []",
+    "  27: Return out: None in: [RegisterVal(rax)] tmp: []"
+  ];
+  let snapshot = g.allocation_snapshot();
+  let lines: ~[&str] = snapshot.line_iter().collect();
+  if lines != expected {
+    fail!(fmt!("got:\n%s", snapshot));
+  }
+
+  // Allocation is deterministic
+  let other = do run_test(Left(21)) |g| {
+    realword_graph(g);
+  };
+  assert!(snapshot == other.allocation_snapshot());
+}
+
+#[test]
+fn random_graphs() {
+  let params = RandomParams::new();
+  for seed in iterator::range(0u, 300) {
+    // Both modes are allocating the graph and computing the same result
+    let mut results = ~[];
+    for &spill_everywhere in [false, true].iter() {
+      let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+      random_graph(&mut *g, seed, &params);
+
+      let mut config = Config::new();
+      config.spill_everywhere = spill_everywhere;
+      match g.allocate(config) {
+        Ok(_) => (),
+        Err(reason) => fail!(fmt!("seed %u: %s", seed, reason))
+      }
+
+      let mut emu = Emulator::new();
+      results.push(emu.run(g));
+    }
+    if results[0] != results[1] {
+      fail!(fmt!("seed %u: got %? and %?", seed, results[0], results[1]));
+    }
+  }
+}
+
+#[test]
+fn intersections() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let a = g.new_instr(Number(0), ~[]);
+  let a = g.get_instr(&a).output.unwrap();
+  let b = g.new_instr(Number(0), ~[]);
+  let b = g.get_instr(&b).output.unwrap();
+
+  // a: [0, 10) [20, 30)   b: [5, 25)
+  g.get_mut_interval(&a).add_range(InstrId(20), InstrId(30));
+  g.get_mut_interval(&a).add_range(InstrId(0), InstrId(10));
+  g.get_mut_interval(&b).add_range(InstrId(5), InstrId(25));
+
+  let expected = ~[(InstrId(5), InstrId(10)), (InstrId(20), InstrId(25))];
+  assert!(g.intersections(&a, &b) == expected);
+  assert!(g.intersections(&b, &a) == expected);
+  assert!(g.get_intersection(&a, &b) == Some(InstrId(5)));
+
+  // Disjoint intervals
+  let c = g.new_instr(Number(0), ~[]);
+  let c = g.get_instr(&c).output.unwrap();
+  g.get_mut_interval(&c).add_range(InstrId(10), InstrId(20));
+  assert!(g.intersections(&a, &c).len() == 0);
+}
+
+#[test]
+fn coalesce_spills() {
+  let (mut g, mut res) = do run_test_with(Config::new(), Left(15)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let mut acc = b.add(Number(0), ~[]);
+
+      // Calls are spilling both accumulator and the next value
+      let mut i = 1;
+      while i <= 5 {
+        let value = b.add(Number(i), ~[]);
+        b.add(Print, ~[value]);
+        acc = b.add(Sum, ~[acc, value]);
+        i += 1;
+      }
+      b.add(Return, ~[acc]);
+      b.end();
+    };
+  };
+
+  fn count_slots(g: &Graph<Kind, Group, Register>) -> uint {
+    let mut slots = ~[];
+    for (_, interval) in g.intervals_iter() {
+      match interval.value {
+        StackVal(_, slot) if !slots.contains(&slot) => slots.push(slot),
+        _ => ()
+      }
+    }
+    return slots.len();
+  }
+
+  assert!(count_slots(g) > 2);
+  let frame_size = res.frame_size;
+  g.coalesce_spills(&mut res);
+  assert!(count_slots(g) <= 2);
+  check_result(g, Left(15));
+
+  // Frame shrinks together with the spill area
+  let normal = Normal.to_uint();
+  assert!(res.spill_count[normal] <= 2);
+  assert!(res.spill_bytes[normal] ==
+          res.spill_count[normal] * Normal.spill_size());
+  assert!(res.frame_size < frame_size);
+}
+
+#[test]
+fn cross_group_use() {
+  let g = do run_test(Left(5)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(5), ~[]);
+      b.add(FixedDouble, ~[n]);
+      b.add(Return, ~[n]);
+      b.end();
+    };
+  };
+
+  // Value itself stays in its group, only the use is copied
+  assert!(count_cross_moves(g) == 1);
+}
+
+#[test]
+fn tie_break() {
+  fn run(tie_break: TieBreak) -> (Value<Group, Register>,
+                                  Value<Group, Register>) {
+    let mut config = Config::new();
+    config.tie_break = tie_break;
+    let mut ids = None;
+    let (g, _) = do run_test_with(config, Left(4)) |g| {
+      do g.block() |b| {
+        b.make_root();
+
+        // Every register is free for both `a` and `x`
+        let a = b.add(Number(1), ~[]);
+        let x = b.add(Increment, ~[a]);
+        let res = b.add(Sum, ~[x, x]);
+        b.add(Return, ~[res]);
+        b.end();
+        ids = Some((a, x));
+      };
+    };
+    let (a, x) = ids.unwrap();
+    return (g.register_at(a).unwrap(), g.register_at(x).unwrap());
+  }
+
+  assert!(run(LowestIndex) == (RegisterVal(rax), RegisterVal(rax)));
+  assert!(run(HighestIndex) == (RegisterVal(rdx), RegisterVal(rdx)));
+  assert!(run(RoundRobin) == (RegisterVal(rax), RegisterVal(rbx)));
+}
+
+#[test]
+fn used_registers() {
+  let (g, res) = do run_test_with(Config::new(), Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut seen = ~[];
+  for (_, interval) in g.intervals_iter() {
+    match interval.value {
+      RegisterVal(r) if !interval.fixed && r.group() == Normal => {
+        if !seen.contains(&r.to_uint()) {
+          seen.push(r.to_uint());
+        }
+      },
+      _ => ()
+    }
+  }
+
+  let used = &res.used_registers[Normal.to_uint()];
+  assert!(used.len() == seen.len());
+  for r in seen.iter() {
+    assert!(used.contains(r));
+  }
+
+  // Sorted by index
+  let mut i = 1;
+  while i < used.len() {
+    assert!(used[i - 1] < used[i]);
+    i += 1;
+  }
+  assert!(res.used_registers[Double.to_uint()].len() == 0);
+}
+
+#[test]
+fn insert_instructions() {
+  let mut ids = None;
+  let g = do run_test(Left(10)) |g| {
+    let mut anchors = None;
+    let block = do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(10), ~[]);
+      let ret = b.add(Return, ~[n]);
+      b.end();
+      anchors = Some((n, ret));
+    };
+
+    // Late lowering of the ended block
+    let (n, ret) = anchors.unwrap();
+    do g.with_block(block) |b| {
+      let four = b.insert_before(ret, Number(4), ~[]);
+      let sum = b.insert_after(four, Sum, ~[n, four]);
+      b.insert_after(sum, JustUse, ~[sum]);
+      ids = Some((n, four, sum, ret));
+    };
+  };
+
+  // Inserted instructions are placed in order, and their values are allocated
+  let (n, four, sum, ret) = ids.unwrap();
+  let block = g.get_block(&g.root.unwrap());
+  let order = do [n, four, sum, ret].map() |id| {
+    block.instructions.position_elem(&g.flat_instr(id)).expect("In block")
+  };
+  let mut i = 1;
+  while i < order.len() {
+    assert!(order[i - 1] < order[i]);
+    i += 1;
+  }
+  match g.register_at(sum) {
+    Some(RegisterVal(_)) => (),
+    _ => fail!("Expected register")
+  }
+}
+
+#[test]
+fn remove_instr() {
+  let mut removed = None;
+  let g = do run_test(Left(10)) |g| {
+    let mut ids = None;
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(10), ~[]);
+      let dead = b.add(Increment, ~[n]);
+      b.add(Return, ~[n]);
+      b.end();
+      ids = Some((n, dead));
+    };
+
+    // Used instruction can't be removed
+    let (n, dead) = ids.unwrap();
+    assert!(g.remove_instr(n).is_err());
+
+    let output = g.get_output(&dead);
+    assert!(g.remove_instr(dead).is_ok());
+    removed = Some((dead, output));
+  };
+
+  // Neither instruction, nor its interval were allocated
+  let (dead, output) = removed.unwrap();
+  assert!(!g.instr_map.contains_key(&dead.to_uint()));
+  for (id, _) in g.intervals_iter() {
+    assert!(id != output);
+  }
+}
+
+#[test]
+fn family_uses() {
+  let mut x = None;
+  let g = do run_test(Left(3)) |g| {
+    let mut sum = None;
+    do g.block() |b| {
+      b.make_root();
+      let one = b.add(Number(1), ~[]);
+      let value = b.add(Sum, ~[one, one]);
+
+      // Calls are splitting the value
+      for _ in iterator::range(0u, 2) {
+        b.add(Print, ~[one]);
+        b.add(ToDouble, ~[value]);
+      }
+
+      let res = b.add(Sum, ~[value, one]);
+      b.add(Return, ~[res]);
+      b.end();
+      sum = Some(value);
+    };
+    x = Some(g.get_output(&sum.unwrap()));
+  };
+
+  let x = x.unwrap();
+  let child = *g.get_interval(&x).children.last();
+  let after = g.get_interval(&x).end();
+
+  // Parent fragment has no uses after its end, but the family does
+  assert!(g.get_interval(&x).next_use(after).is_none());
+  let next = g.next_register_use(x, after).expect("Use in child");
+  assert!(next.pos >= after);
+  assert!(g.next_register_use(child, after).unwrap().pos == next.pos);
+
+  // Last use is found from any fragment
+  let end = g.get_interval(&child).end();
+  let last = g.last_register_use(x, end).expect("Last use");
+  assert!(last.pos >= next.pos);
+  assert!(g.next_fixed_register_use(x, InstrId(0)).is_none());
+}
+
+#[test]
+fn check_gap_schedule() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let ids = do [rax, rbx, rcx].map() |r| {
+    let instr = g.new_instr(Number(0), ~[]);
+    let id = g.get_output(&instr);
+    g.get_mut_interval(&id).value = RegisterVal(*r);
+    id
+  };
+  let (a, b, c) = (ids[0], ids[1], ids[2]);
+
+  // Parallel moves: a => b, b => c
+  g.get_mut_gap(&InstrId(0)).add_move(&a, &b);
+  g.get_mut_gap(&InstrId(0)).add_move(&b, &c);
+
+  // Correct order
+  g.get_mut_gap(&InstrId(1)).add_move(&b, &c);
+  g.get_mut_gap(&InstrId(1)).add_move(&a, &b);
+
+  // Malformed: `b` is overwritten before it is moved
+  g.get_mut_gap(&InstrId(2)).add_move(&a, &b);
+  g.get_mut_gap(&InstrId(2)).add_move(&b, &c);
+
+  let parallel = g.gaps.get(&0).actions.clone();
+  assert!(g.check_gap(parallel, g.gaps.get(&1).actions).is_ok());
+  assert!(g.check_gap(parallel, g.gaps.get(&2).actions).is_err());
+}
+
+#[test]
+fn verify_flag() {
+  let mut config = Config::new();
+  config.verify = true;
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  realword_graph(g);
+  match g.allocate(config) {
+    Ok(_) => check_result(g, Left(21)),
+    Err(reason) => fail!(reason)
+  }
+}
+
+#[test]
+fn align_splits() {
+  struct Aligned {
+    call_block: BlockId,
+    use_block: BlockId,
+    values: ~[IntervalId],
+    sum: InstrId
+  }
+
+  fn body(g: &mut Graph<Kind, Group, Register>) -> Aligned {
+    let call_block = g.empty_block();
+    let use_block = g.empty_block();
+    let mut values = ~[];
+    do g.block() |b| {
+      b.make_root();
+      values.push(b.add(Number(3), ~[]));
+      values.push(b.add(Number(4), ~[]));
+      b.goto(call_block);
+    };
+
+    // Both values are spilled at the call
+    do g.with_block(call_block) |b| {
+      let n = b.add(Number(0), ~[]);
+      b.add(Print, ~[n]);
+      b.goto(use_block);
+    };
+
+    let mut sum = None;
+    do g.with_block(use_block) |b| {
+      let one = b.add(Number(1), ~[]);
+      let inc = b.add(Increment, ~[one]);
+      let s = b.add(Sum, ~[inc, values[0]]);
+      let res = b.add(Sum, ~[s, values[1]]);
+      b.add(Return, ~[res]);
+      b.end();
+      sum = Some(s);
+    };
+
+    Aligned {
+      call_block: call_block,
+      use_block: use_block,
+      values: do values.map() |v| { g.get_output(v) },
+      sum: sum.unwrap()
+    }
+  }
+
+  // Reloads are placed on the edge, in one parallel move
+  let mut r = None;
+  let (g, _) = do run_test_with(Config::new(), Left(9)) |g| {
+    r = Some(body(g));
+  };
+  let r = r.unwrap();
+  let sum = g.flat_instr(&r.sum);
+  let block_start = g.get_block(&g.flat_block(&r.use_block).unwrap()).start();
+  let edge = g.get_block(&g.flat_block(&r.call_block).unwrap()).end().prev();
+  for value in r.values.iter() {
+    let child = g.child_at(value, sum).expect("Reloaded value");
+    assert!(g.get_interval(&child).start() == block_start);
+    assert!(g.gaps.get(&edge.to_uint()).actions.iter().any(|a| {
+      a.to == child
+    }));
+  }
+
+  // Without alignment reloads are right before the use
+  let mut config = Config::new();
+  config.align_splits = false;
+  let mut r = None;
+  let (g, _) = do run_test_with(config, Left(9)) |g| {
+    r = Some(body(g));
+  };
+  let r = r.unwrap();
+  let sum = g.flat_instr(&r.sum);
+  let block_start = g.get_block(&g.flat_block(&r.use_block).unwrap()).start();
+  let child = g.child_at(&r.values[0], sum).expect("Reloaded value");
+  assert!(g.get_interval(&child).start() > block_start);
+}
+
+#[test]
+fn interval_order() {
+  let params = RandomParams::new();
+  for seed in iterator::range(0u, 100) {
+    // Every ordering is allocating the graph and computing the same result
+    let mut results = ~[];
+    let orders = [StartOrder, LongestFirst, CostliestFirst, FixedFirst];
+    for &order in orders.iter() {
+      let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+      random_graph(&mut *g, seed, &params);
+
+      let mut config = Config::new();
+      config.interval_order = order;
+      match g.allocate(config) {
+        Ok(_) => (),
+        Err(reason) => fail!(fmt!("seed %u: %s", seed, reason))
+      }
+
+      let mut emu = Emulator::new();
+      results.push(emu.run(g));
+    }
+    if results.iter().any(|r| *r != results[0]) {
+      fail!(fmt!("seed %u: got %?", seed, results));
+    }
+  }
+
+  // `x` is reloaded right where call's output starts. Taking the first
+  // register for the longer `x` leaves `rbx` to the short output, which is
+  // dead before `fixed` needs `rbx`, so `x` isn't spilled again
+  let orders = [StartOrder, LongestFirst, CostliestFirst];
+  let mut spills = ~[];
+  for &order in orders.iter() {
+    let mut config = Config::new();
+    config.interval_order = order;
+    config.reuse_spill_slots = false;
+    let (_, res) = do run_test_with(config, Left(31)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let arg = b.add(Number(5), ~[]);
+        let x = b.add(Number(10), ~[]);
+        let out = b.add(Print, ~[arg]);
+        b.add(ReadAll, ~[]);
+        b.add(Prefer, ~[out]);
+        let fixed = b.add(Number(7), ~[]);
+        b.add(JustUse, ~[fixed]);
+        let mut sum = b.add(Increment, ~[x]);
+        sum = b.add(Sum, ~[sum, x]);
+        sum = b.add(Sum, ~[sum, x]);
+        b.add(Return, ~[sum]);
+        b.end();
+      };
+    };
+    spills.push(res.spill_count[Normal.to_uint()]);
+  }
+  assert!(spills[1] < spills[0]);
+  assert!(spills[2] < spills[0]);
+}
+
+#[test]
+fn interferes() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let ids = do vec::from_fn(5) |_| {
+    let instr = g.new_instr(Number(0), ~[]);
+    g.get_output(&instr)
+  };
+  let (a, b, c, d, child) = (ids[0], ids[1], ids[2], ids[3], ids[4]);
+
+  // Overlapping: a: [0, 10)   b: [5, 15)
+  g.get_mut_interval(&a).add_range(InstrId(0), InstrId(10));
+  g.get_mut_interval(&b).add_range(InstrId(5), InstrId(15));
+  assert!(g.interferes(a, b));
+  assert!(g.interferes(b, a));
+
+  // Disjoint: c: [20, 30)   d: [30, 40)
+  g.get_mut_interval(&c).add_range(InstrId(20), InstrId(30));
+  g.get_mut_interval(&d).add_range(InstrId(30), InstrId(40));
+  assert!(!g.interferes(c, d));
+  assert!(!g.interferes(a, c));
+
+  // Split child of `a` overlaps with `d`, parent is representing it
+  g.get_mut_interval(&child).add_range(InstrId(35), InstrId(45));
+  g.get_mut_interval(&child).parent = Some(a);
+  g.get_mut_interval(&a).children.push(child);
+  assert!(g.interferes(a, d));
+  assert!(g.interferes(d, a));
+  assert!(!g.interferes(child, b));
+}
+
+#[test]
+fn no_spill() {
+  fn spilled(g: &Graph<Kind, Group, Register>, id: IntervalId) -> bool {
+    for (_, interval) in g.intervals_iter() {
+      if interval.id != id && interval.parent != Some(id) {
+        loop;
+      }
+      match interval.value {
+        StackVal(_, _) => return true,
+        _ => ()
+      }
+    }
+    return false;
+  }
+
+  // Value with the farthest use is kept in register
+  let mut ids = ~[];
+  let (g, res) = do run_test_with(Config::new(), Left(21)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let mut values = ~[];
+      for i in iterator::range(1u, 7) {
+        values.push(b.add(Number(i), ~[]));
+      }
+
+      let mut sum = values[0];
+      for v in values.slice_from(1).iter() {
+        sum = b.add(Sum, ~[sum, *v]);
+      }
+      b.add(Return, ~[sum]);
+      b.end();
+      ids = values;
+    };
+    g.no_spill(*ids.last());
+  };
+  assert!(res.spill_count[Normal.to_uint()] > 0);
+  let kept = g.get_output(ids.last());
+  assert!(!spilled(g, kept));
+  let others = ids.slice_to(ids.len() - 1);
+  assert!(others.iter().any(|id| spilled(g, g.get_output(id))));
+
+  // Value that is live across call can't be kept in register
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[]);
+    let zero = b.add(Number(0), ~[]);
+    b.add(Print, ~[zero]);
+    b.add(Return, ~[n]);
+    b.end();
+    ids = ~[n];
+  };
+  g.no_spill(ids[0]);
+  assert!(g.allocate(Config::new()).is_err());
+}
+
+#[test]
+fn spill_schedule() {
+  let mut ids = None;
+  let (g, _) = do run_test_with(Config::new(), Right(3f)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let x = b.add(Number(3), ~[]);
+      let zero = b.add(Number(0), ~[]);
+      let call = b.add(Print, ~[zero]);
+      let d = b.add(ToDouble, ~[x]);
+      b.add(ReturnDouble, ~[d]);
+      b.end();
+      ids = Some((call, d));
+    };
+  };
+  let (call, use_pos) = ids.unwrap();
+  let call = g.flat_instr(&call);
+  let use_pos = g.flat_instr(&use_pos);
+
+  // `x` is stored before the call and reloaded before its use
+  let ops = g.spill_schedule();
+  assert!(ops.len() == 2);
+  let (store, reload) = (&ops[0], &ops[1]);
+  assert!(store.kind == Store && reload.kind == Reload);
+  assert!(store.pos <= call && call < reload.pos && reload.pos < use_pos);
+  assert!(store.group == Normal && reload.group == Normal);
+  assert!(store.slot == reload.slot);
+}
+
+#[test]
+fn reads_all() {
+  // Values would stay in stack slots without the instruction
+  for &spill_everywhere in [false, true].iter() {
+    let mut config = Config::new();
+    config.spill_everywhere = spill_everywhere;
+    let mut read = None;
+    let (g, _) = do run_test_with(config, Left(6)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let n1 = b.add(Number(1), ~[]);
+        let n2 = b.add(Number(2), ~[]);
+        let n3 = b.add(Number(3), ~[]);
+        read = Some(b.add(ReadAll, ~[]));
+        let mut sum = b.add(Sum, ~[n1, n2]);
+        sum = b.add(Sum, ~[sum, n3]);
+        b.add(Return, ~[sum]);
+        b.end();
+      };
+    };
+
+    // Every live value is in register at the instruction
+    let pos = g.flat_instr(&read.unwrap());
+    let mut live = 0;
+    for (_, interval) in g.intervals_iter() {
+      if interval.fixed || interval.value.group() != Normal ||
+         !interval.covers(pos) {
+        loop;
+      }
+      match interval.value {
+        RegisterVal(_) => live += 1,
+        _ => fail!(fmt!("Interval %u is not in register",
+                        interval.id.to_uint()))
+      }
+    }
+    assert!(live == 3);
+  }
+}
+
+#[test]
+fn locations() {
+  let mut value = None;
+  let mut x = None;
+  let (g, _) = do run_test_with(Config::new(), Right(3f)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[]);
+      let zero = b.add(Number(0), ~[]);
+      b.add(Print, ~[zero]);
+      let d = b.add(ToDouble, ~[n]);
+      b.add(ReturnDouble, ~[d]);
+      b.end();
+      value = Some(n);
+    };
+    x = Some(g.get_output(&value.unwrap()));
+  };
+  let x = x.unwrap();
+
+  // Value is split around the call and spilled
+  let mut start = g.get_interval(&x).start();
+  let mut end = g.get_interval(&x).end();
+  for child in g.get_interval(&x).children.iter() {
+    let child = g.get_interval(child);
+    if child.start() < start { start = child.start(); }
+    if child.end() > end { end = child.end(); }
+  }
+
+  // Ranges tile the lifetime without gaps and overlaps
+  let locations = g.locations(x);
+  assert!(locations.len() >= 2);
+  let (ref first, _) = locations[0];
+  let (ref last, _) = locations[locations.len() - 1];
+  assert!(first.start == start && last.end == end);
+  let mut spilled = false;
+  for i in iterator::range(0u, locations.len()) {
+    let (ref range, ref value) = locations[i];
+    assert!(range.start < range.end);
+    match *value {
+      StackVal(_, _) => spilled = true,
+      _ => ()
+    }
+    if i > 0 {
+      let (ref prev, ref prev_value) = locations[i - 1];
+      assert!(prev.end == range.start);
+      assert!(*prev_value != *value);
+    }
+  }
+  assert!(spilled);
+}
+
+#[test]
+fn greedy_any() {
+  fn run(greedy_any: bool) -> bool {
+    let mut config = Config::new();
+    config.spill_everywhere = true;
+    config.greedy_any = greedy_any;
+    let mut value = None;
+    let mut x = None;
+    let (g, _) = do run_test_with(config, Left(6)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let n = b.add(Number(5), ~[]);
+        let one = b.add(Number(1), ~[]);
+        let sum = b.add(Sum, ~[n, one]);
+        b.add(Return, ~[sum]);
+        b.end();
+        value = Some(n);
+      };
+      x = Some(g.get_output(&value.unwrap()));
+    };
+
+    // Return true if piece of value before its `UseAny` is in stack
+    let x = x.unwrap();
+    let mut spilled = false;
+    for (_, interval) in g.intervals_iter() {
+      if interval.id != x && interval.parent != Some(x) {
+        loop;
+      }
+      match interval.value {
+        StackVal(_, _) => spilled = true,
+        _ => ()
+      }
+    }
+    return spilled;
+  }
+
+  assert!(run(false));
+  assert!(!run(true));
+}
+
+#[test]
+fn edge_move_pos() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let phi = g.phi(Normal);
+  let branch = g.empty_block();
+  let side = g.empty_block();
+  let join = g.empty_block();
+  do g.block() |b| {
+    b.make_root();
+    b.add(Nop, ~[]);
+    b.goto(branch);
+  };
+
+  // Critical edge: branch to the block with two predecessors
+  do g.with_block(branch) |b| {
+    let n = b.add(Number(2), ~[]);
+    b.to_phi(n, phi);
+    b.add(BranchIfTrue, ~[n]);
+    b.branch(join, side);
+  };
+  do g.with_block(side) |b| {
+    let n = b.add(Number(3), ~[]);
+    b.to_phi(n, phi);
+    b.goto(join);
+  };
+  do g.with_block(join) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+  g.prepare().get();
+
+  // Critical edge was split
+  let branch = g.flat_block(&branch).unwrap();
+  let join = g.flat_block(&join).unwrap();
+  assert!(!g.get_block(&branch).successors.contains(&join));
+
+  // Every edge has moves in the block that has no other edges
+  let mut arities = ~[];
+  let blocks = g.get_block_list();
+  for from in blocks.iter() {
+    for to in g.get_block(from).successors.iter() {
+      let succs = g.get_block(from).successors.len();
+      let preds = g.get_block(to).predecessors.len();
+      assert!(succs == 1 || preds == 1);
+      let arity = (succs > 1, preds > 1);
+      if !arities.contains(&arity) {
+        arities.push(arity);
+      }
+
+      let pos = g.edge_move_pos(from, to);
+      if g.block_of(&pos) == *from {
+        assert!(succs == 1 && pos == g.get_block(from).end().prev());
+      } else {
+        assert!(g.block_of(&pos) == *to);
+        assert!(preds == 1 && pos == g.get_block(to).start());
+      }
+    }
+  }
+  assert!(arities.len() == 3);
+}
+
+#[test]
+fn interference_graph() {
+  let mut r = None;
+  let g = do run_test(Left(21)) |g| {
+    r = Some(realword_graph(g));
+  };
+  let r = r.unwrap();
+
+  // Same result as checking every pair
+  let graph = g.interference_graph();
+  for &(id, ref adj) in graph.iter() {
+    for &(other, _) in graph.iter() {
+      if other != id {
+        assert!(adj.contains(&other) == g.interferes(id, other));
+      }
+    }
+  }
+
+  // Phi and increment interfere only if some of their pieces overlap
+  let phi = g.get_output(&g.flat_instr(&r.phi));
+  let counter = g.get_output(&g.flat_instr(&r.counter));
+  let mut phi_family = ~[phi];
+  phi_family.push_all(g.get_interval(&phi).children);
+  let mut counter_family = ~[counter];
+  counter_family.push_all(g.get_interval(&counter).children);
+  let overlap = do phi_family.iter().any() |a| {
+    counter_family.iter().any(|b| g.intersections(a, b).len() != 0)
+  };
+  for &(id, ref adj) in graph.iter() {
+    if id == phi {
+      assert!(adj.contains(&counter) == overlap);
+    }
+  }
+}
+
+#[test]
+fn add_return() {
+  // Value used in rbx is returned in rcx
+  let mut used = None;
+  let mut ret = None;
+  let g = do run_test(Left(4)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[]);
+      let res = b.add(Increment, ~[n]);
+      used = Some(b.add(JustUse, ~[res]));
+      ret = Some(b.add_return(ReturnAny, ~[res], ~[rcx]));
+    };
+  };
+
+  let used = g.get_assignment(&g.flat_instr(&used.unwrap()));
+  assert!(used.inputs[0] == RegisterVal(rbx));
+  let ret = g.get_assignment(&g.flat_instr(&ret.unwrap()));
+  assert!(ret.inputs[0] == RegisterVal(rcx));
+}
+
+#[test]
+fn blocked_fixed_use() {
+  // Value defined in rbx while rbx is held by value that can't be spilled
+  let mut ids = ~[];
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[]);
+    let m = b.add_fixed(Number(2), ~[], rbx);
+    b.add(JustUse, ~[n]);
+    b.add(Return, ~[m]);
+    b.end();
+    ids = ~[n, m];
+  };
+  g.no_spill(ids[0]);
+  let held = g.get_output(&ids[0]);
+  let def = g.get_output(&ids[1]);
+
+  // Error names both intervals and the position of conflicting use
+  match g.allocate(Config::new()) {
+    Ok(_) => fail!("Allocation should fail"),
+    Err(reason) => {
+      let pos = g.flat_instr(&ids[1]);
+      let expected = fmt!("Interval %u needs register %u at %u, \
+                           but it is held by interval %u",
+                          def.to_uint(),
+                          rbx.to_uint(),
+                          pos.to_uint(),
+                          held.to_uint());
+      assert!(reason == expected);
+    }
+  }
+}
+
+#[test]
+fn frame_size() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.register_count.insert(Double.to_uint(), 2);
+
+  let (_, res) = do run_test_with(config, Right(8f)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // One spilled value in Normal group, two in Double
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let d1 = b.add(DoubleNumber(0.5f), ~[]);
+      let d2 = b.add(DoubleNumber(0.5f), ~[]);
+      let d3 = b.add(DoubleNumber(0.5f), ~[]);
+      let d4 = b.add(DoubleNumber(0.5f), ~[]);
+
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      let mut dsum = b.add(DoubleSum, ~[d1, d2]);
+      dsum = b.add(DoubleSum, ~[dsum, d3]);
+      dsum = b.add(DoubleSum, ~[dsum, d4]);
+
+      let total = b.add(ToDouble, ~[sum]);
+      let total = b.add(DoubleSum, ~[dsum, total]);
+      b.add(ReturnDouble, ~[total]);
+      b.end();
+    };
+  };
+
+  // Areas of all groups are laid out in one frame
+  let normal = res.spill_bytes[Normal.to_uint()];
+  let double = res.spill_bytes[Double.to_uint()];
+  let pair = res.spill_bytes[Pair.to_uint()];
+  assert!(normal > 0 && double > 0);
+
+  let align = |n: uint, size: uint| (n + size - 1) / size * size;
+  let mut expected = align(normal, Double.spill_size()) + double;
+  expected = align(align(expected, Pair.spill_size()) + pair,
+                   Double.spill_size());
+  assert!(res.frame_size == expected);
+}
+
+#[test]
+fn fast_path() {
+  fn run(count: uint) -> AllocatorResult {
+    let mut config = Config::new();
+    config.fast_path = true;
+    let expected = count * (count + 1) / 2;
+    let (_, res) = do run_test_with(config, Left(expected)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let mut numbers = ~[];
+        for i in iterator::range(0u, count) {
+          numbers.push(b.add(Number(i + 1), ~[]));
+        }
+        let mut sum = numbers[0];
+        for &n in numbers.slice_from(1).iter() {
+          sum = b.add(Sum, ~[sum, n]);
+        }
+        b.add(ReturnAny, ~[sum]);
+        b.end();
+      };
+    };
+    return res;
+  }
+
+  // Four values fit into registers without splits and spills
+  let res = run(4);
+  let normal = &res.stats[Normal.to_uint()];
+  assert!(normal.fast_groups == 1);
+  assert!(normal.splits == 0);
+  assert!(res.spill_count[Normal.to_uint()] == 0);
+
+  // Fifth value needs a regular walk
+  let res = run(5);
+  assert!(res.stats[Normal.to_uint()].fast_groups == 0);
+  assert!(res.spill_count[Normal.to_uint()] > 0);
+}
+
+#[test]
+fn max_pressure() {
+  // Loop counter, its bound and the value returned after the loop
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  realword_graph(&mut *g);
+  g.prepare().get();
+  let pressure = g.max_pressure();
+  assert!(*pressure.get(&Normal.to_uint()) == 3);
+  assert!(*pressure.get(&Double.to_uint()) == 0);
+
+  // Values summed at the end are all live at once
+  for count in iterator::range(3u, 6u) {
+    let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+    do g.block() |b| {
+      b.make_root();
+      let mut numbers = ~[];
+      for i in iterator::range(0u, count) {
+        numbers.push(b.add(Number(i + 1), ~[]));
+      }
+      let mut sum = numbers[0];
+      for &n in numbers.slice_from(1).iter() {
+        sum = b.add(Sum, ~[sum, n]);
+      }
+      b.add(ReturnAny, ~[sum]);
+      b.end();
+    };
+    g.prepare().get();
+    let pressure = *g.max_pressure().get(&Normal.to_uint());
+    assert!(pressure == count);
+
+    // Values fit into registers only if pressure allows it
+    let res = g.allocate(Config::new()).get();
+    let registers = Normal.registers().len();
+    if res.spill_count[Normal.to_uint()] == 0 {
+      assert!(pressure <= registers);
+    } else {
+      assert!(pressure > registers);
+    }
+  }
+}
+
+#[test]
+fn spill_cost() {
+  fn run(config: Config<Kind, Group, Register>) -> (bool, bool) {
+    let mut ids = ~[];
+    let (g, _) = do run_test_with(config, Left(15)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let n1 = b.add(Number(1), ~[]);
+        let n2 = b.add(Number(2), ~[]);
+        let n3 = b.add(Number(3), ~[]);
+        let n4 = b.add(Number(4), ~[]);
+
+        // All registers are busy here
+        let n5 = b.add(Number(5), ~[]);
+        b.add(Prefer, ~[n1]);
+        b.add(Prefer, ~[n2]);
+        b.add(Prefer, ~[n3]);
+        b.add(Prefer, ~[n4]);
+        let mut sum = b.add(Sum, ~[n5, n1]);
+        sum = b.add(Sum, ~[sum, n2]);
+        sum = b.add(Sum, ~[sum, n3]);
+        sum = b.add(Sum, ~[sum, n4]);
+        b.add(ReturnAny, ~[sum]);
+        b.end();
+        ids = ~[n1, n4, n5];
+      };
+    };
+
+    // Whether nearest and farthest used values are in stack
+    let pos = g.flat_instr(&ids[2]);
+    let spilled = do ids.slice_to(2).map() |id| {
+      let mut res = false;
+      let output = g.get_output(&g.flat_instr(id));
+      for &(ref range, ref value) in g.locations(output).iter() {
+        if range.covers(pos) {
+          res = value.is_stack();
+        }
+      }
+      res
+    };
+    return (spilled[0], spilled[1]);
+  }
+
+  assert!(run(Config::new()) == (false, true));
+
+  // Value living for a shorter time is the cheapest to spill
+  let mut config = Config::new();
+  config.spill_cost = Some(|interval, _| interval.end().to_uint() as f64);
+  assert!(run(config) == (true, false));
+}
+
+#[test]
+fn loop_carried() {
+  let mut acc_id = None;
+  let mut blocks = ~[];
+  let (g, _) = do run_test_with(Config::new(), Left(31)) |g| {
+    let counter = g.phi(Normal);
+    let acc = g.phi(Normal);
+    let header = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let zero = b.add(Number(0), ~[]);
+      let one = b.add(Number(1), ~[]);
+      b.to_phi(zero, counter);
+      b.to_phi(one, acc);
+      b.goto(header);
+    };
+
+    do g.with_block(header) |b| {
+      let limit = b.add(Number(5), ~[]);
+      b.add(BranchIfBigger, ~[counter, limit]);
+      b.branch(exit, body);
+    };
+
+    // Accumulator is used only at the end of the body
+    do g.with_block(body) |b| {
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let step = b.add(Increment, ~[counter]);
+      let sum = b.add(Sum, ~[n2, n3]);
+      let next = b.add(Sum, ~[acc, sum]);
+      b.to_phi(step, counter);
+      b.to_phi(next, acc);
+      b.goto(header);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(ReturnAny, ~[acc]);
+      b.end();
+    };
+    acc_id = Some(acc);
+    blocks = ~[header, body];
+  };
+
+  // Accumulator stays in registers through the whole loop
+  let loop_start = g.get_block(&g.flat_block(&blocks[0]).unwrap()).start();
+  let loop_end = g.get_block(&g.flat_block(&blocks[1]).unwrap()).end();
+  let acc = g.get_output(&g.flat_instr(&acc_id.unwrap()));
+  for &(ref range, ref value) in g.locations(acc).iter() {
+    if range.start < loop_end && loop_start < range.end {
+      assert!(!value.is_stack());
+    }
+  }
+}
+
+#[test]
+fn branch_hinted() {
+  fn run(ordering: BlockOrder, hint_left: bool) {
+    let mut config = Config::new();
+    config.ordering = ordering;
+    let mut blocks = ~[];
+    let (g, _) = do run_test_with(config, Left(1)) |g| {
+      let left = g.empty_block();
+      let right = g.empty_block();
+      let exit = g.empty_block();
+      let mut one = None;
+
+      do g.block() |b| {
+        b.make_root();
+        one = Some(b.add(Number(1), ~[]));
+        let cond = b.add(Number(0), ~[]);
+        b.add(BranchIfTrue, ~[cond]);
+        b.branch_hinted(left, right, if hint_left { left } else { right });
+      };
+
+      for &block in [left, right].iter() {
+        do g.with_block(block) |b| {
+          b.add(Nop, ~[]);
+          b.goto(exit);
+        };
+      }
+
+      do g.with_block(exit) |b| {
+        b.add(Return, ~[one.unwrap()]);
+        b.end();
+      };
+      blocks = ~[left, right];
+    };
+
+    // Hinted successor follows root
+    let expected = if hint_left { blocks[0] } else { blocks[1] };
+    assert!(g.block_order()[1] == expected);
+  }
+
+  for &hint_left in [true, false].iter() {
+    run(LoopAware, hint_left);
+    run(ReversePostorder, hint_left);
+  }
+
+  // Loop exit can't be placed between header and body
+  let mut blocks = ~[];
+  let (g, _) = do run_test_with(Config::new(), Left(3)) |g| {
+    let counter = g.phi(Normal);
+    let header = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let zero = b.add(Number(0), ~[]);
+      b.to_phi(zero, counter);
+      b.goto(header);
+    };
+
+    do g.with_block(header) |b| {
+      let limit = b.add(Number(2), ~[]);
+      b.add(BranchIfBigger, ~[counter, limit]);
+      b.branch_hinted(exit, body, exit);
+    };
+
+    do g.with_block(body) |b| {
+      let step = b.add(Increment, ~[counter]);
+      b.to_phi(step, counter);
+      b.goto(header);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(Return, ~[counter]);
+      b.end();
+    };
+    blocks = ~[header, body];
+  };
+
+  let order = g.block_order();
+  let header = order.position_elem(&blocks[0]).unwrap();
+  assert!(order[header + 1] == blocks[1]);
+}
+
+#[test]
+fn add_range() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let mut n = None;
+  do g.block() |b| {
+    b.make_root();
+    n = Some(b.add(Number(1), ~[]));
+    b.add(Return, ~[n.unwrap()]);
+    b.end();
+  };
+  let id = g.get_output(&n.unwrap());
+
+  fn ranges(g: &Graph<Kind, Group, Register>,
+            id: IntervalId) -> ~[(uint, uint)] {
+    do g.get_interval(&id).ranges.map() |r| {
+      (r.start.to_uint(), r.end.to_uint())
+    }
+  }
+
+  // Out of order
+  g.get_mut_interval(&id).add_range(InstrId(10), InstrId(12));
+  g.get_mut_interval(&id).add_range(InstrId(20), InstrId(24));
+  g.get_mut_interval(&id).add_range(InstrId(2), InstrId(4));
+  assert!(ranges(g, id) == ~[(2, 4), (10, 12), (20, 24)]);
+
+  // Overlapping one range and touching another
+  g.get_mut_interval(&id).add_range(InstrId(11), InstrId(20));
+  assert!(ranges(g, id) == ~[(2, 4), (10, 24)]);
+
+  // Inside of existing range
+  g.get_mut_interval(&id).add_range(InstrId(3), InstrId(4));
+  assert!(ranges(g, id) == ~[(2, 4), (10, 24)]);
+
+  // Covering everything
+  g.get_mut_interval(&id).add_range(InstrId(0), InstrId(30));
+  assert!(ranges(g, id) == ~[(0, 30)]);
+}
+
+#[test]
+fn linear_instructions() {
+  let mut r = None;
+  let g = do run_test(Left(21)) |g| {
+    r = Some(realword_graph(g));
+  };
+  let r = r.unwrap();
+
+  // Positions are contiguous and start in root block
+  let list = g.linear_instructions();
+  assert!(list.len() > 0);
+  match list[0] {
+    (_, block) => assert!(block == g.flat_block(&r.root).unwrap())
+  }
+  let mut gaps = 0;
+  for (i, &(instr, block)) in list.iter().enumerate() {
+    assert!(instr.to_uint() == i);
+    assert!(g.block_of(&instr) == block);
+    if g.is_gap(&instr) {
+      gaps += 1;
+    }
+  }
+
+  // Each block starts and ends with a gap
+  assert!(gaps >= 2 * g.block_order().len());
+}
+
+#[test]
+fn set_hint() {
+  let mut ids = ~[];
+  let (g, _) = do run_test_with(Config::new(), Left(10)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(5), ~[]);
+      b.set_hint(n, rcx);
+
+      // Fixed use wins over the hint
+      let m = b.add(Number(5), ~[]);
+      b.set_hint(m, rcx);
+      let used = b.add(JustUse, ~[m]);
+
+      let sum = b.add(Sum, ~[n, m]);
+      b.add(ReturnAny, ~[sum]);
+      b.end();
+      ids = ~[n, used];
+    };
+  };
+
+  assert!(g.register_at(ids[0]) == Some(RegisterVal(rcx)));
+  let used = g.get_assignment(&g.flat_instr(&ids[1]));
+  assert!(used.inputs[0] == RegisterVal(rbx));
+}
+
+#[test]
+fn merge_redundant_splits() {
+  let mut n = None;
+  let mut config = Config::new();
+  config.merge_splits = true;
+  let (g, res) = do run_test_with(config, Left(7)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let value = b.add(Number(7), ~[]);
+
+      // Value is split between fixed uses of the same register
+      b.add(JustUse, ~[value]);
+      b.add(Nop, ~[]);
+      b.add(JustUse, ~[value]);
+      b.add(ReturnAny, ~[value]);
+      b.end();
+      n = Some(value);
+    };
+  };
+
+  // Pieces were joined back, and no moves are left
+  assert!(res.merged_splits > 0);
+  let out = g.get_output(&g.flat_instr(&n.unwrap()));
+  assert!(g.get_interval(&out).children.len() == 0);
+  assert!(g.get_interval(&out).value == RegisterVal(rbx));
+  assert!(g.statistics().moves == 0);
+}
+
+#[test]
+fn fixed_clobbers() {
+  let mut ids = ~[];
+  let (g, res) = do run_test_with(Config::new(), Left(45)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let a = b.add(Number(6), ~[]);
+      let c = b.add(Number(7), ~[]);
+      let mul = b.add(Mul, ~[a, c]);
+      let mut sum = b.add(Sum, ~[mul, n1]);
+      sum = b.add(Sum, ~[sum, n2]);
+      b.add(ReturnAny, ~[sum]);
+      b.end();
+      ids = ~[n1, n2, mul];
+    };
+  };
+
+  // Values live across instruction are kept in other registers
+  assert!(res.spill_count[Normal.to_uint()] == 0);
+  let pos = g.flat_instr(&ids[2]);
+  for id in ids.slice_to(2).iter() {
+    let output = g.get_output(&g.flat_instr(id));
+    for &(ref range, ref value) in g.locations(output).iter() {
+      if range.covers(pos) {
+        assert!(!value.is_stack() && *value != RegisterVal(rdx));
+      }
+    }
+  }
+}
+
+#[test]
+fn explicit_ids() {
+  let (g, _) = do run_test_with(Config::new(), Left(3)) |g| {
+    let exit = g.empty_block_with_id(BlockId(10));
+    let one = g.new_instr_with_id(InstrId(100), Number(1), ~[]);
+
+    do g.block() |b| {
+      b.make_root();
+      b.add_existing(one);
+      b.add_with_id(InstrId(50), Number(2), ~[]);
+      b.goto(exit);
+    };
+
+    do g.with_block(exit) |b| {
+      let sum = b.add_with_id(InstrId(200), Sum, ~[one, InstrId(50)]);
+      b.add(ReturnAny, ~[sum]);
+      b.end();
+    };
+  };
+
+  // Queries by user ids see the right values
+  assert!(g.block_order().contains(&BlockId(10)));
+  for &id in [InstrId(50), InstrId(100), InstrId(200)].iter() {
+    match g.register_at(id) {
+      Some(RegisterVal(_)) => (),
+      _ => fail!(fmt!("Instruction %u is not in register", id.to_uint()))
+    }
+  }
+  assert!(g.get_assignment(&g.flat_instr(&InstrId(200))).inputs.len() == 2);
+}
+
+#[test]
+fn empty_blocks() {
+  let mut inserted = ~[];
+  let g = do run_test(Left(21)) |g| {
+    let r = realword_graph(g);
+
+    // Empty blocks on existing edges are just passing values through
+    inserted.push(g.split_edge(r.cond, r.left));
+    inserted.push(g.split_edge(r.left, r.after_left));
+  };
+
+  // Positions of empty blocks are assigned by flattening
+  for id in inserted.iter() {
+    let block = g.get_block(&g.flat_block(id).unwrap());
+    assert!(block.start() < block.end());
+  }
+}
+
+#[test]
+fn early_store() {
+  let mut peaks = ~[];
+  for &early_store in [false, true].iter() {
+    let mut config = Config::new();
+    config.early_store = early_store;
+    let mut ids = None;
+    let (g, _) = do run_test_with(config, Left(5)) |g| {
+      do g.block() |b| {
+        b.make_root();
+        let n1 = b.add(Number(1), ~[]);
+        let n2 = b.add(Number(2), ~[]);
+        let n3 = b.add(Number(3), ~[]);
+        let n4 = b.add(Number(4), ~[]);
+
+        // `n4` isn't used in registers here
+        b.add(ToDouble, ~[n1]);
+        b.add(ToDouble, ~[n2]);
+        b.add(ToDouble, ~[n3]);
+
+        // `n4` is used last, so it is spilled to make room for `n5`
+        let n5 = b.add(Number(5), ~[]);
+        b.add(ToDouble, ~[n5]);
+        b.add(ToDouble, ~[n1]);
+        b.add(ToDouble, ~[n2]);
+        b.add(ToDouble, ~[n3]);
+        b.add(ToDouble, ~[n4]);
+        b.add(Return, ~[n5]);
+        b.end();
+        ids = Some((n4, n5));
+      };
+    };
+    let (n4, n5) = ids.unwrap();
+    let n4 = g.flat_instr(&n4);
+    let n5 = g.flat_instr(&n5);
+
+    // Count values held in registers between definitions of `n4` and `n5`
+    let mut peak = 0;
+    let list = g.linear_instructions();
+    for &(pos, _) in list.iter() {
+      if pos <= n4 || pos >= n5 {
+        loop;
+      }
+      let mut pressure = 0;
+      for (_, interval) in g.intervals_iter() {
+        match interval.value {
+          RegisterVal(r) if !interval.fixed && r.group() == Normal &&
+                            interval.covers(pos) => pressure += 1,
+          _ => ()
+        }
+      }
+      if pressure > peak {
+        peak = pressure;
+      }
+    }
+    peaks.push(peak);
+  }
+
+  // Store right after definition releases register of `n4`
+  assert!(peaks[0] == 4);
+  assert!(peaks[1] == 3);
+}
+
+#[test]
+fn unique_spill_slots() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  config.reuse_spill_slots = false;
+  let (g, _) = do run_test_with(config, Left(15)) |g| {
+    pressure_graph(g);
+  };
+
+  // Every spilled interval has its own slot
+  let mut spilled = 0;
+  let mut slots = ~[];
+  for (_, interval) in g.intervals_iter() {
+    match interval.value {
+      StackVal(ref group, slot) => {
+        spilled += 1;
+        if !slots.contains(&(group.to_uint(), slot.to_uint())) {
+          slots.push((group.to_uint(), slot.to_uint()));
+        }
+      },
+      _ => ()
+    }
+  }
+  assert!(spilled > 0);
+  assert!(slots.len() == spilled);
+}
+
+#[test]
+fn spilled() {
+  // Values fit in registers
+  let (_, res) = do run_test_with(Config::new(), Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let sum = b.add(Sum, ~[n1, n2]);
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+  assert!(!res.spilled());
+  assert!(res.total_spills() == 0);
+
+  // Not enough registers
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 2);
+  let (_, res) = do run_test_with(config, Left(15)) |g| {
+    pressure_graph(g);
+  };
+  assert!(res.spilled());
+  assert!(res.total_spills() == res.spill_count[Normal.to_uint()]);
+  assert!(res.total_spills() * Normal.spill_size() ==
+          res.spill_bytes[Normal.to_uint()]);
+}
+
+#[test]
+fn phi_inputs_from_same_block() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let phi = g.phi(Normal);
+  let left = g.empty_block();
+  let right = g.empty_block();
+  let join = g.empty_block();
+  do g.block() |b| {
+    b.make_root();
+    let cond = b.add(Number(0), ~[]);
+    b.add(BranchIfTrue, ~[cond]);
+    b.branch(left, right);
+  };
+
+  // Both inputs come from the left branch
+  do g.with_block(left) |b| {
+    let n1 = b.add(Number(1), ~[]);
+    let n2 = b.add(Number(2), ~[]);
+    b.to_phi(n1, phi);
+    b.to_phi(n2, phi);
+    b.goto(join);
+  };
+  do g.with_block(right) |b| {
+    b.goto(join);
+  };
+  do g.with_block(join) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+
+  // And right branch gives none
+  let errors = g.validate().get_err();
+  assert!(errors.len() == 2);
+  assert!(errors.iter().any(|e| e.contains("several inputs")));
+  let missing = fmt!("no input from block %u", right.to_uint());
+  assert!(errors.iter().any(|e| e.contains(missing.as_slice())));
+}
+
+// Single group with lots of registers, counting requests of its register list
+#[deriving(Clone, Eq, ToStr)]
+enum BigGroup {
+  Big
+}
+
+#[deriving(Clone, Eq, ToStr)]
+struct BigRegister(uint);
+
+#[deriving(Clone, Eq, ToStr)]
+enum BigKind {
+  BigNumber,
+  BigSafepoint,
+  BigCall,
+  BigReturn
+}
+
+static BIG_REGISTER_COUNT: uint = 512;
+static mut BIG_REGISTERS_CALLS: uint = 0;
+
+impl GroupHelper<BigRegister> for BigGroup {
+  fn groups() -> ~[BigGroup] { ~[Big] }
+  fn registers(&self) -> ~[BigRegister] {
+    unsafe { BIG_REGISTERS_CALLS += 1; }
+    vec::from_fn(BIG_REGISTER_COUNT, |i| BigRegister(i))
+  }
+  fn register_count(&self) -> uint { BIG_REGISTER_COUNT }
+  fn to_uint(&self) -> uint { 0 }
+  fn from_uint(_: uint) -> BigGroup { Big }
+  fn spill_size(&self) -> uint { 8 }
+}
+
+impl RegisterHelper<BigGroup> for BigRegister {
+  fn group(&self) -> BigGroup { Big }
+  fn to_uint(&self) -> uint { **self }
+  fn from_uint(_: &BigGroup, i: uint) -> BigRegister { BigRegister(i) }
+}
+
+impl KindHelper<BigGroup, BigRegister> for BigKind {
+  fn clobbers(&self, _: &BigGroup) -> bool {
+    match self {
+      &BigCall => true,
+      _ => false
+    }
+  }
+  fn temporary(&self) -> ~[BigGroup] { ~[] }
+  fn use_kind(&self, _: uint) -> UseKind<BigGroup, BigRegister> {
+    Big.use_reg()
+  }
+  fn result_kind(&self) -> Option<UseKind<BigGroup, BigRegister> > {
+    match self {
+      &BigNumber => Some(Big.use_reg()),
+      _ => None
+    }
+  }
+}
+
+#[test]
+fn cached_registers() {
+  let mut g: ~Graph<BigKind, BigGroup, BigRegister> = ~Graph::new();
+  let mut safepoints = ~[];
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(BigNumber, ~[]);
+    for _ in iterator::range(0u, 20) {
+      safepoints.push(b.add(BigSafepoint, ~[]));
+      b.add(BigCall, ~[]);
+    }
+    b.add(BigReturn, ~[n]);
+    b.end();
+  };
+  for id in safepoints.iter() {
+    g.spill_all_at(*id);
+  }
+  assert!(g.allocate(Config::new()).is_ok());
+
+  // Register list is requested once for fixed intervals, and once for
+  // safepoints and calls, regardless of their number
+  unsafe {
+    assert!(BIG_REGISTERS_CALLS <= 2);
+  }
+}
+
+#[test]
+fn reload_to_fixed_register() {
+  let mut config = Config::new();
+  config.register_count.insert(Normal.to_uint(), 3);
+  let mut use_id = None;
+  let (g, _) = do run_test_with(config, Left(4)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+
+      // `n2` is used last, so it is spilled to make room for `n4`
+      let n4 = b.add(Number(4), ~[]);
+      b.add(ToDouble, ~[n4]);
+      b.add(ToDouble, ~[n1]);
+      b.add(ToDouble, ~[n3]);
+      use_id = Some(b.add(JustUse, ~[n2]));
+      b.add(ReturnAny, ~[n4]);
+      b.end();
+    };
+  };
+  let use_id = g.flat_instr(&use_id.unwrap());
+
+  // Value is reloaded right into register required by its use
+  let ops = g.spill_schedule();
+  let mut reloads = 0;
+  for op in ops.iter() {
+    if op.kind == Reload && op.pos < use_id {
+      assert!(op.register == rbx);
+      reloads += 1;
+    }
+  }
+  assert!(reloads == 1);
+  let a = g.get_assignment(&use_id);
+  assert!(a.inputs == ~[RegisterVal(rbx)]);
+}
+
+#[test]
+fn copy_fixed_conflicts() {
+  let mut config = Config::new();
+  config.copy_fixed_conflicts = true;
+  let mut use_id = None;
+  let (g, _) = do run_test_with(config, Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[]);
+
+      // Value is required both in `rax` and `rbx`
+      use_id = Some(b.add(FixedUse, ~[n, n]));
+      b.add(Return, ~[n]);
+      b.end();
+    };
+  };
+  let use_id = g.flat_instr(&use_id.unwrap());
+
+  // Second use is getting a copy
+  assert!(g.get_instr(&use_id).cross_inputs.len() == 1);
+  let a = g.get_assignment(&use_id);
+  assert!(a.inputs == ~[RegisterVal(rax), RegisterVal(rbx)]);
+}
+
+#[test]
+fn estimate() {
+  for &limit in [2u, 4u].iter() {
+    let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[]);
+      let n2 = b.add(Number(2), ~[]);
+      let n3 = b.add(Number(3), ~[]);
+      let mut sum = b.add(Sum, ~[n1, n2]);
+      sum = b.add(Sum, ~[sum, n3]);
+      b.add(ReturnAny, ~[sum]);
+      b.end();
+    };
+    g.prepare().get();
+
+    let mut config = Config::new();
+    config.register_count.insert(Normal.to_uint(), limit);
+    let estimate = g.estimate(&*config.register_count);
+    assert!(*estimate.pressure.get(&Normal.to_uint()) == 3);
+
+    // Estimate matches the outcome of allocation
+    let res = g.allocate(config).get();
+    assert!(estimate.needs_spills == res.spilled());
+    assert!(estimate.needs_spills == (limit < 3));
+  }
+}
+
+#[test]
+fn fixed_first() {
+  let mut config = Config::new();
+  config.interval_order = FixedFirst;
+  config.tie_break = HighestIndex;
+  let mut shift = None;
+  let (g, _) = do run_test_with(config, Left(6)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[]);
+
+      // Output and temporary fixed to `rdx` are starting at the same
+      // position, temporary is allocated first
+      shift = Some(b.add(Shift, ~[n]));
+      b.add(ReturnAny, ~[shift.unwrap()]);
+      b.end();
+    };
+  };
+
+  // So output doesn't take `rdx` and isn't split
+  let out = g.get_output(&g.flat_instr(&shift.unwrap()));
+  assert!(g.get_interval(&out).value != RegisterVal(rdx));
+  assert!(g.get_interval(&out).children.len() == 0);
+}
+
+#[test]
+fn on_event() {
+  let (port, chan) = std::comm::stream();
+  let mut config = Config::new();
+  config.on_event = Some(|event| chan.send(event));
+  do run_test_with(config, Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut events = ~[];
+  while port.peek() {
+    events.push(port.recv());
+  }
+
+  // Value live across the call is split and spilled
+  let mut seen = ~[];
+  let mut splits = 0;
+  let mut spills = ~[];
+  for event in events.iter() {
+    match *event {
+      Split(parent, _, child) => {
+        // Child is new
+        assert!(parent != child);
+        assert!(!seen.contains(&child));
+        seen.push(parent);
+        seen.push(child);
+        splits += 1;
+      },
+      Spilled(id, _) => {
+        // Interval is spilled only once
+        assert!(!spills.contains(&id));
+        spills.push(id);
+        seen.push(id);
+      },
+      Assigned(id, reg) => {
+        assert!(reg < Normal.registers().len());
+        seen.push(id);
+      }
+    }
+  }
+  assert!(splits > 0);
+  assert!(spills.len() > 0);
+
+  // Every register given during the walk is reported, including registers
+  // that are idle for the whole lifetime of value
+  let (port, chan) = std::comm::stream();
+  let mut config = Config::new();
+  config.spill_everywhere = true;
+  config.greedy_any = true;
+  config.on_event = Some(|event| chan.send(event));
+  let (g, _) = do run_test_with(config, Left(21)) |g| {
+    realword_graph(g);
+  };
+
+  let mut assigned = ~[];
+  while port.peek() {
+    match port.recv() {
+      Assigned(id, _) => assigned.push(id),
+      _ => ()
+    }
+  }
+  for (_, interval) in g.intervals_iter() {
+    match interval.value {
+      RegisterVal(_) if !interval.fixed => {
+        assert!(assigned.contains(&interval.id));
+      },
+      _ => ()
+    }
+  }
+}
+
+#[test]
+fn conditional_def() {
+  for &cond in [0u, 1u].iter() {
+    let mut ids = None;
+    let expected = if cond == 0 { Left(1) } else { Left(2) };
+    let (g, _) = do run_test_with(Config::new(), expected) |g| {
+      let left = g.empty_block();
+      let right = g.empty_block();
+      let join = g.empty_block();
+      let mut prev = None;
+      let mut res = None;
+      do g.block() |b| {
+        b.make_root();
+        prev = Some(b.add(Number(1), ~[]));
+        let c = b.add(Number(cond), ~[]);
+        b.add(BranchIfTrue, ~[c]);
+        b.branch(left, right);
+      };
+      do g.with_block(left) |b| {
+        // Output is defined only on this path, and keeps `prev` on the other
+        let value = b.add(Number(2), ~[]);
+        res = Some(b.add(Redefine, ~[prev.unwrap(), value]));
+        b.goto(join);
+      };
+      do g.with_block(right) |b| {
+        b.add(Nop, ~[]);
+        b.goto(join);
+      };
+      do g.with_block(join) |b| {
+        b.add(Return, ~[res.unwrap()]);
+        b.end();
+      };
+      ids = Some((prev.unwrap(), res.unwrap(), left, right, join));
+    };
+    let (prev, res, left, right, join) = ids.unwrap();
+    let prev = g.get_output(&g.flat_instr(&prev));
+    let out = g.get_output(&g.flat_instr(&res));
+    let left = g.get_block(&g.flat_block(&left).unwrap());
+    let right = g.get_block(&g.flat_block(&right).unwrap());
+    let join = g.get_block(&g.flat_block(&join).unwrap());
+
+    // Output is live on both paths to the join
+    assert!(join.live_in.contains(&out.to_uint()));
+    assert!(left.live_out.contains(&out.to_uint()));
+    assert!(right.live_out.contains(&out.to_uint()));
+
+    // But defined only on one of them, previous value is live-in on other
+    assert!(!left.live_in.contains(&out.to_uint()));
+    assert!(!right.live_in.contains(&out.to_uint()));
+    assert!(right.live_in.contains(&prev.to_uint()));
+  }
+}
+
+#[test]
+fn conditional_def_unused() {
+  let mut ids = None;
+  let (g, _) = do run_test_with(Config::new(), Left(1)) |g| {
+    let left = g.empty_block();
+    let right = g.empty_block();
+    let join = g.empty_block();
+    let mut prev = None;
+    do g.block() |b| {
+      b.make_root();
+      prev = Some(b.add(Number(1), ~[]));
+      let c = b.add(Number(0), ~[]);
+      b.add(BranchIfTrue, ~[c]);
+      b.branch(left, right);
+    };
+    do g.with_block(left) |b| {
+      let value = b.add(Number(2), ~[]);
+      b.add(Redefine, ~[prev.unwrap(), value]);
+      b.goto(join);
+    };
+    do g.with_block(right) |b| {
+      b.add(Nop, ~[]);
+      b.goto(join);
+    };
+    do g.with_block(join) |b| {
+      b.add(Return, ~[prev.unwrap()]);
+      b.end();
+    };
+    ids = Some(right);
+  };
+
+  // Output is dead after its block, other path gets no phi movement
+  let right = g.get_block(&g.flat_block(&ids.unwrap()).unwrap());
+  for id in right.instructions.iter() {
+    assert!(g.get_instr(id).output.is_none());
+  }
+}
@@ -0,0 +1,190 @@
+use std::iterator;
+use linearscan::*;
+use emulator::*;
+
+// Shape of generated graphs
+pub struct RandomParams {
+  // Number of branches and loops placed one after another
+  regions: uint,
+
+  // Maximum number of instructions in each block
+  instructions: uint,
+
+  // Maximum number of iterations of each loop
+  iterations: uint
+}
+
+impl RandomParams {
+  pub fn new() -> RandomParams {
+    RandomParams {
+      regions: 4,
+      instructions: 8,
+      iterations: 3
+    }
+  }
+}
+
+// Xorshift generator, results must not depend on the platform
+struct Rng {
+  state: u64
+}
+
+impl Rng {
+  fn new(seed: uint) -> Rng {
+    Rng {
+      state: (seed as u64) * 0x9e3779b97f4a7c15u64 + 0x2545f4914f6cdd1du64
+    }
+  }
+
+  fn next(&mut self) -> uint {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 7;
+    self.state ^= self.state << 17;
+    return (self.state >> 16) as uint;
+  }
+
+  // Random number in [0, n)
+  fn below(&mut self, n: uint) -> uint {
+    return self.next() % n;
+  }
+
+  fn pick(&mut self, values: &[InstrId]) -> InstrId {
+    return values[self.below(values.len())];
+  }
+}
+
+// Add random computations to the block, new values are pushed to `values`
+fn fill(g: &mut Graph<Kind, Group, Register>,
+        rng: &mut Rng,
+        block: BlockId,
+        values: &mut ~[InstrId],
+        params: &RandomParams) {
+  let count = rng.below(params.instructions) + 1;
+  do g.with_block(block) |b| {
+    let mut doubles = ~[];
+    for _ in iterator::range(0u, count) {
+      let a = rng.pick(*values);
+      let c = rng.pick(*values);
+      let res = match rng.below(12) {
+        0 => Some(b.add(Number(rng.below(100)), ~[])),
+        1 => Some(b.add(Constant(rng.below(100)), ~[])),
+        2 => Some(b.add(Sum, ~[a, c])),
+        3 => Some(b.add(Add, ~[a, c])),
+        4 => Some(b.add(Increment, ~[a])),
+        5 => Some(b.add(Print, ~[a])),
+        6 => Some(b.add(Shift, ~[a])),
+        7 => { b.add(JustUse, ~[a]); None },
+        8 => { b.add(Prefer, ~[a]); None },
+        9 => { b.add(PartialCall, ~[a]); None },
+        10 => {
+          doubles.push(b.add(ToDouble, ~[a]));
+          None
+        },
+        _ => {
+          if doubles.len() > 0 {
+            let d = rng.pick(doubles);
+            doubles.push(b.add(DoubleSum, ~[d, d]));
+          }
+          None
+        }
+      };
+      match res {
+        Some(res) => values.push(res),
+        None => ()
+      }
+    }
+  };
+}
+
+// Generate well-formed random graph: straight-line code, branches with phis,
+// counted loops with loop-carried values and calls. Graph's result depends
+// only on the seed.
+pub fn random_graph(g: &mut Graph<Kind, Group, Register>,
+                    seed: uint,
+                    params: &RandomParams) {
+  let mut rng = Rng::new(seed);
+  let mut values = ~[];
+
+  let mut current = g.empty_block();
+  do g.with_block(current) |b| {
+    b.make_root();
+    values.push(b.add(Number(rng.below(100)), ~[]));
+  };
+  fill(g, &mut rng, current, &mut values, params);
+
+  for _ in iterator::range(0u, params.regions) {
+    if rng.below(2) == 0 {
+      // Branch: values defined in arms are merged by phi
+      let phi = g.phi(Normal);
+      let left = g.empty_block();
+      let right = g.empty_block();
+      let join = g.empty_block();
+
+      let a = rng.pick(values);
+      let c = rng.pick(values);
+      do g.with_block(current) |b| {
+        let cmp = b.add(Compare, ~[a, c]);
+        b.add(BranchIfTrue, ~[cmp]);
+        b.branch(left, right);
+      };
+
+      for &arm in [left, right].iter() {
+        let mut arm_values = values.clone();
+        fill(g, &mut rng, arm, &mut arm_values, params);
+        let input = rng.pick(arm_values);
+        do g.with_block(arm) |b| {
+          b.to_phi(input, phi);
+          b.goto(join);
+        };
+      }
+
+      current = join;
+      values.push(phi);
+    } else {
+      // Counted loop with one more loop-carried value
+      let counter = g.phi(Normal);
+      let carried = g.phi(Normal);
+      let header = g.empty_block();
+      let body = g.empty_block();
+      let exit = g.empty_block();
+
+      let init = rng.pick(values);
+      let iterations = rng.below(params.iterations) + 1;
+      do g.with_block(current) |b| {
+        let zero = b.add(Number(0), ~[]);
+        b.to_phi(zero, counter);
+        b.to_phi(init, carried);
+        b.goto(header);
+      };
+
+      do g.with_block(header) |b| {
+        let limit = b.add(Number(iterations), ~[]);
+        b.add(BranchIfBigger, ~[counter, limit]);
+        b.branch(exit, body);
+      };
+
+      let mut body_values = values.clone();
+      body_values.push(counter);
+      body_values.push(carried);
+      fill(g, &mut rng, body, &mut body_values, params);
+      let next = rng.pick(body_values);
+      do g.with_block(body) |b| {
+        let step = b.add(Increment, ~[counter]);
+        b.to_phi(step, counter);
+        b.to_phi(next, carried);
+        b.goto(header);
+      };
+
+      current = exit;
+      values.push(counter);
+      values.push(carried);
+    }
+    fill(g, &mut rng, current, &mut values, params);
+  }
+
+  let res = rng.pick(values);
+  do g.with_block(current) |b| {
+    b.add(Return, ~[res]);
+    b.end();
+  };
+}